@@ -0,0 +1,293 @@
+// Challenge-response authentication after the version negotiation. The
+// server sends a random challenge, the client answers with a user name and
+// HMAC-SHA256(secret, challenge || user name), where the secret is the
+// password or the user's token. The user name is empty with a password.
+//
+// Failed attempts lock the client's address out for a time that doubles
+// with every failure past the first few.
+//
+// The role of a client decides what it may do once connected. Token users
+// may have a role of their own, everyone else gets the configured one.
+
+use std::collections::HashMap;
+
+use std::fmt;
+
+use std::fs;
+
+use std::io;
+
+use std::net::{
+    IpAddr,
+    SocketAddr
+};
+
+use std::path::Path;
+
+use std::str::FromStr;
+
+use std::time::{
+    Duration,
+    Instant
+};
+
+use super::crypto::{hmac, random_key, KEY_SIZE};
+
+pub const CHALLENGE_SIZE: usize = KEY_SIZE;
+pub const RESPONSE_SIZE: usize = 32;
+pub const SESSION_TOKEN_SIZE: usize = KEY_SIZE;
+
+// Time a client has to answer a challenge before another client may start
+// a handshake.
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Failures allowed before the address is locked out.
+const FREE_ATTEMPTS: u32 = 3;
+const MAX_LOCKOUT: Duration = Duration::from_secs(300);
+
+// Failures of an address are forgotten after this long without one.
+const FORGET_AFTER: Duration = Duration::from_secs(600);
+
+/// What a client may do, decided when it is authenticated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// Watches only, its input is dropped.
+    ViewOnly,
+    /// Controls the mouse and keyboard.
+    Interactive,
+    /// May also shut the server down.
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String>
+    {
+        match s {
+            "view-only" => Ok(Role::ViewOnly),
+            "interactive" => Ok(Role::Interactive),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role '{}'", s))
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            Role::ViewOnly => write!(f, "view-only"),
+            Role::Interactive => write!(f, "interactive"),
+            Role::Admin => write!(f, "admin")
+        }
+    }
+}
+
+impl Role {
+    pub fn can_control(&self) -> bool
+    {
+        *self != Role::ViewOnly
+    }
+
+    pub fn can_exit(&self) -> bool
+    {
+        *self == Role::Admin
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    Password(String),
+    // Tokens and roles by user name.
+    Tokens(HashMap<String, (String, Option<Role>)>),
+}
+
+impl Credentials {
+    /// Reads the password from the first line of a file, so that it is not
+    /// on the command line for other users to see.
+    pub fn load_password(path: &Path) -> io::Result<Self>
+    {
+        let contents = fs::read_to_string(path)?;
+        let password = contents.lines().next().unwrap_or("");
+
+        if password.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the first line is empty"))
+        }
+
+        Ok(Credentials::Password(password.to_string()))
+    }
+
+    /// Reads a token file with one "user token [role]" line per user. Empty
+    /// lines and lines starting with # are skipped.
+    pub fn load_tokens(path: &Path) -> io::Result<Self>
+    {
+        let mut tokens = HashMap::new();
+
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() | line.starts_with('#') {
+                continue
+            }
+
+            let mut fields = line.split_whitespace();
+
+            let invalid = |message: String| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, message))
+            };
+
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(user), Some(token), role, None) if user.len() <= 255 => {
+                    let role = match role {
+                        Some(role) => Some(role.parse().map_err(invalid)?),
+                        None => None
+                    };
+
+                    tokens.insert(user.to_string(), (token.to_string(), role));
+                },
+                _ => return Err(invalid("expected a user name, a token and optionally a role".to_string()))
+            }
+        }
+
+        Ok(Credentials::Tokens(tokens))
+    }
+
+    fn secret(&self, user: &str) -> Option<&str>
+    {
+        match *self {
+            Credentials::Password(ref password) if user.is_empty() => Some(password),
+            Credentials::Password(_) => None,
+            Credentials::Tokens(ref tokens) => tokens.get(user).map(|(token, _)| token.as_str())
+        }
+    }
+
+    fn role(&self, user: &str) -> Option<Role>
+    {
+        match *self {
+            Credentials::Password(_) => None,
+            Credentials::Tokens(ref tokens) => tokens.get(user).and_then(|&(_, role)| role)
+        }
+    }
+}
+
+/// A challenge sent to a client, with the handshake it answers.
+#[derive(Debug)]
+pub struct Challenge {
+    pub src: SocketAddr,
+    pub protocol_version: u8,
+    pub cache_size: Option<u16>,
+    pub challenge: [u8; CHALLENGE_SIZE],
+    sent: Instant,
+}
+
+impl Challenge {
+    pub fn is_expired(&self, now: Instant) -> bool
+    {
+        now.duration_since(self.sent) > CHALLENGE_TIMEOUT
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Instant,
+}
+
+pub struct Authenticator {
+    credentials: Credentials,
+    failures: HashMap<IpAddr, Failures>,
+}
+
+impl Authenticator {
+    pub fn new(credentials: Credentials) -> Self
+    {
+        Authenticator {
+            credentials,
+            failures: HashMap::new(),
+        }
+    }
+
+    pub fn is_locked_out(&mut self, src: SocketAddr, now: Instant) -> bool
+    {
+        self.failures.retain(|_, failures| now.duration_since(failures.last) < FORGET_AFTER);
+
+        match self.failures.get(&src.ip().to_canonical()) {
+            Some(failures) => now < failures.locked_until,
+            None => false
+        }
+    }
+
+    pub fn challenge(&self,
+                     src: SocketAddr,
+                     protocol_version: u8,
+                     cache_size: Option<u16>,
+                     now: Instant)
+        -> io::Result<Challenge>
+    {
+        Ok(Challenge {
+            src,
+            protocol_version,
+            cache_size,
+            challenge: random_key()?,
+            sent: now,
+        })
+    }
+
+    /// The role of an authenticated user, if it has one of its own.
+    pub fn role(&self, user: &str) -> Option<Role>
+    {
+        self.credentials.role(user)
+    }
+
+    /// Checks the response to a challenge. Failures are counted against the
+    /// client's address, success clears them.
+    pub fn verify(&mut self, challenge: &Challenge, user: &str, response: &[u8], now: Instant) -> bool
+    {
+        let valid = match self.credentials.secret(user) {
+            Some(secret) => {
+                let expected = hmac(secret.as_bytes(), &[&challenge.challenge, user.as_bytes()]);
+
+                (response.len() == RESPONSE_SIZE) & constant_time_eq(&expected, response)
+            },
+            None => false
+        };
+
+        let ip = challenge.src.ip().to_canonical();
+
+        if valid {
+            self.failures.remove(&ip);
+        } else {
+            let failures = self.failures.entry(ip).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: now,
+            });
+
+            failures.count += 1;
+            failures.last = now;
+
+            if failures.count >= FREE_ATTEMPTS {
+                let lockout = Duration::from_secs(1 << (failures.count - FREE_ATTEMPTS).min(16));
+                failures.locked_until = now + lockout.min(MAX_LOCKOUT);
+
+                println!("Auth: {} locked out for {:?}", ip, lockout.min(MAX_LOCKOUT));
+            }
+        }
+
+        valid
+    }
+}
+
+/// A random token a client resumes its session with from another address.
+pub fn session_token() -> io::Result<[u8; SESSION_TOKEN_SIZE]>
+{
+    random_key()
+}
+
+/// Compares secrets of the same length in constant time.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool
+{
+    (a.len() == b.len()) & (a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0)
+}
@@ -0,0 +1,166 @@
+// Mirror of the macroblock cache kept by the client.
+//
+// When the cache is enabled, the client stores every decoded macroblock that
+// is prefixed with a slot number in that slot, unless the slot already holds
+// a block with a newer timestamp. The server only refers to a slot once the
+// client has acknowledged the packet that filled it, so a reference never
+// points to a block the client might not have.
+
+pub const NO_SLOT: u16 = 0xFFFF;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    hash: u64,
+    timestamp: u32,
+    acked: bool,
+}
+
+#[derive(Debug)]
+pub struct BlockCache {
+    slots: Vec<Option<Entry>>,
+}
+
+impl BlockCache {
+    pub fn new(size: u16) -> Self
+    {
+        BlockCache {
+            slots: vec![None; size as usize],
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool
+    {
+        !self.slots.is_empty()
+    }
+
+    /// The slot a block with the given hash is stored in.
+    pub fn slot(&self, hash: u64) -> u16
+    {
+        (hash % self.slots.len() as u64) as u16
+    }
+
+    /// Returns the slot of an acknowledged block with the given hash.
+    pub fn lookup(&self, hash: u64) -> Option<u16>
+    {
+        if !self.is_enabled() {
+            return None
+        }
+
+        let slot = self.slot(hash);
+
+        match self.slots[slot as usize] {
+            Some(entry) if entry.acked & (entry.hash == hash) => Some(slot),
+            _ => None
+        }
+    }
+
+    /// Record that a block was sent to be stored in a slot.
+    pub fn store(&mut self, slot: u16, hash: u64, timestamp: u32)
+    {
+        self.slots[slot as usize] = Some(Entry {
+            hash,
+            timestamp,
+            acked: false,
+        });
+    }
+
+    /// Record that the block sent to a slot at the given timestamp arrived.
+    pub fn ack(&mut self, slot: u16, timestamp: u32)
+    {
+        if let Some(ref mut entry) = self.slots[slot as usize] {
+            if entry.timestamp == timestamp {
+                entry.acked = true;
+            }
+        }
+    }
+}
+
+/// FNV-1a hash of the pixels of a macroblock, given as a 4 bytes per pixel
+/// image of the given width.
+pub fn hash_macroblock(data: *mut i8, width: isize, x0: isize, y0: isize) -> u64
+{
+    let mut hash = FNV_OFFSET_BASIS;
+
+    for y in y0..y0 + 16 {
+        let row = (y * width + x0) * 4;
+
+        for i in 0..16 * 4 {
+            // Skip the unused fourth byte of each pixel.
+            if i % 4 == 3 {
+                continue
+            }
+
+            let byte = unsafe { *data.offset(row + i) as u8 };
+
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups_hit_acked_blocks_only()
+    {
+        let mut cache = BlockCache::new(16);
+        let hash = 0x1234;
+        let slot = cache.slot(hash);
+
+        cache.store(slot, hash, 7);
+        assert_eq!(cache.lookup(hash), None);
+
+        // An acknowledgement of an older packet for the slot does not count.
+        cache.ack(slot, 6);
+        assert_eq!(cache.lookup(hash), None);
+
+        cache.ack(slot, 7);
+        assert_eq!(cache.lookup(hash), Some(slot));
+
+        // Another block sharing the slot is a miss.
+        assert_eq!(cache.lookup(hash + 16), None);
+
+        // Storing it replaces the acknowledged block.
+        cache.store(slot, hash + 16, 8);
+        assert_eq!(cache.lookup(hash), None);
+        assert_eq!(cache.lookup(hash + 16), None);
+
+        cache.ack(slot, 8);
+        assert_eq!(cache.lookup(hash + 16), Some(slot));
+    }
+
+    #[test]
+    fn disabled()
+    {
+        let cache = BlockCache::new(0);
+
+        assert!(!cache.is_enabled());
+        assert_eq!(cache.lookup(0x1234), None);
+    }
+
+    #[test]
+    fn hash_skips_the_fourth_byte()
+    {
+        let mut image = vec![0i8; 32 * 16 * 4];
+        let hash = hash_macroblock(image.as_mut_ptr(), 32, 0, 0);
+
+        for pixel in image.chunks_mut(4) {
+            pixel[3] = 1;
+        }
+        assert_eq!(hash_macroblock(image.as_mut_ptr(), 32, 0, 0), hash);
+
+        // A pixel of the block next to it does not count either.
+        image[16 * 4] = 1;
+        assert_eq!(hash_macroblock(image.as_mut_ptr(), 32, 0, 0), hash);
+
+        image[0] = 1;
+        assert_ne!(hash_macroblock(image.as_mut_ptr(), 32, 0, 0), hash);
+    }
+}
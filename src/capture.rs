@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use std::fs;
+
+use std::io;
+
+use std::os::raw::{c_char, c_int};
+
+use std::path::Path;
+
+use std::slice;
+
+use std::sync::Arc;
+
+use super::x11::xlib::
+{
+    Display,
+    XImage
+};
+
+use super::xinterface;
+
+/// Offset and size of a captured area.
+pub type Area = (i32, i32, u32, u32);
+
+/// A captured image, destroyed once no context uses it anymore. A view cut
+/// from the screenshot of a monitor holds a copy of its pixels.
+#[derive(Debug)]
+pub enum Screenshot {
+    Captured(*mut XImage),
+    // The image points into the pixels.
+    Cut { image: Box<XImage>, _pixels: Vec<u8> },
+}
+
+// The image is only read once captured.
+unsafe impl Send for Screenshot {}
+unsafe impl Sync for Screenshot {}
+
+impl Screenshot {
+    pub fn image(&self) -> *mut XImage
+    {
+        match *self {
+            Screenshot::Captured(image) => image,
+            // Readers do not write to it.
+            Screenshot::Cut { ref image, .. } => &**image as *const XImage as *mut XImage
+        }
+    }
+
+    // Copies an area given relative to the image out of it, in the same
+    // format.
+    fn cut(&self, (x, y, width, height): Area) -> Screenshot
+    {
+        let mut image = unsafe { *self.image() };
+
+        let bytes_per_pixel = image.bits_per_pixel as usize / 8;
+        let stride = image.bytes_per_line as usize;
+        let row_size = bytes_per_pixel * width as usize;
+
+        let mut pixels = Vec::with_capacity(row_size * height as usize);
+
+        for row in y as usize..y as usize + height as usize {
+            let start = row * stride + x as usize * bytes_per_pixel;
+            pixels.extend_from_slice(unsafe { slice::from_raw_parts(image.data.add(start) as *const u8, row_size) });
+        }
+
+        image.width = width as c_int;
+        image.height = height as c_int;
+        image.bytes_per_line = row_size as c_int;
+        image.data = pixels.as_mut_ptr() as *mut c_char;
+
+        Screenshot::Cut { image: Box::new(image), _pixels: pixels }
+    }
+
+    /// Writes the image to a binary PPM file. Pixels are 32 bit BGRX, as
+    /// the encoder reads them.
+    pub fn write_ppm(&self, path: &Path) -> io::Result<()>
+    {
+        let (width, height, stride, data) = unsafe {
+            let image = &*self.image();
+            (image.width as usize, image.height as usize, image.bytes_per_line as usize, image.data as *const u8)
+        };
+
+        let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        out.reserve(3 * width * height);
+
+        for y in 0..height {
+            let row = unsafe { slice::from_raw_parts(data.add(y * stride), 4 * width) };
+
+            for pixel in row.chunks(4) {
+                out.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+        }
+
+        fs::write(path, out)
+    }
+}
+
+impl Drop for Screenshot {
+    fn drop(&mut self)
+    {
+        if let Screenshot::Captured(image) = *self {
+            xinterface::destroy_image(image);
+        }
+    }
+}
+
+/// Takes the screenshots of all sessions over one connection to the X
+/// server, which is shared behind a mutex. Sessions viewing the same area
+/// share the screenshot taken for it in a frame. The first view of a
+/// monitor in a frame is captured on its own, once there are others the
+/// monitor is captured and they are cut from it.
+#[derive(Debug)]
+pub struct Capture {
+    display: *mut Display,
+    window: u64,
+    frame: u64,
+    views: HashMap<Area, Arc<Screenshot>>,
+    // Monitors viewed in the frame, with their screenshot once taken.
+    monitors: HashMap<Area, Option<Screenshot>>,
+}
+
+// The display is only used by one thread at a time.
+unsafe impl Send for Capture {}
+
+impl Capture {
+    pub fn new() -> Self
+    {
+        let display = xinterface::open_display();
+
+        Capture {
+            display,
+            window: xinterface::get_root_window(display),
+            frame: 0,
+            views: HashMap::new(),
+            monitors: HashMap::new(),
+        }
+    }
+
+    /// A screenshot of the area of the monitor for the given frame number,
+    /// taken unless another session took it in the same frame. Without a
+    /// frame number a new screenshot is taken.
+    pub fn screenshot(&mut self, frame: Option<u64>, monitor: Area, area: Area) -> Arc<Screenshot>
+    {
+        let frame = match frame {
+            Some(frame) => frame,
+            None => return Arc::new(self.capture(area))
+        };
+
+        if frame != self.frame {
+            self.frame = frame;
+            self.views.clear();
+            self.monitors.clear();
+        }
+
+        if let Some(view) = self.views.get(&area) {
+            return view.clone()
+        }
+
+        let (x, y, width, height) = area;
+        let cut = (x - monitor.0, y - monitor.1, width, height);
+
+        let view = match self.monitors.get(&monitor) {
+            // An area reaching past the monitor is captured on its own.
+            _ if !contains(monitor, area) => self.capture(area),
+            Some(Some(screenshot)) => screenshot.cut(cut),
+            Some(None) => {
+                let screenshot = self.capture(monitor);
+                let view = screenshot.cut(cut);
+
+                self.monitors.insert(monitor, Some(screenshot));
+                view
+            },
+            None => {
+                self.monitors.insert(monitor, None);
+                self.capture(area)
+            }
+        };
+
+        let view = Arc::new(view);
+        self.views.insert(area, view.clone());
+
+        view
+    }
+
+    fn capture(&self, (x, y, width, height): Area) -> Screenshot
+    {
+        Screenshot::Captured(xinterface::get_image(self.display, self.window, width, x, height, y))
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self)
+    {
+        self.views.clear();
+        self.monitors.clear();
+        xinterface::close_display(self.display);
+    }
+}
+
+// Whether the area lies within the monitor.
+fn contains((x, y, width, height): Area, (area_x, area_y, area_width, area_height): Area) -> bool
+{
+    (area_x >= x) & (area_y >= y)
+        & (area_x + area_width as i32 <= x + width as i32)
+        & (area_y + area_height as i32 <= y + height as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem;
+
+    // A 32 bit image of the given size whose pixels hold their own index.
+    fn screenshot(width: u32, height: u32) -> Screenshot
+    {
+        let mut pixels: Vec<u8> = (0..width * height).flat_map(|i| i.to_le_bytes()).collect();
+
+        let mut image: XImage = unsafe { mem::zeroed() };
+        image.width = width as c_int;
+        image.height = height as c_int;
+        image.bits_per_pixel = 32;
+        image.bytes_per_line = 4 * width as c_int;
+        image.data = pixels.as_mut_ptr() as *mut c_char;
+
+        Screenshot::Cut { image: Box::new(image), _pixels: pixels }
+    }
+
+    fn pixel(screenshot: &Screenshot, x: usize, y: usize) -> u32
+    {
+        let image = unsafe { &*screenshot.image() };
+        let start = y * image.bytes_per_line as usize + 4 * x;
+        let bytes = unsafe { slice::from_raw_parts(image.data.add(start) as *const u8, 4) };
+
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    #[test]
+    fn cut_copies_the_area()
+    {
+        let monitor = screenshot(10, 8);
+        let view = monitor.cut((3, 2, 4, 5));
+
+        let image = unsafe { &*view.image() };
+        assert_eq!((image.width, image.height, image.bytes_per_line), (4, 5, 16));
+
+        for y in 0..5 {
+            for x in 0..4 {
+                assert_eq!(pixel(&view, x, y), ((y + 2) * 10 + x + 3) as u32);
+            }
+        }
+    }
+
+    #[test]
+    fn contains_areas_within_the_monitor()
+    {
+        let monitor = (1920, 0, 1280, 1024);
+
+        assert!(contains(monitor, monitor));
+        assert!(contains(monitor, (1920, 0, 640, 368)));
+        assert!(contains(monitor, (2560, 656, 640, 368)));
+
+        assert!(!contains(monitor, (1900, 0, 640, 368)));
+        assert!(!contains(monitor, (2561, 656, 640, 368)));
+        assert!(!contains(monitor, (2560, 657, 640, 368)));
+    }
+}
@@ -0,0 +1,344 @@
+// Messages from the client. Every message is an opcode followed by fields of
+// a fixed size, except for the handshake, the keyboard text, the list of
+// acknowledged packet ids and the user name of an auth response, whose sizes
+// follow from the message length or a length byte. Numbers are big endian.
+//
+// Decoding checks the length of every message before reading it, so a
+// malformed message is an error rather than a panic. A message that decodes
+// encodes to the same bytes.
+
+use std::fmt;
+
+use std::str;
+
+use super::auth::{RESPONSE_SIZE, SESSION_TOKEN_SIZE};
+
+pub const OPCODE_RECEIVE_HANDSHAKE: u8              = 0;
+pub const OPCODE_RECEIVE_REQUEST_SCREEN_INFO: u8    = 1;
+pub const OPCODE_RECEIVE_REQUEST_VIEW: u8           = 2;
+pub const OPCODE_RECEIVE_REFRESH: u8                = 3;
+pub const OPCODE_RECEIVE_CLOSE: u8                  = 4;
+pub const OPCODE_RECEIVE_EXIT: u8                   = 5;
+pub const OPCODE_RECEIVE_LEFT_CLICK: u8             = 6;
+pub const OPCODE_RECEIVE_RIGHT_CLICK: u8            = 7;
+pub const OPCODE_RECEIVE_DOUBLE_CLICK: u8           = 8;
+pub const OPCODE_RECEIVE_DRAG: u8                   = 9;
+pub const OPCODE_RECEIVE_KEYBOARD: u8               = 10;
+pub const OPCODE_RECEIVE_ACK: u8                    = 11;
+pub const OPCODE_RECEIVE_HEARTBEAT: u8              = 12;
+pub const OPCODE_RECEIVE_AUTH_RESPONSE: u8          = 13;
+pub const OPCODE_RECEIVE_REQUEST_STATS: u8          = 14;
+pub const OPCODE_RECEIVE_MTU_PROBE_ACK: u8          = 15;
+pub const OPCODE_RECEIVE_RESUME: u8                 = 16;
+
+/// The protocol versions a client supports and what it asks for. A
+/// liveness timeout is only sent along with a cache size, a version 2
+/// handshake sends a negotiation instead of either.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub min_version: u8,
+    pub max_version: u8,
+    pub cache_size: Option<u16>,
+    pub liveness_timeout: Option<u16>,
+    pub negotiation: Option<Negotiation>,
+}
+
+/// The capabilities a version 2 client supports and the parameters it asks
+/// for, see negotiation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Negotiation {
+    pub capabilities: u32,
+    pub params: Vec<(u8, u16)>, // Parameter id and value, at most 255
+}
+
+impl Negotiation {
+    /// The value of the first parameter with the given id.
+    pub fn param(&self, id: u8) -> Option<u16>
+    {
+        self.params.iter().find(|&&(param, _)| param == id).map(|&(_, value)| value)
+    }
+}
+
+/// A point in the view of the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Click {
+    pub x: u16,
+    pub y: u16,
+}
+
+/// A point on a given screen and segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub x: u16,
+    pub y: u16,
+    pub screen: u8,
+    pub segment: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthResponse {
+    pub user: String,
+    pub response: [u8; RESPONSE_SIZE],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    Handshake(Handshake),
+    RequestScreenInfo,
+    RequestView(u8, u8), // Screen and segment
+    Refresh,
+    Close,
+    Exit,
+    LeftClick(Click),
+    RightClick(Click),
+    DoubleClick(Click),
+    Drag(Position, Position),
+    Keyboard(String),
+    Ack(Vec<u32>), // Packet ids, at most 255
+    Heartbeat,
+    AuthResponse(AuthResponse),
+    RequestStats,
+    MtuProbeAck(u16),
+    Resume([u8; SESSION_TOKEN_SIZE]),
+}
+
+/// Why a message could not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeError {
+    Empty,
+    UnknownOpcode(u8),
+    // Opcode and length of a message that is too short or too long
+    Length(u8, usize),
+    // Opcode of a message with text that is not UTF-8
+    Utf8(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            DecodeError::Empty => write!(f, "empty message"),
+            DecodeError::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+            DecodeError::Length(opcode, length) => {
+                write!(f, "message with opcode {} has an invalid length of {} bytes", opcode, length)
+            },
+            DecodeError::Utf8(opcode) => write!(f, "message with opcode {} holds invalid UTF-8", opcode)
+        }
+    }
+}
+
+impl ClientMessage {
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError>
+    {
+        let opcode = match buf.first() {
+            Some(&opcode) => opcode,
+            None => return Err(DecodeError::Empty)
+        };
+
+        let length_error = DecodeError::Length(opcode, buf.len());
+        let body = &buf[1..];
+
+        // Messages of a fixed size.
+        let fixed = |size: usize| {
+            if body.len() == size { Ok(body) } else { Err(length_error) }
+        };
+
+        let message = match opcode {
+            // The versions, then a cache size and a liveness timeout, or the
+            // u32 capabilities, the number of parameters and the parameters.
+            OPCODE_RECEIVE_HANDSHAKE => {
+                let (cache_size, liveness_timeout, negotiation) = match body.len() {
+                    2 => (None, None, None),
+                    4 => (Some(u16_at(body, 2)), None, None),
+                    6 => (Some(u16_at(body, 2)), Some(u16_at(body, 4)), None),
+                    length if (length >= 7) && (length == 7 + 3 * body[6] as usize) => {
+                        let params = body[7..].chunks(3).map(|param| (param[0], u16_at(param, 1))).collect();
+
+                        (None, None, Some(Negotiation { capabilities: u32_at(body, 2), params }))
+                    },
+                    _ => return Err(length_error)
+                };
+
+                ClientMessage::Handshake(Handshake {
+                    min_version: body[0],
+                    max_version: body[1],
+                    cache_size,
+                    liveness_timeout,
+                    negotiation,
+                })
+            },
+            OPCODE_RECEIVE_REQUEST_SCREEN_INFO => fixed(0).map(|_| ClientMessage::RequestScreenInfo)?,
+            OPCODE_RECEIVE_REQUEST_VIEW => fixed(2).map(|body| ClientMessage::RequestView(body[0], body[1]))?,
+            OPCODE_RECEIVE_REFRESH => fixed(0).map(|_| ClientMessage::Refresh)?,
+            OPCODE_RECEIVE_CLOSE => fixed(0).map(|_| ClientMessage::Close)?,
+            OPCODE_RECEIVE_EXIT => fixed(0).map(|_| ClientMessage::Exit)?,
+            OPCODE_RECEIVE_LEFT_CLICK => ClientMessage::LeftClick(click(fixed(4)?)),
+            OPCODE_RECEIVE_RIGHT_CLICK => ClientMessage::RightClick(click(fixed(4)?)),
+            OPCODE_RECEIVE_DOUBLE_CLICK => ClientMessage::DoubleClick(click(fixed(4)?)),
+            OPCODE_RECEIVE_DRAG => {
+                let body = fixed(12)?;
+                ClientMessage::Drag(position(&body[..6]), position(&body[6..]))
+            },
+            OPCODE_RECEIVE_KEYBOARD => match str::from_utf8(body) {
+                Ok(text) => ClientMessage::Keyboard(text.to_string()),
+                Err(_) => return Err(DecodeError::Utf8(opcode))
+            },
+            // The number of ids, then the ids.
+            OPCODE_RECEIVE_ACK => {
+                let count = *body.first().ok_or(length_error)? as usize;
+                let ids = &body[1..];
+
+                if ids.len() != 4 * count {
+                    return Err(length_error)
+                }
+
+                ClientMessage::Ack(ids.chunks(4).map(|id| u32_at(id, 0)).collect())
+            },
+            OPCODE_RECEIVE_HEARTBEAT => fixed(0).map(|_| ClientMessage::Heartbeat)?,
+            // The length of the user name, the user name and the HMAC.
+            OPCODE_RECEIVE_AUTH_RESPONSE => {
+                let user_length = *body.first().ok_or(length_error)? as usize;
+
+                if body.len() != 1 + user_length + RESPONSE_SIZE {
+                    return Err(length_error)
+                }
+
+                let user = match str::from_utf8(&body[1..1 + user_length]) {
+                    Ok(user) => user.to_string(),
+                    Err(_) => return Err(DecodeError::Utf8(opcode))
+                };
+
+                let mut response = [0u8; RESPONSE_SIZE];
+                response.copy_from_slice(&body[1 + user_length..]);
+
+                ClientMessage::AuthResponse(AuthResponse { user, response })
+            },
+            OPCODE_RECEIVE_REQUEST_STATS => fixed(0).map(|_| ClientMessage::RequestStats)?,
+            OPCODE_RECEIVE_MTU_PROBE_ACK => ClientMessage::MtuProbeAck(u16_at(fixed(2)?, 0)),
+            OPCODE_RECEIVE_RESUME => {
+                let mut token = [0u8; SESSION_TOKEN_SIZE];
+                token.copy_from_slice(fixed(SESSION_TOKEN_SIZE)?);
+
+                ClientMessage::Resume(token)
+            },
+            _ => return Err(DecodeError::UnknownOpcode(opcode))
+        };
+
+        Ok(message)
+    }
+}
+
+#[allow(dead_code)]
+impl ClientMessage {
+    /// The message as the client sends it, for clients written in Rust.
+    /// Acks of more than 255 ids and user names longer than 255 bytes do
+    /// not fit the format.
+    pub fn encode(&self) -> Vec<u8>
+    {
+        fn push_click(out: &mut Vec<u8>, opcode: u8, click: Click)
+        {
+            out.push(opcode);
+            out.extend_from_slice(&click.x.to_be_bytes());
+            out.extend_from_slice(&click.y.to_be_bytes());
+        }
+
+        let mut out = Vec::new();
+
+        match *self {
+            ClientMessage::Handshake(ref handshake) => {
+                out.extend_from_slice(&[OPCODE_RECEIVE_HANDSHAKE, handshake.min_version, handshake.max_version]);
+
+                if let Some(ref negotiation) = handshake.negotiation {
+                    out.extend_from_slice(&negotiation.capabilities.to_be_bytes());
+                    out.push(negotiation.params.len() as u8);
+
+                    for &(id, value) in &negotiation.params {
+                        out.push(id);
+                        out.extend_from_slice(&value.to_be_bytes());
+                    }
+                } else if handshake.cache_size.is_some() | handshake.liveness_timeout.is_some() {
+                    out.extend_from_slice(&handshake.cache_size.unwrap_or(0).to_be_bytes());
+
+                    if let Some(timeout) = handshake.liveness_timeout {
+                        out.extend_from_slice(&timeout.to_be_bytes());
+                    }
+                }
+            },
+            ClientMessage::RequestScreenInfo => out.push(OPCODE_RECEIVE_REQUEST_SCREEN_INFO),
+            ClientMessage::RequestView(screen, segment) => {
+                out.extend_from_slice(&[OPCODE_RECEIVE_REQUEST_VIEW, screen, segment]);
+            },
+            ClientMessage::Refresh => out.push(OPCODE_RECEIVE_REFRESH),
+            ClientMessage::Close => out.push(OPCODE_RECEIVE_CLOSE),
+            ClientMessage::Exit => out.push(OPCODE_RECEIVE_EXIT),
+            ClientMessage::LeftClick(click) => push_click(&mut out, OPCODE_RECEIVE_LEFT_CLICK, click),
+            ClientMessage::RightClick(click) => push_click(&mut out, OPCODE_RECEIVE_RIGHT_CLICK, click),
+            ClientMessage::DoubleClick(click) => push_click(&mut out, OPCODE_RECEIVE_DOUBLE_CLICK, click),
+            ClientMessage::Drag(from, to) => {
+                out.push(OPCODE_RECEIVE_DRAG);
+
+                for position in &[from, to] {
+                    out.extend_from_slice(&position.x.to_be_bytes());
+                    out.extend_from_slice(&position.y.to_be_bytes());
+                    out.extend_from_slice(&[position.screen, position.segment]);
+                }
+            },
+            ClientMessage::Keyboard(ref text) => {
+                out.push(OPCODE_RECEIVE_KEYBOARD);
+                out.extend_from_slice(text.as_bytes());
+            },
+            ClientMessage::Ack(ref ids) => {
+                out.extend_from_slice(&[OPCODE_RECEIVE_ACK, ids.len() as u8]);
+
+                for id in ids {
+                    out.extend_from_slice(&id.to_be_bytes());
+                }
+            },
+            ClientMessage::Heartbeat => out.push(OPCODE_RECEIVE_HEARTBEAT),
+            ClientMessage::AuthResponse(ref auth) => {
+                out.extend_from_slice(&[OPCODE_RECEIVE_AUTH_RESPONSE, auth.user.len() as u8]);
+                out.extend_from_slice(auth.user.as_bytes());
+                out.extend_from_slice(&auth.response);
+            },
+            ClientMessage::RequestStats => out.push(OPCODE_RECEIVE_REQUEST_STATS),
+            ClientMessage::MtuProbeAck(size) => {
+                out.push(OPCODE_RECEIVE_MTU_PROBE_ACK);
+                out.extend_from_slice(&size.to_be_bytes());
+            },
+            ClientMessage::Resume(ref token) => {
+                out.push(OPCODE_RECEIVE_RESUME);
+                out.extend_from_slice(token);
+            }
+        }
+
+        out
+    }
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16
+{
+    u16::from_be_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32
+{
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn click(body: &[u8]) -> Click
+{
+    Click {
+        x: u16_at(body, 0),
+        y: u16_at(body, 2),
+    }
+}
+
+fn position(body: &[u8]) -> Position
+{
+    Position {
+        x: u16_at(body, 0),
+        y: u16_at(body, 2),
+        screen: body[4],
+        segment: body[5],
+    }
+}
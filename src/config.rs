@@ -0,0 +1,428 @@
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+
+use super::auth::{Credentials, Role};
+
+use super::crypto::{to_hex, KeyPair, OVERHEAD};
+
+use super::fec::{FecMode, PARITY_HEADER_SIZE};
+
+use super::heartbeat::{MIN_LIVENESS_TIMEOUT, MAX_LIVENESS_TIMEOUT};
+
+use super::metric::Metric;
+
+use super::net::BindAddress;
+
+use super::packet::DEFAULT_PACKET_SIZE;
+
+// Datagram size a session starts with when the path MTU is probed. It fits
+// the smallest MTU of IPv6, 1280 bytes, less the IPv6 and UDP headers.
+pub const PROBE_START_SIZE: usize = 1232;
+
+// Bounds of the datagram size. Datagrams must hold a fragment with some data,
+// and at most fill a UDP datagram over IPv4.
+pub const MIN_DATAGRAM_SIZE: usize = 256;
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+const USAGE: &str = "\
+Usage: screen_server [options]
+
+Options:
+    --metric NAME            Block change metric: sse, max-abs, luma or ssim (default: sse)
+    --threshold N            Error above which a macroblock is sent (default depends on metric)
+    --tolerance N            Per channel difference below which a pixel counts as unchanged (default: 4)
+    --max-stale-frames N     Frames after which a block with changed pixels is sent regardless of the threshold (default: 10)
+    --frame-budget BYTES     Maximum number of bytes of image data sent per frame, 0 for no limit (default: 65536)
+    --fps N                  Highest number of frames per second (default: 10)
+    --quality N              Highest JPEG quality, 1 to 100 (default: 50)
+    --target-bitrate BITS    Bitrate in bits per second the rate control aims for, 0 to estimate it from acknowledgements (default: 0)
+    --cache-size N           Largest number of macroblocks a client may cache, 0 to disable the cache (default: 1024)
+    --retry-frames N         Frames after which an unacknowledged block is sent again (default: 5)
+    --transport NAME         Transport to the client: udp, tcp, or websocket, which also serves a
+                             browser viewer over HTTP on the same port (default: udp)
+    --allow-origin ORIGIN    Origin of a page besides the server's own that may open WebSocket sessions,
+                             such as https://example.com, may be given more than once
+    --bind ADDRESS           Address both sockets are bound to, an IPv6 address may end in %SCOPE_ID
+                             (default: ::, which accepts both IPv6 and IPv4 clients)
+    --ipv6-only              Do not accept IPv4 clients on an IPv6 address
+    --port N                 Port client messages are received on (default: 9998)
+    --max-clients N          Largest number of clients connected at once (default: 4)
+    --liveness-timeout SECS  Time without heartbeats after which a client counts as gone, unless it asks
+                             for another between 2 and 60 in the handshake (default: 5)
+    --resume-grace SECS      Time a UDP client that went silent may resume its session from another
+                             address, 0 to close the session at once (default: 30)
+    --data-port N            Port replies are sent from, 0 for any free port (default: 9999)
+    --reply MODE             Where UDP replies go: port:N for port N of the client's address, source
+                             for the client's source address and port, or same-socket to also send from
+                             the receiving socket, which works through NAT (default: port:36492)
+    --secure                 Encrypt UDP sessions, clients must know the server's public key
+    --key-file PATH          File holding the server's private key, created if missing
+                             (default: screen_server.key)
+    --max-datagram BYTES     Largest UDP datagram sent, larger macroblocks are split into fragments
+                             (default: 1000)
+    --probe-mtu              Start with datagrams of at most 1232 bytes and probe the path for larger
+                             ones, up to --max-datagram
+    --receive-buffer BYTES   Size of the buffer client datagrams are received in (default: 1500)
+    --fec MODE               Parity packets over image packets so clients recover single losses: off,
+                             auto to follow the measured loss, or N for one per N packets (default: off)
+    --password-file PATH     Require clients to prove they know the password, the first line of the
+                             file, needs --secure over udp
+    --token-file PATH        Require clients to prove they know the token of a user, the file holds
+                             one user name, token and optionally role per line, needs --secure over udp
+    --role ROLE              Role of clients without one of their own: view-only, interactive, or
+                             admin, which may also shut the server down (default: interactive)
+    --control-socket PATH    Unix socket on which the user running the server can list and kick
+                             clients, change quality and fps, take snapshots and shut down
+    --help                   Print this message";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub metric: Metric,
+    pub threshold: i64,
+    pub tolerance: u8,
+    pub max_stale_frames: u32,
+    pub frame_budget: usize,
+    pub fps: u64,
+    pub quality: u8,
+    pub target_bitrate: u64,
+    pub cache_size: u16,
+    pub retry_frames: u32,
+    pub transport: Transport,
+    pub bind_address: BindAddress,
+    pub v6_only: bool,
+    pub port: u16,
+    pub data_port: u16,
+    pub reply: ReplyMode,
+    pub secure: bool,
+    pub key_file: PathBuf,
+    // Loaded from the key file when secure.
+    pub server_keys: Option<KeyPair>,
+    pub password_file: Option<PathBuf>,
+    pub token_file: Option<PathBuf>,
+    // The password or the tokens loaded from their file.
+    pub credentials: Option<Credentials>,
+    pub role: Role,
+    // Origins besides the server's own whose pages may open WebSocket
+    // sessions.
+    pub allowed_origins: Vec<String>,
+    pub max_datagram_size: usize,
+    pub probe_mtu: bool,
+    pub receive_buffer: usize,
+    pub fec: FecMode,
+    pub max_clients: usize,
+    pub liveness_timeout: u16,
+    pub resume_grace: u64,
+    pub control_socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    WebSocket,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String>
+    {
+        match s {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            "websocket" => Ok(Transport::WebSocket),
+            _ => Err(format!("unknown transport '{}'", s))
+        }
+    }
+}
+
+/// How replies reach the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyMode {
+    /// Send from the data port to a fixed port of the client's address.
+    Port(u16),
+    /// Send from the data port to the address and port the client sent from.
+    Source,
+    /// Send from the receiving socket to the address and port the client
+    /// sent from, so replies pass the mapping a NAT made for the client.
+    SameSocket,
+}
+
+impl FromStr for ReplyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String>
+    {
+        match s {
+            "source" => Ok(ReplyMode::Source),
+            "same-socket" => Ok(ReplyMode::SameSocket),
+            _ if s.starts_with("port:") => {
+                s[5..].parse()
+                    .map(ReplyMode::Port)
+                    .map_err(|_| format!("invalid reply port '{}'", &s[5..]))
+            },
+            _ => Err(format!("unknown reply mode '{}'", s))
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self
+    {
+        Config {
+            metric: Metric::Sse,
+            threshold: Metric::Sse.default_threshold(),
+            tolerance: 4,
+            max_stale_frames: 10,
+            frame_budget: 65536,
+            fps: 10,
+            quality: 50,
+            target_bitrate: 0,
+            cache_size: 1024,
+            retry_frames: 5,
+            transport: Transport::Udp,
+            bind_address: BindAddress::any(),
+            v6_only: false,
+            port: 9998,
+            data_port: 9999,
+            reply: ReplyMode::Port(36492),
+            secure: false,
+            key_file: PathBuf::from("screen_server.key"),
+            server_keys: None,
+            password_file: None,
+            token_file: None,
+            credentials: None,
+            role: Role::Interactive,
+            allowed_origins: Vec::new(),
+            max_datagram_size: DEFAULT_PACKET_SIZE,
+            probe_mtu: false,
+            receive_buffer: 1500,
+            fec: FecMode::Off,
+            max_clients: 4,
+            liveness_timeout: 5,
+            resume_grace: 30,
+            control_socket: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parse the command line, printing the usage and exiting on errors.
+    pub fn from_args() -> Self
+    {
+        let args: Vec<String> = env::args().skip(1).collect();
+
+        let mut config = match Self::parse(&args) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("{}\n\n{}", e, USAGE);
+                process::exit(1);
+            }
+        };
+
+        if config.secure {
+            match KeyPair::load_or_generate(&config.key_file) {
+                Ok(keys) => {
+                    println!("Secure: Public key {}", to_hex(&keys.public));
+                    config.server_keys = Some(keys);
+                },
+                Err(e) => {
+                    println!("Could not load key file {}: {}", config.key_file.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        if let Some(ref path) = config.password_file {
+            match Credentials::load_password(path) {
+                Ok(password) => config.credentials = Some(password),
+                Err(e) => {
+                    println!("Could not load password file {}: {}", path.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        if let Some(ref path) = config.token_file {
+            match Credentials::load_tokens(path) {
+                Ok(tokens) => config.credentials = Some(tokens),
+                Err(e) => {
+                    println!("Could not load token file {}: {}", path.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        config
+    }
+
+    pub fn parse(args: &[String]) -> Result<Self, String>
+    {
+        let mut config = Config::default();
+        let mut threshold = None;
+        let mut it = args.iter();
+
+        while let Some(arg) = it.next() {
+            match arg.as_str() {
+                "--metric" => config.metric = value(&mut it, arg)?,
+                "--threshold" => threshold = Some(value(&mut it, arg)?),
+                "--tolerance" => config.tolerance = value(&mut it, arg)?,
+                "--max-stale-frames" => config.max_stale_frames = value(&mut it, arg)?,
+                "--frame-budget" => config.frame_budget = value(&mut it, arg)?,
+                "--fps" => config.fps = value(&mut it, arg)?,
+                "--quality" => config.quality = value(&mut it, arg)?,
+                "--target-bitrate" => config.target_bitrate = value(&mut it, arg)?,
+                "--cache-size" => config.cache_size = value(&mut it, arg)?,
+                "--retry-frames" => config.retry_frames = value(&mut it, arg)?,
+                "--transport" => config.transport = value(&mut it, arg)?,
+                "--bind" => config.bind_address = value(&mut it, arg)?,
+                "--ipv6-only" => config.v6_only = true,
+                "--port" => config.port = value(&mut it, arg)?,
+                "--data-port" => config.data_port = value(&mut it, arg)?,
+                "--reply" => config.reply = value(&mut it, arg)?,
+                "--secure" => config.secure = true,
+                "--key-file" => config.key_file = value(&mut it, arg)?,
+                "--password-file" => config.password_file = Some(value(&mut it, arg)?),
+                "--token-file" => config.token_file = Some(value(&mut it, arg)?),
+                "--role" => config.role = value(&mut it, arg)?,
+                "--allow-origin" => config.allowed_origins.push(value(&mut it, arg)?),
+                "--max-datagram" => config.max_datagram_size = value(&mut it, arg)?,
+                "--probe-mtu" => config.probe_mtu = true,
+                "--receive-buffer" => config.receive_buffer = value(&mut it, arg)?,
+                "--fec" => config.fec = value(&mut it, arg)?,
+                "--max-clients" => config.max_clients = value(&mut it, arg)?,
+                "--liveness-timeout" => config.liveness_timeout = value(&mut it, arg)?,
+                "--resume-grace" => config.resume_grace = value(&mut it, arg)?,
+                "--control-socket" => config.control_socket = Some(value(&mut it, arg)?),
+                "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                },
+                _ => return Err(format!("unknown option '{}'", arg))
+            }
+        }
+
+        config.threshold = threshold.unwrap_or(config.metric.default_threshold());
+
+        if (config.fps == 0) | (config.quality == 0) | (config.quality > 100) {
+            return Err("fps must be positive and quality between 1 and 100".to_string())
+        }
+
+        // A block with changed pixels is otherwise sent in every frame.
+        if config.max_stale_frames == 0 {
+            return Err("--max-stale-frames must be at least 1".to_string())
+        }
+
+        if config.max_clients == 0 {
+            return Err("at least one client must be allowed".to_string())
+        }
+
+        if (config.liveness_timeout < MIN_LIVENESS_TIMEOUT) | (config.liveness_timeout > MAX_LIVENESS_TIMEOUT) {
+            return Err(format!("the liveness timeout must be between {} and {} seconds", MIN_LIVENESS_TIMEOUT, MAX_LIVENESS_TIMEOUT))
+        }
+
+        if (config.transport == Transport::Udp)
+            & (config.data_port == config.port)
+            & (config.reply != ReplyMode::SameSocket)
+        {
+            return Err("the data port must differ from the port unless replies use the same socket".to_string())
+        }
+
+        if config.secure & (config.transport != Transport::Udp) {
+            return Err("--secure is only supported with the udp transport".to_string())
+        }
+
+        if config.password_file.is_some() & config.token_file.is_some() {
+            return Err("--password-file and --token-file cannot be combined".to_string())
+        }
+
+        // Over plain UDP anyone who can spoof the address of an authenticated
+        // client could send input in its name.
+        if (config.password_file.is_some() | config.token_file.is_some())
+            & (config.transport == Transport::Udp)
+            & !config.secure
+        {
+            return Err("--password-file and --token-file require --secure with the udp transport".to_string())
+        }
+
+        // Any page a browser opens may connect to the WebSocket port, only
+        // clients that authenticate may send input through it.
+        if (config.transport == Transport::WebSocket)
+            & config.password_file.is_none()
+            & config.token_file.is_none()
+            & (config.role != Role::ViewOnly)
+        {
+            return Err("--transport websocket requires --password-file or --token-file unless --role is view-only".to_string())
+        }
+
+        if (config.max_datagram_size < MIN_DATAGRAM_SIZE) | (config.max_datagram_size > MAX_DATAGRAM_SIZE) {
+            return Err(format!("the datagram size must be between {} and {}", MIN_DATAGRAM_SIZE, MAX_DATAGRAM_SIZE))
+        }
+
+        if config.probe_mtu & (config.transport != Transport::Udp) {
+            return Err("--probe-mtu is only supported with the udp transport".to_string())
+        }
+
+        if config.receive_buffer < MIN_DATAGRAM_SIZE {
+            return Err(format!("the receive buffer must hold at least {} bytes", MIN_DATAGRAM_SIZE))
+        }
+
+        if (config.fec != FecMode::Off) & (config.transport != Transport::Udp) {
+            return Err("--fec is only supported with the udp transport".to_string())
+        }
+
+        Ok(config)
+    }
+
+    /// Whether macroblocks too large for a datagram are split into
+    /// fragments. Over TCP messages may be of any size.
+    pub fn fragments(&self) -> bool
+    {
+        self.transport == Transport::Udp
+    }
+
+    /// The plaintext a datagram of the given size holds, which leaves room
+    /// for sealing it with --secure.
+    pub fn plaintext_size(&self, datagram_size: usize) -> usize
+    {
+        if self.secure {
+            datagram_size - 1 - OVERHEAD
+        } else {
+            datagram_size
+        }
+    }
+
+    /// The largest packet sent for a datagram of the given size. With --fec
+    /// a parity packet is somewhat larger than the packets it covers.
+    pub fn packet_size(&self, datagram_size: usize) -> usize
+    {
+        if self.fec != FecMode::Off {
+            self.plaintext_size(datagram_size) - PARITY_HEADER_SIZE
+        } else {
+            self.plaintext_size(datagram_size)
+        }
+    }
+
+    /// The largest packet a session starts with, given the largest datagram
+    /// it allows and whether its path is probed.
+    pub fn initial_packet_size(&self, max_datagram_size: usize, probe_mtu: bool) -> usize
+    {
+        if probe_mtu {
+            self.packet_size(max_datagram_size.min(PROBE_START_SIZE))
+        } else {
+            self.packet_size(max_datagram_size)
+        }
+    }
+}
+
+fn value<'a, I, T>(it: &mut I, option: &str) -> Result<T, String>
+    where I: Iterator<Item = &'a String>,
+          T: FromStr
+{
+    match it.next() {
+        Some(v) => v.parse().map_err(|_| format!("invalid value '{}' for {}", v, option)),
+        None => Err(format!("missing value for {}", option))
+    }
+}
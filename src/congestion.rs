@@ -0,0 +1,315 @@
+use std::time::{
+    Duration,
+    Instant
+};
+
+use super::packet::DEFAULT_PACKET_SIZE;
+
+// The window is counted in bytes, it grows by about one packet of the
+// default size per round trip.
+const SEGMENT_SIZE: usize = DEFAULT_PACKET_SIZE;
+
+// Window bounds in bytes. The initial window holds about ten packets, as
+// TCP's does since RFC 6928.
+const INITIAL_WINDOW: usize = 10 * SEGMENT_SIZE;
+const MIN_WINDOW: usize = 4 * SEGMENT_SIZE;
+const MAX_WINDOW: usize = 4 * 1024 * 1024;
+
+// Round trip times this far above the lowest one seen mean a queue is
+// building up along the path.
+const DELAY_FACTOR: f64 = 1.5;
+const DELAY_MARGIN_MS: u64 = 10;
+
+// The lowest round trip time is forgotten after this long, in case the path
+// changed.
+const MIN_RTT_LIFETIME: Duration = Duration::from_secs(10);
+
+// Window reductions on loss and on rising delay.
+const LOSS_DECREASE: f64 = 0.5;
+const DELAY_DECREASE: f64 = 0.85;
+
+// Packets go out somewhat faster than one window per round trip, so the
+// window rather than the pacing limits the rate.
+const PACING_GAIN: f64 = 1.25;
+
+const INITIAL_RTT_MS: u64 = 100;
+
+/// AIMD congestion control with an additional delay signal. The window of
+/// unacknowledged bytes grows by one packet per round trip, or doubles per
+/// round trip in slow start, and shrinks on loss or when round trip times
+/// rise well above the lowest one seen. At most one reduction happens per
+/// round trip: only packets sent after the last reduction can cause the
+/// next one.
+///
+/// Packets are paced at the window per round trip, or faster if needed to
+/// send the queued packets within the frame interval.
+#[derive(Debug)]
+pub struct CongestionController {
+    window: usize,
+    slow_start_threshold: usize,
+    in_flight: usize,
+
+    // The lowest round trip time and when it was measured.
+    min_rtt: Option<(Duration, Instant)>,
+    srtt: Duration,
+
+    // Packets with lower ids were sent before the last reduction.
+    recovery_id: u32,
+
+    next_send: Instant,
+}
+
+impl CongestionController {
+    pub fn new() -> Self
+    {
+        CongestionController {
+            window: INITIAL_WINDOW,
+            slow_start_threshold: MAX_WINDOW,
+            in_flight: 0,
+            min_rtt: None,
+            srtt: Duration::from_millis(INITIAL_RTT_MS),
+            recovery_id: 0,
+            next_send: Instant::now(),
+        }
+    }
+
+    /// Starts over on a new path. Losses of packets sent on the old one are
+    /// not held against it.
+    pub fn restart(&mut self, next_id: u32)
+    {
+        *self = CongestionController::new();
+        self.recovery_id = next_id;
+    }
+
+    /// Bytes that may be in flight.
+    pub fn window(&self) -> usize
+    {
+        self.window
+    }
+
+    /// Whether a packet of the given size fits the window. A single packet
+    /// is always allowed so that a small window cannot stall the sender.
+    pub fn can_send(&self, size: usize) -> bool
+    {
+        (self.in_flight == 0) | (self.in_flight + size <= self.window)
+    }
+
+    /// The time the next packet may be sent, or None if the window is full.
+    pub fn send_time(&self, size: usize) -> Option<Instant>
+    {
+        if self.can_send(size) {
+            Some(self.next_send)
+        } else {
+            None
+        }
+    }
+
+    /// Account for a sent packet. The queued bytes and the frame interval
+    /// set the lowest pacing rate.
+    pub fn on_send(&mut self, size: usize, queued: usize, frame_interval: Duration, now: Instant)
+    {
+        self.in_flight += size;
+
+        let window_rate = self.window as f64 * PACING_GAIN / self.srtt.as_secs_f64();
+        let frame_rate = queued as f64 / frame_interval.as_secs_f64().max(0.001);
+        let rate = window_rate.max(frame_rate);
+
+        let start = if self.next_send > now { self.next_send } else { now };
+        self.next_send = start + Duration::from_secs_f64(size as f64 / rate);
+    }
+
+    pub fn on_ack(&mut self, packet_id: u32, size: usize, rtt: Duration, next_id: u32, now: Instant)
+    {
+        self.in_flight = self.in_flight.saturating_sub(size);
+
+        self.srtt = (self.srtt * 7 + rtt) / 8;
+
+        let (min_rtt, measured) = match self.min_rtt {
+            Some((min_rtt, measured)) if (min_rtt <= rtt) & (now.duration_since(measured) < MIN_RTT_LIFETIME) => {
+                (min_rtt, measured)
+            },
+            _ => (rtt, now)
+        };
+
+        self.min_rtt = Some((min_rtt, measured));
+
+        let delay_limit = min_rtt.mul_f64(DELAY_FACTOR) + Duration::from_millis(DELAY_MARGIN_MS);
+
+        if rtt > delay_limit {
+            self.reduce(packet_id, DELAY_DECREASE, next_id);
+        } else if self.window < self.slow_start_threshold {
+            self.window = (self.window + size).min(MAX_WINDOW);
+        } else {
+            self.window = (self.window + SEGMENT_SIZE * size / self.window).min(MAX_WINDOW);
+        }
+    }
+
+    pub fn on_loss(&mut self, packet_id: u32, size: usize, next_id: u32)
+    {
+        self.in_flight = self.in_flight.saturating_sub(size);
+        self.reduce(packet_id, LOSS_DECREASE, next_id);
+    }
+
+    fn reduce(&mut self, packet_id: u32, factor: f64, next_id: u32)
+    {
+        if sent_before(packet_id, self.recovery_id) {
+            return
+        }
+
+        self.window = ((self.window as f64 * factor) as usize).max(MIN_WINDOW);
+        self.slow_start_threshold = self.window;
+        self.recovery_id = next_id;
+    }
+}
+
+// Whether packet id a was sent before b. Ids wrap around, the later one is
+// taken to be less than half the id space ahead.
+fn sent_before(a: u32, b: u32) -> bool
+{
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTT: Duration = Duration::from_millis(INITIAL_RTT_MS);
+
+    // Acknowledges a window of packets of the default size.
+    fn ack_window(controller: &mut CongestionController, next_id: &mut u32, now: Instant)
+    {
+        for _ in 0..controller.window / SEGMENT_SIZE {
+            controller.on_send(SEGMENT_SIZE, 0, RTT, now);
+            controller.on_ack(*next_id, SEGMENT_SIZE, RTT, *next_id + 1, now);
+            *next_id += 1;
+        }
+    }
+
+    #[test]
+    fn slow_start_doubles_per_round_trip()
+    {
+        let now = Instant::now();
+        let mut controller = CongestionController::new();
+        let mut next_id = 0;
+
+        for round in 1..=4 {
+            ack_window(&mut controller, &mut next_id, now);
+            assert_eq!(controller.window, INITIAL_WINDOW << round);
+        }
+
+        // After a loss it grows by about a packet per round trip.
+        controller.on_loss(next_id, SEGMENT_SIZE, next_id + 1);
+        next_id += 1;
+
+        let window = controller.window;
+        assert_eq!(window, (INITIAL_WINDOW << 4) / 2);
+
+        ack_window(&mut controller, &mut next_id, now);
+        assert!((controller.window > window) & (controller.window <= window + SEGMENT_SIZE));
+    }
+
+    #[test]
+    fn one_reduction_per_round_trip()
+    {
+        let mut controller = CongestionController::new();
+
+        // Packets 0 to 19 are in flight when 3 is lost.
+        controller.on_loss(3, SEGMENT_SIZE, 20);
+        assert_eq!(controller.window, INITIAL_WINDOW / 2);
+
+        // Losses of packets sent before the reduction do not count again.
+        controller.on_loss(5, SEGMENT_SIZE, 25);
+        controller.on_loss(19, SEGMENT_SIZE, 25);
+        assert_eq!(controller.window, INITIAL_WINDOW / 2);
+
+        // Nor does rising delay.
+        let now = Instant::now();
+        controller.on_ack(18, SEGMENT_SIZE, RTT, 25, now);
+        let window = controller.window;
+
+        controller.on_ack(17, SEGMENT_SIZE, RTT * 3, 25, now);
+        assert_eq!(controller.window, window);
+
+        controller.on_loss(20, SEGMENT_SIZE, 25);
+        assert_eq!(controller.window, (window / 2).max(MIN_WINDOW));
+    }
+
+    #[test]
+    fn rising_delay_reduces()
+    {
+        let now = Instant::now();
+        let mut controller = CongestionController::new();
+
+        controller.on_ack(0, SEGMENT_SIZE, RTT, 10, now);
+        let window = controller.window;
+
+        controller.on_ack(1, SEGMENT_SIZE, RTT * 2, 10, now);
+        assert_eq!(controller.window, (window as f64 * DELAY_DECREASE) as usize);
+    }
+
+    #[test]
+    fn reductions_across_the_id_wrap()
+    {
+        let mut controller = CongestionController::new();
+        controller.restart(u32::MAX - 2);
+
+        // Sent on the old path.
+        controller.on_loss(u32::MAX - 5, SEGMENT_SIZE, 3);
+        assert_eq!(controller.window, INITIAL_WINDOW);
+
+        controller.on_loss(1, SEGMENT_SIZE, 3);
+        assert_eq!(controller.window, INITIAL_WINDOW / 2);
+
+        assert!(sent_before(u32::MAX, 0));
+        assert!(!sent_before(0, u32::MAX));
+        assert!(!sent_before(7, 7));
+    }
+
+    #[test]
+    fn window_floor()
+    {
+        let mut controller = CongestionController::new();
+
+        for id in 0..20 {
+            controller.on_loss(id, SEGMENT_SIZE, id + 1);
+            assert!(controller.window >= MIN_WINDOW);
+        }
+
+        assert_eq!(controller.window, MIN_WINDOW);
+
+        // A single packet always goes out, more only within the window.
+        assert!(controller.can_send(MIN_WINDOW * 2));
+
+        controller.on_send(MIN_WINDOW, 0, RTT, Instant::now());
+        assert_eq!(controller.send_time(1), None);
+    }
+
+    #[test]
+    fn pacing_intervals()
+    {
+        let now = Instant::now();
+        let mut controller = CongestionController::new();
+
+        // A window per round trip, somewhat faster: 10 packets in 80 ms.
+        let interval = RTT.mul_f64(SEGMENT_SIZE as f64 / (INITIAL_WINDOW as f64 * PACING_GAIN));
+
+        controller.on_send(SEGMENT_SIZE, 0, RTT, now);
+        controller.on_send(SEGMENT_SIZE, 0, RTT, now);
+
+        let paced = controller.send_time(SEGMENT_SIZE).unwrap().duration_since(now);
+        assert!(paced.abs_diff(interval * 2) < Duration::from_micros(10));
+
+        // Faster when the queue would not go out within the frame interval,
+        // 100 packets in 50 ms.
+        let now = controller.next_send;
+        controller.on_send(SEGMENT_SIZE, 100 * SEGMENT_SIZE, Duration::from_millis(50), now);
+
+        let paced = controller.send_time(SEGMENT_SIZE).unwrap().duration_since(now);
+        assert!(paced.abs_diff(Duration::from_micros(500)) < Duration::from_micros(10));
+
+        // A send after an idle period is not paced against the past.
+        let later = now + Duration::from_secs(1);
+        controller.on_send(SEGMENT_SIZE, 0, RTT, later);
+        assert!(controller.send_time(SEGMENT_SIZE).unwrap() > later);
+    }
+}
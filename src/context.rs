@@ -2,6 +2,20 @@ use num_iter::range_step;
 
 use std::i64;
 
+// Upper bound on the number of lost blocks sent again per frame.
+const MAX_RETRIES_PER_FRAME: usize = 64;
+
+// The time a block may go unacknowledged doubles with each retry, up to
+// 2^MAX_RETRY_BACKOFF times the retry interval.
+const MAX_RETRY_BACKOFF: u32 = 3;
+
+// How long a closing context waits for the encoder to finish its image.
+const ENCODER_TIMEOUT_MS: u64 = 5000;
+
+use std::collections::HashSet;
+
+use std::sync::{Arc, Mutex};
+
 use std::sync::mpsc::
 {
     Sender,
@@ -13,12 +27,36 @@ use std::thread::{
     JoinHandle
 };
 
+use std::time::{
+    Duration,
+    Instant
+};
+
 use super::protocol::
 {
     ContextMessage,
     EncoderMessage
 };
 
+use super::capture::{
+    Capture,
+    Screenshot
+};
+
+use super::block_cache::{
+    self,
+    BlockCache,
+    NO_SLOT
+};
+
+use super::config::Config;
+
+use super::metric::
+{
+    BlockStats,
+    Metric
+};
+
 use super::monitor_info::MonitorInfo;
 
 use super::util::
@@ -28,11 +66,7 @@ use super::util::
     DataBox
 };
 
-use super::x11::xlib::
-{
-    Display,
-    XImage
-};
+use super::x11::xlib::XImage;
 
 use super::xinterface;
 
@@ -40,13 +74,15 @@ use super::xinterface;
 pub struct Context
 {
     image_pointer: Option<*mut XImage>,
-    display: *mut Display,
-    window: u64,
+    // Keeps the image alive while the context uses it.
+    screenshot: Option<Arc<Screenshot>>,
+    capture: Arc<Mutex<Capture>>,
     width: u32,
     height: u32,
     offset_x: i32,
     offset_y: i32,
     client_state: Vec<u8>,
+    acked_state: Vec<u8>,
     bpp: u32,
     raw_bpp: u32,
     size: usize,
@@ -58,6 +94,22 @@ pub struct Context
     n_blocks_x: u32,
     n_blocks_y: u32,
     errors: Vec<(i64, usize)>,
+    slots: Vec<u16>,
+    cached: Vec<(usize, u16)>,
+    hashes: Vec<u64>,
+    sent_slots: Vec<Option<(u32, u16)>>,
+    block_cache: BlockCache,
+    block_stats: Vec<BlockStats>,
+    stale_frames: Vec<u32>,
+    carried_frames: Vec<u32>,
+    lost: Vec<bool>,
+    overdue: Vec<bool>,
+    retries: Vec<u32>,
+    retry_frames: u32,
+    metric: Metric,
+    threshold: i64,
+    tolerance: u8,
+    max_stale_frames: u32,
     block_table: Vec<usize>,
     timestamp: u32,
     current_version: Vec<u32>,
@@ -67,66 +119,147 @@ pub struct Context
     segment_id: usize
 }
 
-pub fn start_context_thread(monitor_info: Vec<MonitorInfo>,
+// What the encoder is working on. Its answer holds the blocks it sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    FirstImage,
+    Frame,
+}
+
+pub fn start_context_thread(config: Config,
+                            monitor_info: Vec<MonitorInfo>,
+                            capture: Arc<Mutex<Capture>>,
                             to_encoder: Sender<EncoderMessage>,
                             receiver: Receiver<ContextMessage>)
     -> JoinHandle<()>
 {
     thread::spawn(move || {
-        let mut context = Context::new(monitor_info);
+        let mut context = Context::new(&config, monitor_info, capture);
+
+        // The encoder answers on the context's channel. Until it has, the
+        // screenshot it reads stays as it is: views and refreshes wait, and
+        // frames are skipped, so a slow encoder lowers the frame rate
+        // instead of queueing frames. Likewise while the sender's queue is
+        // backlogged.
+        let mut encoding = None;
+        let mut backlogged = false;
+        let mut view = None;
+        let mut refresh = false;
 
         loop {
             match receiver.recv() {
                 Ok(ContextMessage::RequestView(screen, segment)) => {
                     println!("Context: Request view ({}, {})", screen, segment);
-                    context.change_screen(screen as usize);
-                    context.change_segment(segment as usize);
-
-                    context.get_new_screenshot();
-                    context.set_initial_state();
-
-                    let data = get_data(context.image_pointer);
-                    let msg = EncoderMessage::FirstImage(data);
-
-                    to_encoder.send(msg).unwrap();
+                    view = Some((screen, segment));
                 },
                 Ok(ContextMessage::Refresh) => {
-                    context.get_new_screenshot();
-                    context.set_initial_state();
-
-                    let data = get_data(context.image_pointer);
-                    let msg = EncoderMessage::FirstImage(data);
-
-                    to_encoder.send(msg).unwrap();
+                    refresh = true;
+                },
+                Ok(ContextMessage::Encoded(timestamp, sent)) => {
+                    context.timestamp = timestamp;
+
+                    // Only the blocks the encoder managed to send within the
+                    // frame budget reach the client.
+                    match encoding.take() {
+                        Some(Encoding::FirstImage) => context.initial_state_sent(&sent),
+                        Some(Encoding::Frame) => context.update_client_state(&sent),
+                        None => ()
+                    }
                 },
                 Ok(ContextMessage::Close) => {
                     println!("Context: Close");
+
+                    if encoding.is_some() {
+                        wait_for_encoder(&receiver);
+                    }
+
                     context.close();
                     to_encoder.send(EncoderMessage::Close).unwrap();
                     return;
                 }
-                Ok(ContextMessage::NewScreenshot) => {
-                    context.get_new_screenshot();
-                    context.set_block_errors();
-                    context.update_client_state();
-
-                    let pnt = context.get_image_pointer();
-                    let err = context.errors.clone();
-                    let msg = EncoderMessage::DataAndErrors(pnt, err);
-
-                    to_encoder.send(msg).unwrap();
+                Ok(ContextMessage::NewScreenshot(frame)) => {
+                    if encoding.is_none() & view.is_none() & !refresh & !backlogged {
+                        context.get_new_screenshot(Some(frame));
+                        context.set_block_errors();
+                        context.find_cached_blocks();
+
+                        let pnt = context.get_image_pointer();
+                        let err = context.errors.clone();
+                        let slots = context.slots.clone();
+                        let cached = context.cached.clone();
+                        let msg = EncoderMessage::DataAndErrors(pnt, err, slots, cached);
+
+                        to_encoder.send(msg).unwrap();
+                        encoding = Some(Encoding::Frame);
+                    }
+                },
+                Ok(ContextMessage::CacheSize(size)) => {
+                    println!("Context: Cache size {}", size);
+                    context.block_cache = BlockCache::new(size);
+                    to_encoder.send(EncoderMessage::CacheSize(size)).unwrap();
+                },
+                Ok(ContextMessage::Capabilities(capabilities)) => {
+                    to_encoder.send(EncoderMessage::Capabilities(capabilities)).unwrap();
                 },
                 Ok(ContextMessage::AckPackets(timestamp, ids)) => {
                     context.handle_ack(timestamp, &ids);
                 },
+                Ok(ContextMessage::LostPackets(timestamp, ids)) => {
+                    context.handle_loss(timestamp, &ids);
+                },
+                Ok(ContextMessage::Rate(decision)) => {
+                    context.threshold = decision.threshold;
+                    to_encoder.send(EncoderMessage::Rate(decision)).unwrap();
+                },
+                Ok(ContextMessage::LinkStats(stats)) => {
+                    to_encoder.send(EncoderMessage::LinkStats(stats)).unwrap();
+                },
+                Ok(ContextMessage::MaxPacketSize(size)) => {
+                    to_encoder.send(EncoderMessage::MaxPacketSize(size)).unwrap();
+                },
+                Ok(ContextMessage::Backlog(backlog)) => {
+                    backlogged = backlog;
+                },
                 _ => panic!()
             };
+
+            // A new view or a refresh starts with a full image once the
+            // encoder is done.
+            if encoding.is_none() & (view.is_some() | refresh) {
+                if let Some((screen, segment)) = view.take() {
+                    context.change_screen(screen as usize);
+                    context.change_segment(segment as usize);
+                }
+
+                refresh = false;
+
+                context.get_new_screenshot(None);
+                context.set_initial_state();
+
+                let data = get_data(context.image_pointer);
+                to_encoder.send(EncoderMessage::FirstImage(data)).unwrap();
+                encoding = Some(Encoding::FirstImage);
+            }
         };
     })
 }
 
+// The encoder may still read the screenshot, which must outlive it. An
+// encoder that does not answer in time is gone.
+fn wait_for_encoder(receiver: &Receiver<ContextMessage>)
+{
+    let deadline = Instant::now() + Duration::from_millis(ENCODER_TIMEOUT_MS);
+
+    loop {
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(ContextMessage::Encoded(..)) | Err(_) => return,
+            _ => ()
+        }
+    }
+}
+
 impl Context {
-    pub fn new(monitor_info: Vec<MonitorInfo>) -> Self
+    pub fn new(config: &Config, monitor_info: Vec<MonitorInfo>, capture: Arc<Mutex<Capture>>) -> Self
     {
         let width = monitor_info[0].view_width;
         let height = monitor_info[0].view_height;
@@ -137,24 +270,24 @@ impl Context {
             panic!("height and width must be divisible by 16")
         }
 
-        let display = xinterface::open_display();
-
         let block_size = 8;
         let n_blocks_x = width / block_size;
         let n_blocks_y = height / block_size;
         let n_blocks = (n_blocks_x * n_blocks_y) as usize;
+        let n_macroblocks = n_blocks / 4;
 
         let bpp = 3;
 
         let mut c = Context {
             image_pointer: None,
-            display: display,
-            window: xinterface::get_root_window(display),
+            screenshot: None,
+            capture,
             width: width,
             height: height,
             offset_x: offset_x,
             offset_y: offset_y,
             client_state: vec![0u8; (height*width*bpp) as usize],
+            acked_state: vec![0u8; (height*width*bpp) as usize],
             bpp: bpp,
             raw_bpp: 4,
             block_size: 8,
@@ -165,11 +298,27 @@ impl Context {
             n_blocks: n_blocks,
             n_blocks_x: n_blocks_x,
             n_blocks_y: n_blocks_y,
-            errors: vec![(0i64, 0usize); n_blocks],
+            errors: Vec::with_capacity(n_macroblocks),
+            slots: Vec::with_capacity(n_macroblocks),
+            cached: Vec::with_capacity(n_macroblocks),
+            hashes: vec![0u64; n_macroblocks],
+            sent_slots: vec![None; n_macroblocks],
+            block_cache: BlockCache::new(0),
+            block_stats: vec![BlockStats::default(); n_macroblocks],
+            stale_frames: vec![0u32; n_macroblocks],
+            carried_frames: vec![0u32; n_macroblocks],
+            lost: vec![false; n_macroblocks],
+            overdue: vec![false; n_macroblocks],
+            retries: vec![0u32; n_macroblocks],
+            retry_frames: config.retry_frames,
+            metric: config.metric,
+            threshold: config.threshold,
+            tolerance: config.tolerance,
+            max_stale_frames: config.max_stale_frames,
             block_table: vec![0usize; (width*height) as usize],
             timestamp: 0,
-            current_version: vec![0u32; (n_blocks_x * n_blocks_y / 4) as usize],
-            most_recent_version: vec![0u32; (n_blocks_x * n_blocks_y / 4) as usize],
+            current_version: vec![0u32; n_macroblocks],
+            most_recent_version: vec![0u32; n_macroblocks],
             monitor_info: monitor_info,
             screen_id: 0,
             segment_id: 0,
@@ -228,20 +377,19 @@ impl Context {
     }
 
     // TODO: return pointer
-    fn get_new_screenshot(&mut self)
+    // Replaces the screenshot, the old one is destroyed once no other
+    // session uses it. Screenshots of a frame are shared, see capture.
+    fn get_new_screenshot(&mut self, frame: Option<u64>)
     {
-        // Delete old (is None if uninitialized)
-        if let Some(im_pointer) = self.image_pointer {
-            xinterface::destroy_image(im_pointer);
-            self.image_pointer = None;
-        }
-
         // Use a modern GPU...
-        let image = xinterface::get_image(self.display, self.window,
-                                          self.width, self.offset_x,
-                                          self.height, self.offset_y);
+        let monitor = &self.monitor_info[self.screen_id];
+
+        let screenshot = self.capture.lock().unwrap().screenshot(frame,
+                                                                 (monitor.offset_x, monitor.offset_y, monitor.width, monitor.height),
+                                                                 (self.offset_x, self.offset_y, self.width, self.height));
 
-        self.image_pointer = Some(image);
+        self.image_pointer = Some(screenshot.image());
+        self.screenshot = Some(screenshot);
     }
 
     fn set_initial_state(&mut self)
@@ -258,25 +406,42 @@ impl Context {
                                self.height);
     }
 
-    fn close(&self)
+    fn close(&mut self)
     {
-        xinterface::close_display(self.display);
+        self.image_pointer = None;
+        self.screenshot = None;
     }
 
+    // Collects the macroblocks that must be sent. A block is sent once its
+    // error reaches the threshold, or once it has contained a pixel that
+    // differs by more than the tolerance for max_stale_frames frames, so
+    // small changes are never lost. Blocks that were carried over from
+    // earlier frames because the frame budget was spent come first, the
+    // rest are sorted by descending error.
+    //
+    // Blocks are compared with the state that was last sent, except when
+    // their latest update is lost or has not been acknowledged in time.
+    // Those are compared with the state the client acknowledged, and sent
+    // again if it differs from the screen.
     fn set_block_errors(&mut self)
     {
         let DataBox(data) = get_data(self.image_pointer);
+        let metric = self.metric;
         // Define here for speed
         let mut r;
         let mut g;
         let mut b;
-        let mut d_b;
-        let mut d_g;
-        let mut d_r;
 
-        // Reset errors.
-        for n in 0..self.errors.len() {
-            self.errors[n] = (0, n);
+        for stats in &mut self.block_stats {
+            stats.reset();
+        }
+
+        for block in 0..self.overdue.len() {
+            let unacked = self.most_recent_version[block] > self.current_version[block];
+            let age = self.timestamp.saturating_sub(self.most_recent_version[block]);
+            let timeout = self.retry_frames << self.retries[block].min(MAX_RETRY_BACKOFF);
+
+            self.overdue[block] = self.lost[block] | (unacked & (age >= timeout));
         }
 
         // Get all pixels and errors
@@ -294,21 +459,114 @@ impl Context {
                 r = *data.offset(raw_ind + 2) as u8;
             }
 
-            d_r = r as i64 - self.client_state[state_ind] as i64;
-            d_g = g as i64 - self.client_state[state_ind + 1] as i64;
-            d_b = b as i64 - self.client_state[state_ind + 2] as i64;
+            let state = if self.overdue[*block] {
+                &self.acked_state
+            } else {
+                &self.client_state
+            };
+
+            let old = (state[state_ind],
+                       state[state_ind + 1],
+                       state[state_ind + 2]);
+
+            self.block_stats[*block].add(metric, old, (r, g, b));
+        }
+
+        self.errors.clear();
+
+        let mut retries = 0;
+
+        for block in 0..self.block_stats.len() {
+            let stats = self.block_stats[block];
+            let error = stats.error(metric);
+            let pending = self.errors.len();
+
+            if self.overdue[block] {
+                if stats.max_abs_diff() == 0 {
+                    // The client shows what is on screen, whether or not the
+                    // update arrived.
+                    self.lost[block] = false;
+                    self.retries[block] = 0;
+                    self.most_recent_version[block] = self.current_version[block];
+                    copy_state_block(&self.acked_state, &mut self.client_state, self.width, block);
+                } else if retries < MAX_RETRIES_PER_FRAME {
+                    retries += 1;
+                    self.retries[block] += 1;
+                    self.stale_frames[block] = 0;
+                    self.errors.push((i64::MAX, block));
+                }
+            } else if error >= self.threshold {
+                self.stale_frames[block] = 0;
+                self.errors.push((error, block));
+            } else if stats.max_abs_diff() > self.tolerance {
+                self.stale_frames[block] += 1;
+
+                if self.stale_frames[block] >= self.max_stale_frames {
+                    self.stale_frames[block] = 0;
+                    self.errors.push((error.max(1), block));
+                }
+            } else {
+                self.stale_frames[block] = 0;
+            }
+
+            // A block that no longer has to be sent is not carried over.
+            if self.errors.len() == pending {
+                self.carried_frames[block] = 0;
+            }
+        }
 
-            self.errors[*block].0 += d_r * d_r + d_g * d_g + d_b * d_b;
+        let carried = &self.carried_frames;
+        self.errors.sort_by(|a, b| (carried[b.1], b.0).cmp(&(carried[a.1], a.0)));
+    }
 
-            /*if (self.most_recent_version[*block] + 2 < self.timestamp) &
-                (self.current_version[*block] + 2 < self.timestamp) &
-                (self.most_recent_version[*block] > self.current_version[*block])
-            {
-                self.errors[*block].0 = i64::max_value();
-            }*/
+    // Moves the blocks the client has cached from the errors to the cached
+    // blocks, and determines the slots of the blocks that remain. A slot
+    // holds one block per frame: blocks whose slot a cached block refers to
+    // or an earlier block takes are not stored.
+    fn find_cached_blocks(&mut self)
+    {
+        self.slots.clear();
+        self.cached.clear();
+
+        if !self.block_cache.is_enabled() {
+            return
         }
 
-        self.errors.sort_by(|a, b| b.cmp(a));
+        let DataBox(data) = get_data(self.image_pointer);
+        let blocks_x = self.width as usize / 16;
+        let width = self.width as isize;
+
+        let block_cache = &self.block_cache;
+        let hashes = &mut self.hashes;
+        let cached = &mut self.cached;
+
+        self.errors.retain(|&(_, block)| {
+            let x0 = (block % blocks_x) as isize * 16;
+            let y0 = (block / blocks_x) as isize * 16;
+
+            let hash = block_cache::hash_macroblock(data, width, x0, y0);
+            hashes[block] = hash;
+
+            match block_cache.lookup(hash) {
+                Some(slot) => {
+                    cached.push((block, slot));
+                    false
+                },
+                None => true
+            }
+        });
+
+        let mut taken: HashSet<u16> = self.cached.iter().map(|&(_, slot)| slot).collect();
+
+        for &(_, block) in &self.errors {
+            let slot = self.block_cache.slot(self.hashes[block]);
+
+            if taken.insert(slot) {
+                self.slots.push(slot);
+            } else {
+                self.slots.push(NO_SLOT);
+            }
+        }
     }
 
     fn generate_block_lookup_table(&mut self)
@@ -327,57 +585,147 @@ impl Context {
         }
     }
 
-    fn update_client_state(&mut self)
+    // Copies the blocks the encoder sent and the blocks copied from the
+    // client's cache into the client state. Blocks which had to be sent but
+    // were not are carried over to the next frame.
+    fn update_client_state(&mut self, sent: &[usize])
+    {
+        for err in &self.errors {
+            self.carried_frames[err.1] += 1;
+        }
+
+        // The encoder sends the blocks in order, so the slot of the i-th sent
+        // block is the i-th slot.
+        for (i, &block) in sent.iter().enumerate() {
+            if self.block_cache.is_enabled() {
+                let slot = self.slots[i];
+
+                if slot == NO_SLOT {
+                    self.sent_slots[block] = None;
+                } else {
+                    self.block_cache.store(slot, self.hashes[block], self.timestamp);
+                    self.sent_slots[block] = Some((self.timestamp, slot));
+                }
+            }
+
+            self.copy_block(block);
+        }
+
+        for i in 0..self.cached.len() {
+            let block = self.cached[i].0;
+            self.copy_block(block);
+        }
+    }
+
+    // Records the timestamp of a full image, which holds every block.
+    fn initial_state_sent(&mut self, sent: &[usize])
+    {
+        self.errors.clear();
+
+        for &block in sent {
+            self.carried_frames[block] = 0;
+            self.stale_frames[block] = 0;
+            self.lost[block] = false;
+            self.most_recent_version[block] = self.timestamp;
+        }
+    }
+
+    fn copy_block(&mut self, block: usize)
     {
         let mut r;
         let mut g;
         let mut b;
         let mut dest_ind;
 
-        self.timestamp += 1;
-
         let DataBox(data) = get_data(self.image_pointer);
         let blocks_x = self.width as isize / 16;
 
-        for err in &self.errors {
-            let (error, block) = *err;
-            if error == 0 { break }
+        self.carried_frames[block] = 0;
+        self.lost[block] = false;
+        self.most_recent_version[block] = self.timestamp;
 
-            self.most_recent_version[block] = self.timestamp;
+        // Calculate initial index
+        let x_block = block as isize % blocks_x;
+        let x0 = x_block*64;
+        let y0 = (block as isize - x_block)*16/blocks_x;
+        let ind0 = y0 * 4 * self.width as isize + x0;
 
+        for row_ind in range_step(ind0, ind0 + 16*4*self.width as isize, 4*self.width as isize) {
+            for ind in range_step(row_ind, row_ind + 16*4, 4) {
+                if ind >= (4 * self.width * self.height) as isize {
+                    break
+                }
 
-            // Calculate initial index
-            let x_block = block as isize % blocks_x;
-            let x0 = x_block*64;
-            let y0 = (block as isize - x_block)*16/blocks_x;
-            let ind0 = y0 * 4 * self.width as isize + x0;
+                b = value_at(data, ind);
+                g = value_at(data, ind + 1);
+                r = value_at(data, ind + 2);
 
-            for row_ind in range_step(ind0, ind0 + 16*4*self.width as isize, 4*self.width as isize) {
-                for ind in range_step(row_ind, row_ind + 16*4, 4) {
-                    if ind >= (4 * self.width * self.height) as isize {
-                        break
-                    }
+                dest_ind = ind as usize*3/4;
 
-                    b = value_at(data, ind);
-                    g = value_at(data, ind + 1);
-                    r = value_at(data, ind + 2);
+                self.client_state[dest_ind] = r;
+                self.client_state[dest_ind+1] = g;
+                self.client_state[dest_ind+2] = b;
+            }
+        }
+    }
 
-                    dest_ind = ind as usize*3/4;
+    fn handle_ack(&mut self, timestamp: u32, ids: &Vec<u16>)
+    {
+        for id in ids {
+            let block = *id as usize;
+
+            if self.current_version[block] < timestamp {
+                self.current_version[block] = timestamp;
+            }
 
-                    self.client_state[dest_ind] = r;
-                    self.client_state[dest_ind+1] = g;
-                    self.client_state[dest_ind+2] = b;
+            // Only the most recent version is kept, so only its arrival
+            // updates the acknowledged state.
+            if self.most_recent_version[block] == timestamp {
+                self.lost[block] = false;
+                self.retries[block] = 0;
+                copy_state_block(&self.client_state, &mut self.acked_state, self.width, block);
+            }
+
+            // The client now has the block in its cache.
+            if let Some((sent_timestamp, slot)) = self.sent_slots[*id as usize] {
+                if sent_timestamp == timestamp {
+                    self.block_cache.ack(slot, timestamp);
                 }
             }
         }
     }
 
-    fn handle_ack(&mut self, timestamp: u32, ids: &Vec<u16>)
+    // A packet was not acknowledged in time. Blocks whose most recent update
+    // was in it are sent again, blocks that have been updated since are
+    // left alone.
+    fn handle_loss(&mut self, timestamp: u32, ids: &Vec<u16>)
     {
         for id in ids {
-            if self.current_version[*id as usize] < timestamp {
-                self.current_version[*id as usize] = timestamp;
+            let block = *id as usize;
+
+            if self.most_recent_version[block] == timestamp {
+                self.lost[block] = true;
             }
         }
     }
+}
+
+// Copies a macroblock between two 3 bytes per pixel states.
+fn copy_state_block(source: &[u8], dest: &mut [u8], width: u32, block: usize)
+{
+    let width = width as usize;
+    let blocks_x = width / 16;
+    let x0 = (block % blocks_x) * 16;
+    let y0 = (block / blocks_x) * 16;
+
+    for y in y0..y0 + 16 {
+        let start = (y * width + x0) * 3;
+        let end = start + 16 * 3;
+
+        if end > dest.len() {
+            break
+        }
+
+        dest[start..end].copy_from_slice(&source[start..end]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,238 @@
+// A Unix socket on which a local admin manages the running server. Every
+// line is a command, answered with its output and a last line of "ok" or
+// "error: " and the reason.
+//
+//     clients                  Id, address, role, state and view of each client
+//     stats [ID]               Link statistics of one client or all
+//     kick ID                  Close the session of a client
+//     quality N                Highest JPEG quality of all sessions, 1 to 100
+//     fps N                    Highest frame rate of all sessions
+//     snapshot PATH [SCREEN]   Write a screenshot of a screen to a PPM file
+//     shutdown                 Close all sessions and exit
+//
+// Commands go to main like client messages do. Whoever can open the socket
+// controls the server, so only the user running it may.
+
+use std::fs;
+
+use std::io::{
+    BufRead,
+    BufReader,
+    Error,
+    ErrorKind,
+    Result,
+    Write
+};
+
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use std::path::{Path, PathBuf};
+
+use std::process;
+
+use std::str::FromStr;
+
+use std::sync::mpsc::{
+    channel,
+    Sender,
+};
+
+use std::thread::{
+    self,
+    JoinHandle
+};
+
+use std::time::Duration;
+
+use super::protocol::{
+    ClientId,
+    MainMessage,
+    MainSender,
+    ReceiverMessage
+};
+
+const POLL_MS: u64 = 100;
+
+/// Commands are not from a session, they carry this id to main.
+pub const CONTROL_CLIENT: ClientId = ClientId::MAX;
+
+#[derive(Debug)]
+pub enum ControlCommand {
+    Clients,
+    Stats(Option<ClientId>),
+    Kick(ClientId),
+    Quality(u8),
+    Fps(u64),
+    Snapshot(PathBuf, u8), // File and screen
+    Shutdown,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String>
+    {
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        match words.as_slice() {
+            ["clients"] => Ok(ControlCommand::Clients),
+            ["stats"] => Ok(ControlCommand::Stats(None)),
+            ["stats", client] => Ok(ControlCommand::Stats(Some(number(client)?))),
+            ["kick", client] => Ok(ControlCommand::Kick(number(client)?)),
+            ["quality", quality] => match number(quality)? {
+                quality @ 1..=100 => Ok(ControlCommand::Quality(quality)),
+                _ => Err("quality must be between 1 and 100".to_string())
+            },
+            ["fps", fps] => match number(fps)? {
+                0 => Err("fps must be positive".to_string()),
+                fps => Ok(ControlCommand::Fps(fps))
+            },
+            ["snapshot", path] => Ok(ControlCommand::Snapshot(PathBuf::from(path), 0)),
+            ["snapshot", path, screen] => Ok(ControlCommand::Snapshot(PathBuf::from(path), number(screen)?)),
+            ["shutdown"] => Ok(ControlCommand::Shutdown),
+            _ => Err(format!("unknown command '{}'", s.trim()))
+        }
+    }
+}
+
+fn number<T: FromStr>(word: &str) -> std::result::Result<T, String>
+{
+    word.parse().map_err(|_| format!("invalid number '{}'", word))
+}
+
+pub struct ControlServer {
+    path: PathBuf,
+    to_listener: Sender<ReceiverMessage>,
+    handle: JoinHandle<()>,
+}
+
+impl ControlServer {
+    /// Stops accepting connections and removes the socket. Open connections
+    /// end with the server.
+    pub fn close(self)
+    {
+        self.to_listener.send(ReceiverMessage::Close).unwrap();
+        self.handle.join().unwrap();
+
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub fn start_control_listener(path: &Path, main_sender: Sender<(ClientId, MainMessage)>) -> Result<ControlServer>
+{
+    let listener = bind(path)?;
+    listener.set_nonblocking(true)?;
+
+    println!("Control: Listening on {}", path.display());
+
+    let (to_listener, listener_receiver) = channel();
+
+    let handle = thread::spawn(move || {
+        loop {
+            if let Ok(ReceiverMessage::Close) = listener_receiver.try_recv() {
+                return
+            }
+
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    println!("Control: Connection");
+
+                    let main_sender = MainSender::new(CONTROL_CLIENT, main_sender.clone());
+                    thread::spawn(move || serve(stream, main_sender));
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(POLL_MS)),
+                Err(e) => {
+                    println!("Control: Accept failed: {}", e);
+                    thread::sleep(Duration::from_millis(POLL_MS));
+                }
+            }
+        }
+    });
+
+    Ok(ControlServer {
+        path: path.to_path_buf(),
+        to_listener,
+        handle,
+    })
+}
+
+// Binds the socket in a directory only the user can enter, so that it is
+// never reachable before its permissions are set, then moves it to the path.
+// A socket left behind by an earlier run is replaced, one a running server
+// accepts connections on or anything else at the path is not.
+fn bind(path: &Path) -> Result<UnixListener>
+{
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if !metadata.file_type().is_socket() => {
+            return Err(Error::new(ErrorKind::AlreadyExists, "not a socket"))
+        },
+        Ok(_) if UnixStream::connect(path).is_ok() => {
+            return Err(Error::new(ErrorKind::AddrInUse, "a running server listens on it"))
+        },
+        _ => ()
+    }
+
+    let name = path.file_name().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no file name"))?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), process::id()));
+
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let staged = private.join(name);
+
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+
+        Ok(listener)
+    });
+
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&private);
+
+    result
+}
+
+// Answers the commands of one connection until it is closed or main is gone.
+fn serve(stream: UnixStream, main_sender: MainSender)
+{
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return
+    };
+
+    // The listener does not block, its connections do.
+    if stream.set_nonblocking(false).is_err() {
+        return
+    }
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return
+        };
+
+        if line.trim().is_empty() {
+            continue
+        }
+
+        let result = line.parse().and_then(|command| {
+            let (reply_sender, reply_receiver) = channel();
+
+            main_sender.send(MainMessage::Control(command, reply_sender))
+                .map_err(|_| "the server is shutting down".to_string())?;
+
+            reply_receiver.recv().unwrap_or_else(|_| Err("the server is shutting down".to_string()))
+        });
+
+        let reply = match result {
+            Ok(output) => format!("{}ok\n", output),
+            Err(reason) => format!("error: {}\n", reason)
+        };
+
+        if writer.write_all(reply.as_bytes()).is_err() {
+            return
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// ChaCha20-Poly1305 authenticated encryption (RFC 8439).
+
+pub const TAG_SIZE: usize = 16;
+
+/// Encrypts the plaintext and appends the tag to the output.
+pub fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8], out: &mut Vec<u8>)
+{
+    let start = out.len();
+
+    out.extend_from_slice(plaintext);
+    chacha20_xor(key, nonce, 1, &mut out[start..]);
+
+    let tag = tag(key, nonce, aad, &out[start..]);
+    out.extend_from_slice(&tag);
+}
+
+/// Checks the tag at the end of the ciphertext and decrypts it.
+pub fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>
+{
+    if ciphertext.len() < TAG_SIZE {
+        return None
+    }
+
+    let (data, received) = ciphertext.split_at(ciphertext.len() - TAG_SIZE);
+    let expected = tag(key, nonce, aad, data);
+
+    // Compare in constant time.
+    let difference = expected.iter()
+        .zip(received)
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+
+    if difference != 0 {
+        return None
+    }
+
+    let mut plaintext = data.to_vec();
+    chacha20_xor(key, nonce, 1, &mut plaintext);
+
+    Some(plaintext)
+}
+
+fn tag(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; 16]
+{
+    let block = chacha20_block(key, nonce, 0);
+
+    let mut one_time_key = [0u8; 32];
+    one_time_key.copy_from_slice(&block[..32]);
+
+    let mut mac_data = Vec::with_capacity(aad.len() + ciphertext.len() + 32);
+    mac_data.extend_from_slice(aad);
+    pad16(&mut mac_data);
+    mac_data.extend_from_slice(ciphertext);
+    pad16(&mut mac_data);
+    mac_data.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    mac_data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+
+    poly1305(&one_time_key, &mac_data)
+}
+
+fn pad16(data: &mut Vec<u8>)
+{
+    while !data.len().is_multiple_of(16) {
+        data.push(0);
+    }
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize)
+{
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u8; 32], nonce: &[u8; 12], counter: u32) -> [u8; 64]
+{
+    let mut state = [0u32; 16];
+
+    state[0] = 0x61707865;
+    state[1] = 0x3320646e;
+    state[2] = 0x79622d32;
+    state[3] = 0x6b206574;
+
+    for i in 0..8 {
+        state[4 + i] = le32(&key[4 * i..]);
+    }
+
+    state[12] = counter;
+
+    for i in 0..3 {
+        state[13 + i] = le32(&nonce[4 * i..]);
+    }
+
+    let mut working = state;
+
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut out = [0u8; 64];
+
+    for i in 0..16 {
+        out[4 * i..4 * i + 4].copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+    }
+
+    out
+}
+
+fn chacha20_xor(key: &[u8; 32], nonce: &[u8; 12], counter: u32, data: &mut [u8])
+{
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, nonce, counter.wrapping_add(i as u32));
+
+        for (byte, k) in chunk.iter_mut().zip(block.iter()) {
+            *byte ^= k;
+        }
+    }
+}
+
+// Poly1305 with 26 bit limbs, as in poly1305-donna.
+fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; 16]
+{
+    const MASK: u32 = 0x3FFFFFF;
+
+    let r0 = le32(&key[0..]) & 0x3FFFFFF;
+    let r1 = (le32(&key[3..]) >> 2) & 0x3FFFF03;
+    let r2 = (le32(&key[6..]) >> 4) & 0x3FFC0FF;
+    let r3 = (le32(&key[9..]) >> 6) & 0x3F03FFF;
+    let r4 = (le32(&key[12..]) >> 8) & 0x00FFFFF;
+
+    let s1 = r1 * 5;
+    let s2 = r2 * 5;
+    let s3 = r3 * 5;
+    let s4 = r4 * 5;
+
+    let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0u32, 0u32, 0u32, 0u32, 0u32);
+
+    for chunk in message.chunks(16) {
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+
+        // Full blocks get the high bit, a final partial block is padded
+        // with a one byte instead.
+        let hibit = if chunk.len() == 16 {
+            1 << 24
+        } else {
+            block[chunk.len()] = 1;
+            0
+        };
+
+        h0 += le32(&block[0..]) & MASK;
+        h1 += (le32(&block[3..]) >> 2) & MASK;
+        h2 += (le32(&block[6..]) >> 4) & MASK;
+        h3 += (le32(&block[9..]) >> 6) & MASK;
+        h4 += (le32(&block[12..]) >> 8) | hibit;
+
+        let m = |a: u32, b: u32| a as u64 * b as u64;
+
+        let d0 = m(h0, r0) + m(h1, s4) + m(h2, s3) + m(h3, s2) + m(h4, s1);
+        let mut d1 = m(h0, r1) + m(h1, r0) + m(h2, s4) + m(h3, s3) + m(h4, s2);
+        let mut d2 = m(h0, r2) + m(h1, r1) + m(h2, r0) + m(h3, s4) + m(h4, s3);
+        let mut d3 = m(h0, r3) + m(h1, r2) + m(h2, r1) + m(h3, r0) + m(h4, s4);
+        let mut d4 = m(h0, r4) + m(h1, r3) + m(h2, r2) + m(h3, r1) + m(h4, r0);
+
+        let mut c = (d0 >> 26) as u32;
+        h0 = d0 as u32 & MASK;
+        d1 += c as u64;
+        c = (d1 >> 26) as u32;
+        h1 = d1 as u32 & MASK;
+        d2 += c as u64;
+        c = (d2 >> 26) as u32;
+        h2 = d2 as u32 & MASK;
+        d3 += c as u64;
+        c = (d3 >> 26) as u32;
+        h3 = d3 as u32 & MASK;
+        d4 += c as u64;
+        c = (d4 >> 26) as u32;
+        h4 = d4 as u32 & MASK;
+        h0 += c * 5;
+        c = h0 >> 26;
+        h0 &= MASK;
+        h1 += c;
+    }
+
+    let mut c = h1 >> 26;
+    h1 &= MASK;
+    h2 += c;
+    c = h2 >> 26;
+    h2 &= MASK;
+    h3 += c;
+    c = h3 >> 26;
+    h3 &= MASK;
+    h4 += c;
+    c = h4 >> 26;
+    h4 &= MASK;
+    h0 += c * 5;
+    c = h0 >> 26;
+    h0 &= MASK;
+    h1 += c;
+
+    // Compute h - p and keep it if it is not negative.
+    let mut g0 = h0.wrapping_add(5);
+    c = g0 >> 26;
+    g0 &= MASK;
+    let mut g1 = h1.wrapping_add(c);
+    c = g1 >> 26;
+    g1 &= MASK;
+    let mut g2 = h2.wrapping_add(c);
+    c = g2 >> 26;
+    g2 &= MASK;
+    let mut g3 = h3.wrapping_add(c);
+    c = g3 >> 26;
+    g3 &= MASK;
+    let g4 = h4.wrapping_add(c).wrapping_sub(1 << 26);
+
+    let select = (g4 >> 31).wrapping_sub(1);
+    let keep = !select;
+
+    h0 = (h0 & keep) | (g0 & select);
+    h1 = (h1 & keep) | (g1 & select);
+    h2 = (h2 & keep) | (g2 & select);
+    h3 = (h3 & keep) | (g3 & select);
+    h4 = (h4 & keep) | (g4 & select);
+
+    let h0 = h0 | (h1 << 26);
+    let h1 = (h1 >> 6) | (h2 << 20);
+    let h2 = (h2 >> 12) | (h3 << 14);
+    let h3 = (h3 >> 18) | (h4 << 8);
+
+    let mut f = h0 as u64 + le32(&key[16..]) as u64;
+    let t0 = f as u32;
+    f = h1 as u64 + le32(&key[20..]) as u64 + (f >> 32);
+    let t1 = f as u32;
+    f = h2 as u64 + le32(&key[24..]) as u64 + (f >> 32);
+    let t2 = f as u32;
+    f = h3 as u64 + le32(&key[28..]) as u64 + (f >> 32);
+    let t3 = f as u32;
+
+    let mut out = [0u8; 16];
+    out[0..4].copy_from_slice(&t0.to_le_bytes());
+    out[4..8].copy_from_slice(&t1.to_le_bytes());
+    out[8..12].copy_from_slice(&t2.to_le_bytes());
+    out[12..16].copy_from_slice(&t3.to_le_bytes());
+
+    out
+}
+
+fn le32(bytes: &[u8]) -> u32
+{
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
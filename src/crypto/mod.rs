@@ -0,0 +1,402 @@
+// Encrypted sessions: a Noise_NK_25519_ChaChaPoly_SHA256 handshake, in
+// which the client knows the server's static public key, followed by
+// datagrams sealed with the resulting keys.
+//
+// Since datagrams may be lost or reordered, each carries its nonce as an
+// explicit counter. Counters are never reused by the sender, the receiver
+// rejects counters it has already seen or that are too old to track.
+
+// Public for the tests against the vectors of their specifications.
+pub mod chacha20poly1305;
+pub mod sha256;
+pub mod x25519;
+
+use std::fmt;
+
+use std::fs::{
+    self,
+    File,
+    OpenOptions
+};
+
+use std::io::{
+    self,
+    Read,
+    Write
+};
+
+use std::os::unix::fs::OpenOptionsExt;
+
+use std::path::Path;
+
+use self::chacha20poly1305::{open, seal, TAG_SIZE};
+
+use self::sha256::{hkdf, sha256, HASH_SIZE};
+
+pub use self::sha256::hmac;
+
+pub const KEY_SIZE: usize = 32;
+
+// The counter in front of each sealed datagram.
+pub const COUNTER_SIZE: usize = 8;
+
+/// Bytes added to a datagram by sealing it.
+pub const OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+
+const PROTOCOL_NAME: &[u8] = b"Noise_NK_25519_ChaChaPoly_SHA256";
+
+// Number of counters below the highest one received that are tracked.
+const REPLAY_WINDOW: u64 = 64;
+
+#[derive(Clone)]
+pub struct KeyPair {
+    private: [u8; KEY_SIZE],
+    pub public: [u8; KEY_SIZE],
+}
+
+impl KeyPair {
+    pub fn generate() -> io::Result<Self>
+    {
+        Ok(Self::from_private(random_key()?))
+    }
+
+    pub fn from_private(private: [u8; KEY_SIZE]) -> Self
+    {
+        KeyPair {
+            private,
+            public: x25519::public_key(&private),
+        }
+    }
+
+    /// Reads the private key from the file, or creates the file with a new
+    /// key readable only by the owner.
+    pub fn load_or_generate(path: &Path) -> io::Result<Self>
+    {
+        if path.exists() {
+            let bytes = fs::read(path)?;
+
+            if bytes.len() != KEY_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "key file must hold 32 bytes"))
+            }
+
+            let mut private = [0u8; KEY_SIZE];
+            private.copy_from_slice(&bytes);
+
+            return Ok(Self::from_private(private))
+        }
+
+        let pair = Self::generate()?;
+
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(&pair.private)?;
+
+        Ok(pair)
+    }
+
+    fn dh(&self, public: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE]
+    {
+        x25519::scalar_mult(&self.private, public)
+    }
+}
+
+// Never print the private key.
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "KeyPair {{ public: {} }}", to_hex(&self.public))
+    }
+}
+
+/// Hexadecimal form of a public key, as printed by the server.
+pub fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn random_key() -> io::Result<[u8; KEY_SIZE]>
+{
+    let mut key = [0u8; KEY_SIZE];
+    File::open("/dev/urandom")?.read_exact(&mut key)?;
+
+    Ok(key)
+}
+
+fn nonce(counter: u64) -> [u8; 12]
+{
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+
+    nonce
+}
+
+// The hash and chaining key of a handshake in progress, and the key of the
+// handshake messages once there is one.
+struct SymmetricState {
+    chaining_key: [u8; HASH_SIZE],
+    hash: [u8; HASH_SIZE],
+    key: Option<[u8; KEY_SIZE]>,
+    counter: u64,
+}
+
+impl SymmetricState {
+    fn new(responder_static: &[u8; KEY_SIZE]) -> Self
+    {
+        let mut hash = [0u8; HASH_SIZE];
+        hash[..PROTOCOL_NAME.len()].copy_from_slice(PROTOCOL_NAME);
+
+        let mut state = SymmetricState {
+            chaining_key: hash,
+            hash,
+            key: None,
+            counter: 0,
+        };
+
+        // Empty prologue, then the pre-message with the responder's key.
+        state.mix_hash(&[]);
+        state.mix_hash(responder_static);
+
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8])
+    {
+        self.hash = sha256(&[&self.hash, data]);
+    }
+
+    fn mix_key(&mut self, input: &[u8])
+    {
+        let (chaining_key, key) = hkdf(&self.chaining_key, input);
+
+        self.chaining_key = chaining_key;
+        self.key = Some(key);
+        self.counter = 0;
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8], out: &mut Vec<u8>)
+    {
+        let start = out.len();
+
+        match self.key {
+            Some(ref key) => {
+                seal(key, &nonce(self.counter), &self.hash, plaintext, out);
+                self.counter += 1;
+            },
+            None => out.extend_from_slice(plaintext)
+        }
+
+        let hash = sha256(&[&self.hash, &out[start..]]);
+        self.hash = hash;
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>>
+    {
+        let plaintext = match self.key {
+            Some(ref key) => open(key, &nonce(self.counter), &self.hash, ciphertext)?,
+            None => ciphertext.to_vec()
+        };
+
+        self.counter += 1;
+        self.mix_hash(ciphertext);
+
+        Some(plaintext)
+    }
+
+    // The keys for initiator to responder and responder to initiator.
+    fn split(&self) -> ([u8; KEY_SIZE], [u8; KEY_SIZE])
+    {
+        hkdf(&self.chaining_key, &[])
+    }
+}
+
+/// Seals outgoing datagrams.
+pub struct Sealer {
+    key: [u8; KEY_SIZE],
+    counter: u64,
+}
+
+impl Sealer {
+    /// Appends the counter, the encrypted datagram and its tag to the
+    /// output. Everything already in the output is authenticated as well.
+    pub fn seal(&mut self, plaintext: &[u8], out: &mut Vec<u8>)
+    {
+        let counter = self.counter;
+        self.counter += 1;
+
+        out.extend_from_slice(&counter.to_be_bytes());
+
+        let aad = out.clone();
+        seal(&self.key, &nonce(counter), &aad, plaintext, out);
+    }
+}
+
+// Never print the key.
+impl fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "Sealer {{ counter: {} }}", self.counter)
+    }
+}
+
+/// Opens incoming datagrams and rejects replays.
+pub struct Opener {
+    key: [u8; KEY_SIZE],
+    highest: Option<u64>,
+    // Bit i is set if highest - i was received.
+    seen: u64,
+}
+
+impl Opener {
+    /// Opens a datagram sealed after the given prefix.
+    pub fn open(&mut self, prefix: &[u8], sealed: &[u8]) -> Option<Vec<u8>>
+    {
+        if sealed.len() < OVERHEAD {
+            return None
+        }
+
+        let mut counter_bytes = [0u8; COUNTER_SIZE];
+        counter_bytes.copy_from_slice(&sealed[..COUNTER_SIZE]);
+        let counter = u64::from_be_bytes(counter_bytes);
+
+        if !self.is_new(counter) {
+            return None
+        }
+
+        let mut aad = prefix.to_vec();
+        aad.extend_from_slice(&counter_bytes);
+
+        let plaintext = open(&self.key, &nonce(counter), &aad, &sealed[COUNTER_SIZE..])?;
+
+        // Only authentic datagrams move the window.
+        self.mark(counter);
+
+        Some(plaintext)
+    }
+
+    fn is_new(&self, counter: u64) -> bool
+    {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) if highest - counter >= REPLAY_WINDOW => false,
+            Some(highest) => self.seen & (1 << (highest - counter)) == 0
+        }
+    }
+
+    fn mark(&mut self, counter: u64)
+    {
+        match self.highest {
+            Some(highest) if counter <= highest => {
+                self.seen |= 1 << (highest - counter);
+            },
+            Some(highest) => {
+                let shift = counter - highest;
+
+                self.seen = if shift >= REPLAY_WINDOW {
+                    1
+                } else {
+                    (self.seen << shift) | 1
+                };
+
+                self.highest = Some(counter);
+            },
+            None => {
+                self.seen = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+/// A session accepted by the server: the reply that completes the
+/// handshake, the payload of the client's first message and the keys.
+pub struct ResponderSession {
+    pub reply: Vec<u8>,
+    pub payload: Vec<u8>,
+    pub sealer: Sealer,
+    pub opener: Opener,
+}
+
+/// Reads the client's first handshake message, -> e, es, and writes the
+/// reply, <- e, ee. Returns None if the message is not authentic.
+pub fn respond(server: &KeyPair, message: &[u8]) -> Option<ResponderSession>
+{
+    if message.len() < KEY_SIZE + TAG_SIZE {
+        return None
+    }
+
+    let mut state = SymmetricState::new(&server.public);
+
+    let mut remote_ephemeral = [0u8; KEY_SIZE];
+    remote_ephemeral.copy_from_slice(&message[..KEY_SIZE]);
+
+    state.mix_hash(&remote_ephemeral);
+    state.mix_key(&server.dh(&remote_ephemeral));
+
+    let payload = state.decrypt_and_hash(&message[KEY_SIZE..])?;
+
+    let ephemeral = KeyPair::generate().ok()?;
+    let mut reply = ephemeral.public.to_vec();
+
+    state.mix_hash(&ephemeral.public);
+    state.mix_key(&ephemeral.dh(&remote_ephemeral));
+    state.encrypt_and_hash(&[], &mut reply);
+
+    let (to_server, to_client) = state.split();
+
+    Some(ResponderSession {
+        reply,
+        payload,
+        sealer: Sealer { key: to_client, counter: 0 },
+        opener: Opener { key: to_server, highest: None, seen: 0 },
+    })
+}
+
+/// The client side of the handshake, for clients written in Rust.
+#[allow(dead_code)]
+pub struct Initiator {
+    state: SymmetricState,
+    ephemeral: KeyPair,
+}
+
+#[allow(dead_code)]
+impl Initiator {
+    /// Starts a handshake with a server whose public key is known. Returns
+    /// the first handshake message, carrying the payload encrypted.
+    pub fn start(server_public: &[u8; KEY_SIZE], payload: &[u8]) -> io::Result<(Self, Vec<u8>)>
+    {
+        let mut state = SymmetricState::new(server_public);
+        let ephemeral = KeyPair::generate()?;
+
+        let mut message = ephemeral.public.to_vec();
+
+        state.mix_hash(&ephemeral.public);
+        state.mix_key(&ephemeral.dh(server_public));
+        state.encrypt_and_hash(payload, &mut message);
+
+        Ok((Initiator { state, ephemeral }, message))
+    }
+
+    /// Reads the server's reply and returns the keys of the session.
+    pub fn finish(mut self, reply: &[u8]) -> Option<(Sealer, Opener)>
+    {
+        if reply.len() < KEY_SIZE + TAG_SIZE {
+            return None
+        }
+
+        let mut remote_ephemeral = [0u8; KEY_SIZE];
+        remote_ephemeral.copy_from_slice(&reply[..KEY_SIZE]);
+
+        self.state.mix_hash(&remote_ephemeral);
+        self.state.mix_key(&self.ephemeral.dh(&remote_ephemeral));
+        self.state.decrypt_and_hash(&reply[KEY_SIZE..])?;
+
+        let (to_server, to_client) = self.state.split();
+
+        Some((Sealer { key: to_server, counter: 0 },
+              Opener { key: to_client, highest: None, seen: 0 }))
+    }
+}
@@ -0,0 +1,206 @@
+// X25519 Diffie-Hellman (RFC 7748), following the field arithmetic of
+// TweetNaCl. Field elements are 16 limbs of 16 bits.
+
+type Gf = [i64; 16];
+
+const A24: Gf = [0xDB41, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+const BASE_POINT: [u8; 32] = [
+    9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// The public key belonging to a private key.
+pub fn public_key(private: &[u8; 32]) -> [u8; 32]
+{
+    scalar_mult(private, &BASE_POINT)
+}
+
+/// The shared secret of a private key and the other side's public key.
+pub fn scalar_mult(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32]
+{
+    let mut z = *scalar;
+    z[31] = (z[31] & 127) | 64;
+    z[0] &= 248;
+
+    let x = unpack(point);
+
+    let mut a: Gf = [0; 16];
+    let mut b = x;
+    let mut c: Gf = [0; 16];
+    let mut d: Gf = [0; 16];
+
+    a[0] = 1;
+    d[0] = 1;
+
+    for i in (0..255).rev() {
+        let bit = ((z[i >> 3] >> (i & 7)) & 1) as i64;
+
+        swap(&mut a, &mut b, bit);
+        swap(&mut c, &mut d, bit);
+
+        let e = add(&a, &c);
+        a = sub(&a, &c);
+        c = add(&b, &d);
+        b = sub(&b, &d);
+        d = square(&e);
+        let f = square(&a);
+        a = mul(&c, &a);
+        c = mul(&b, &e);
+        let e = add(&a, &c);
+        a = sub(&a, &c);
+        b = square(&a);
+        c = sub(&d, &f);
+        a = mul(&c, &A24);
+        a = add(&a, &d);
+        c = mul(&c, &a);
+        a = mul(&d, &f);
+        d = mul(&b, &x);
+        b = square(&e);
+
+        swap(&mut a, &mut b, bit);
+        swap(&mut c, &mut d, bit);
+    }
+
+    pack(&mul(&a, &invert(&c)))
+}
+
+fn carry(o: &mut Gf)
+{
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+
+        o[i] -= c << 16;
+    }
+}
+
+// Swaps p and q in constant time if bit is 1.
+fn swap(p: &mut Gf, q: &mut Gf, bit: i64)
+{
+    let mask = !(bit - 1);
+
+    for i in 0..16 {
+        let t = mask & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+fn pack(n: &Gf) -> [u8; 32]
+{
+    let mut t = *n;
+    let mut m: Gf = [0; 16];
+
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+
+    for _ in 0..2 {
+        m[0] = t[0] - 0xFFED;
+
+        for i in 1..15 {
+            m[i] = t[i] - 0xFFFF - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xFFFF;
+        }
+
+        m[15] = t[15] - 0x7FFF - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xFFFF;
+
+        swap(&mut t, &mut m, 1 - b);
+    }
+
+    let mut out = [0u8; 32];
+
+    for i in 0..16 {
+        out[2 * i] = t[i] as u8;
+        out[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+
+    out
+}
+
+fn unpack(n: &[u8; 32]) -> Gf
+{
+    let mut o: Gf = [0; 16];
+
+    for i in 0..16 {
+        o[i] = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8);
+    }
+
+    o[15] &= 0x7FFF;
+    o
+}
+
+fn add(a: &Gf, b: &Gf) -> Gf
+{
+    let mut o: Gf = [0; 16];
+
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+
+    o
+}
+
+fn sub(a: &Gf, b: &Gf) -> Gf
+{
+    let mut o: Gf = [0; 16];
+
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+
+    o
+}
+
+fn mul(a: &Gf, b: &Gf) -> Gf
+{
+    let mut t = [0i64; 31];
+
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+
+    let mut o: Gf = [0; 16];
+    o.copy_from_slice(&t[..16]);
+
+    carry(&mut o);
+    carry(&mut o);
+
+    o
+}
+
+fn square(a: &Gf) -> Gf
+{
+    mul(a, a)
+}
+
+// a^(p - 2)
+fn invert(i: &Gf) -> Gf
+{
+    let mut c = *i;
+
+    for a in (0..254).rev() {
+        c = square(&c);
+
+        if (a != 2) & (a != 4) {
+            c = mul(&c, i);
+        }
+    }
+
+    c
+}
@@ -12,8 +12,25 @@ use self::entropy::build_huff_lut;
 
 use super::tables::*;
 
+use super::block_cache::NO_SLOT;
+
+use super::config::Config;
+
+use super::link_stats::LinkStats;
+
 use super::monitor_info::MonitorInfo;
 
+use super::negotiation::{CAPABILITY_END_OF_FRAME, CAPABILITY_FRAGMENTS, CAPABILITY_RATE_INFO};
+
+use super::packet::
+{
+    Packet,
+    PacketPool,
+    DEFAULT_PACKET_SIZE,
+    FRAGMENT_HEADER_SIZE,
+    HEADER_SIZE
+};
+
 use super::util::
 {
     value_at,
@@ -27,10 +44,22 @@ use std::thread::{
 
 use super::protocol::
 {
+    ContextMessage,
+    MainMessage,
+    MainSender,
     SenderMessage,
-    EncoderMessage
+    EncoderMessage,
+
+    OPCODE_SEND_IMAGE_DATA,
+    OPCODE_SEND_IMAGE_FRAGMENT,
+    OPCODE_SEND_CACHED_BLOCKS,
+    OPCODE_SEND_END_OF_FRAME,
 };
 
+use std::mem;
+
+use std::time::Duration;
+
 use std::sync::mpsc::
 {
     Sender,
@@ -39,13 +68,32 @@ use std::sync::mpsc::
 
 use num_iter::range_step;
 
+// Loss rate above which the link limits the frame budget, how much more
+// than the link delivered a frame may hold, and the least it may hold.
+const LOSSY_LINK: f64 = 0.02;
+const LINK_HEADROOM: f64 = 1.25;
+const MIN_LINK_BUDGET: usize = 4 * DEFAULT_PACKET_SIZE;
+
+#[derive(Debug)]
+struct HuffmanTables {
+    luma_dc: Vec<(u8, u16)>,
+    luma_ac: Vec<(u8, u16)>,
+    chroma_dc: Vec<(u8, u16)>,
+    chroma_ac: Vec<(u8, u16)>,
+}
+
+// Writes bits straight into the packet that is being filled.
+#[derive(Debug)]
+struct BitWriter {
+    accumulator: u32,
+    nbits: u8,
+    packet: Packet,
+}
+
 #[derive(Debug)]
 pub struct Encoder {
     tables: Vec<u8>,
-    luma_dctable: Vec<(u8, u16)>,
-    luma_actable: Vec<(u8, u16)>,
-    chroma_dctable: Vec<(u8, u16)>,
-    chroma_actable: Vec<(u8, u16)>,
+    huffman: HuffmanTables,
     width: isize,
     height: isize,
     size: isize,
@@ -53,34 +101,82 @@ pub struct Encoder {
     macroblock_size: isize,
 
     udp_channel: Sender<SenderMessage>,
+    pool: PacketPool,
 
-    accumulator: u32,
-    nbits: u8,
+    frame_budget: usize,
+    cache_enabled: bool,
 
-    buffer: Vec<u8>,
+    max_packet_size: usize,
+    capabilities: u32, // Agreed in the handshake, see negotiation
+
+    link_stats: LinkStats,
+    frame_interval: Duration,
+
+    writer: BitWriter,
+    cached_packet: Packet,
 
     size_accumulator: u64,
     timestamp: u32,
+    // Packets sent for the current frame.
+    frame_packets: u16,
     monitor_info: Vec<MonitorInfo>
 }
 
-pub fn start_encoder_thread(monitor_info: Vec<MonitorInfo>,
+pub fn start_encoder_thread(config: Config,
+                            monitor_info: Vec<MonitorInfo>,
                             udp_sender: Sender<SenderMessage>,
+                            returned_packets: Receiver<Packet>,
+                            to_context: Sender<ContextMessage>,
+                            to_main: MainSender,
                             receiver: Receiver<EncoderMessage>)
     -> JoinHandle<()>
 {
     thread::spawn(move || {
-        let mut encoder = Encoder::new(monitor_info, udp_sender.clone());
-
+        let pool = PacketPool::new(returned_packets);
+        let mut encoder = Encoder::new(&config, monitor_info, udp_sender.clone(), pool);
         loop {
             match receiver.recv() {
                 Ok(EncoderMessage::FirstImage(DataBox(data))) => {
                     println!("Encoder: First image");
 
-                    encoder.initial_encode_rgb(data);
+                    let (sent, bytes) = encoder.initial_encode_rgb(data);
+                    let msg = ContextMessage::Encoded(encoder.timestamp, sent);
+
+                    to_context.send(msg).unwrap();
+                    to_main.send(MainMessage::FrameEncoded(bytes)).unwrap();
+                },
+                Ok(EncoderMessage::DataAndErrors(DataBox(data), errors, slots, cached)) => {
+                    let (sent, bytes) = encoder.update_encode_rgb(data, &errors, &slots, &cached);
+                    let msg = ContextMessage::Encoded(encoder.timestamp, sent);
+
+                    to_context.send(msg).unwrap();
+                    to_main.send(MainMessage::FrameEncoded(bytes)).unwrap();
+                },
+                Ok(EncoderMessage::Rate(decision)) => {
+                    encoder.frame_interval = decision.frame_interval;
+
+                    // Image data with the new quality starts at the next
+                    // timestamp. Clients that are not told keep the quality
+                    // they started with.
+                    if encoder.has(CAPABILITY_RATE_INFO) {
+                        encoder.set_quality(decision.quality);
+
+                        let msg = SenderMessage::RateInfo(encoder.timestamp + 1, decision);
+                        udp_sender.send(msg).unwrap();
+                    }
+                },
+                Ok(EncoderMessage::CacheSize(size)) => {
+                    encoder.cache_enabled = size > 0;
                 },
-                Ok(EncoderMessage::DataAndErrors(DataBox(data), errors)) => {
-                    encoder.update_encode_rgb(data, &errors);
+                Ok(EncoderMessage::Capabilities(capabilities)) => {
+                    encoder.capabilities = capabilities;
+                },
+                Ok(EncoderMessage::LinkStats(stats)) => {
+                    encoder.link_stats = stats;
+                },
+                Ok(EncoderMessage::MaxPacketSize(size)) => {
+                    println!("Encoder: Packets of up to {} bytes", size);
+                    encoder.max_packet_size = size;
                 },
                 Ok(EncoderMessage::Close) => {
                     println!("Encoder: Close");
@@ -95,8 +191,10 @@ pub fn start_encoder_thread(monitor_info: Vec<MonitorInfo>,
 }
 
 impl Encoder {
-    fn new(monitor_info: Vec<MonitorInfo>,
-           sender: Sender<SenderMessage>) -> Self
+    fn new(config: &Config,
+           monitor_info: Vec<MonitorInfo>,
+           sender: Sender<SenderMessage>,
+           pool: PacketPool) -> Self
     {
         let width = monitor_info[0].view_width as isize;
         let height = monitor_info[0].view_height as isize;
@@ -107,106 +205,134 @@ impl Encoder {
         let cd = build_huff_lut(&STD_CHROMA_DC_CODE_LENGTHS, &STD_CHROMA_DC_VALUES);
         let ca = build_huff_lut(&STD_CHROMA_AC_CODE_LENGTHS, &STD_CHROMA_AC_VALUES);
 
-        let mut tables = Vec::new();
-        tables.extend(STD_LUMA_QTABLE.iter().map(|&v| v));
-        tables.extend(STD_CHROMA_QTABLE.iter().map(|&v| v));
+        let packet = pool.get(OPCODE_SEND_IMAGE_DATA);
+        let cached_packet = pool.get(OPCODE_SEND_CACHED_BLOCKS);
 
         Encoder {
-            tables: tables,
-            luma_dctable: ld,
-            luma_actable: la,
-            chroma_dctable: cd,
-            chroma_actable: ca,
+            tables: build_quant_tables(config.quality),
+            huffman: HuffmanTables {
+                luma_dc: ld,
+                luma_ac: la,
+                chroma_dc: cd,
+                chroma_ac: ca,
+            },
             width: width,
             height: height,
             size: height * width * 4,
             macroblock_size: 16,
             bpp: monitor_info[0].raw_bpp,
-            accumulator: 0,
             size_accumulator: 0,
-            nbits: 0,
-            buffer: Vec::with_capacity(768),
             udp_channel: sender,
+            pool,
+            frame_budget: config.frame_budget,
+            cache_enabled: false,
+            max_packet_size: config.initial_packet_size(config.max_datagram_size, config.probe_mtu),
+            capabilities: 0,
+            link_stats: LinkStats::default(),
+            frame_interval: Duration::from_nanos(1_000_000_000 / config.fps),
+            writer: BitWriter {
+                accumulator: 0,
+                nbits: 0,
+                packet,
+            },
+            cached_packet,
             timestamp: 0,
+            frame_packets: 0,
             monitor_info: monitor_info
         }
     }
 
-    fn initial_encode_rgb(&mut self, data: *mut i8)
+    fn initial_encode_rgb(&mut self, data: *mut i8) -> (Vec<usize>, usize)
     {
-        let mut dct_yblock   = [0i32; 64];
-        let mut dct_cb_block = [0i32; 64];
-        let mut dct_cr_block = [0i32; 64];
-
-        let mut yblock   = [0u8; 64];
-        let mut cb_block = [0u8; 64];
-        let mut cr_block = [0u8; 64];
-
-        let la = self.luma_actable.clone();
-        let ld = self.luma_dctable.clone();
-        let cd = self.chroma_dctable.clone();
-        let ca = self.chroma_actable.clone();
-
-        let mut x;
-        let mut y;
-
+        let mut sent = Vec::with_capacity((self.width * self.height / 256) as usize);
+        let mut bytes = 0;
         let mut bl = 0;
 
         self.timestamp += 1;
 
         for y0 in range_step(0, self.height, self.macroblock_size) {
             for x0 in range_step(0, self.width, self.macroblock_size) {
-                self.write_bits(bl as u16, 10);
+                // Blocks of a full image are not cached.
+                let slot = if self.cache_enabled {
+                    Some(NO_SLOT)
+                } else {
+                    None
+                };
 
-                for j in 0..2 {
-                    for i in 0..2 {
-                        x = x0 + 8*i;
-                        y = y0 + 8*j;
-                        let index = self.bpp * (y * self.width + x);
-                        copy_blocks_ycbcr(data, index, self.width, self.bpp, &mut yblock, &mut cb_block, &mut cr_block);
+                bytes += self.encode_macroblock(data, bl, x0, y0, slot);
+                sent.push(bl);
 
-                        // Level shift and fdct
-                        // Coeffs are scaled by 8
-                        fdct(&yblock, &mut dct_yblock);
-                        fdct(&cb_block, &mut dct_cb_block);
-                        fdct(&cr_block, &mut dct_cr_block);
+                bl += 1;
+            }
+        }
 
-                        // Quantization
-                        for k in 0usize..64 {
-                            dct_yblock[k]   = ((dct_yblock[k] / 8)   as f32 / self.tables[k] as f32).round() as i32;
-                            dct_cb_block[k] = ((dct_cb_block[k] / 8) as f32 / self.tables[64..][k] as f32).round() as i32;
-                            dct_cr_block[k] = ((dct_cr_block[k] / 8) as f32 / self.tables[64..][k] as f32).round() as i32;
-                        }
+        self.end_of_data();
+        (sent, bytes)
+    }
 
-                        self.write_block(&dct_yblock, &ld, &la);
-                        self.write_block(&dct_cb_block, &cd, &ca);
-                        self.write_block(&dct_cr_block, &cd, &ca);
+    // Sends the references to cached blocks, then encodes the blocks in
+    // order until the frame budget is spent. Returns the encoded blocks that
+    // were sent and the number of bytes they took, the remaining blocks are
+    // carried over to the next frame by the context.
+    fn update_encode_rgb(&mut self,
+                         data: *mut i8,
+                         errors: &[(i64, usize)],
+                         slots: &[u16],
+                         cached: &[(usize, u16)])
+        -> (Vec<usize>, usize)
+    {
+        self.timestamp += 1;
 
+        for &(block, slot) in cached {
+            self.write_cached_block(block as u16, slot);
+        }
 
-                    }
-                }
-                self.write_final_bits();
+        let mut sent = Vec::with_capacity(errors.len());
+        let mut bytes = 0;
 
-                if self.buffer.len() > 0 {
-                    self.buffer.shrink_to_fit();
-                    self.udp_channel.send(SenderMessage::Macroblock(self.timestamp, self.buffer.clone())).unwrap();
-                    self.buffer = Vec::with_capacity(768);
-                }
+        // Speed up with lookup table?
+        let n_blocks_x = self.width / self.macroblock_size;
 
-                bl += 1;
+        // The context only passes blocks which must be sent, sorted by
+        // descending priority.
+        for (i, error) in errors.iter().enumerate() {
+            let (_, block) = *error;
+
+            if let Some(budget) = self.budget() {
+                if bytes >= budget {
+                    break
+                }
             }
+
+            let x0 = (block as isize % n_blocks_x) * 16;
+            let y0 = (block as isize / n_blocks_x) * 16;
+
+            let slot = if self.cache_enabled {
+                Some(slots[i])
+            } else {
+                None
+            };
+
+            bytes += self.encode_macroblock(data, block, x0, y0, slot);
+
+            sent.push(block);
         }
 
-        self.udp_channel.send(SenderMessage::EndOfData(self.timestamp)).unwrap();
+        self.end_of_data();
+        (sent, bytes)
     }
 
-    fn update_encode_rgb(&mut self,
+    // Encodes the macroblock at (x0, y0) into the current packet, preceded by
+    // its cache slot if the cache is enabled. Returns the size of the encoded
+    // block in bytes.
+    fn encode_macroblock(&mut self,
                          data: *mut i8,
-                         errors: &Vec<(i64, usize)>)
+                         block: usize,
+                         x0: isize,
+                         y0: isize,
+                         slot: Option<u16>)
         -> usize
     {
-        self.timestamp += 1;
-
         let mut dct_yblock   = [0i32; 64];
         let mut dct_cb_block = [0i32; 64];
         let mut dct_cr_block = [0i32; 64];
@@ -215,70 +341,182 @@ impl Encoder {
         let mut cb_block = [0u8; 64];
         let mut cr_block = [0u8; 64];
 
-        let la = self.luma_actable.clone();
-        let ld = self.luma_dctable.clone();
-        let cd = self.chroma_dctable.clone();
-        let ca = self.chroma_actable.clone();
+        let start = self.writer.packet.data.len();
+
+        if let Some(slot) = slot {
+            self.writer.write_slot(slot);
+        }
+
+        self.writer.write_bits(block as u16, 10);
 
-        let mut sent = 0;
+        for y in range_step(y0, y0+16, 8) {
+            for x in range_step(x0, x0+16, 8) { // 2 * 4 * 8
+                let index = self.bpp * (y * self.width + x);
+                copy_blocks_ycbcr(data, index, self.width, self.bpp, &mut yblock, &mut cb_block, &mut cr_block);
 
-        for error in errors {
-            let (err, block) = *error;
-            if err < 10000 {
-                // errors are sorted, 0 => no more changed blocks
-                // TODO: don't add zero-error blocks to the error vec?
-                break
+                // Level shift and fdct
+                // Coeffs are scaled by 8
+                fdct(&yblock, &mut dct_yblock);
+                fdct(&cb_block, &mut dct_cb_block);
+                fdct(&cr_block, &mut dct_cr_block);
+
+                // Quantization
+                for k in 0usize..64 {
+                    dct_yblock[k]   = ((dct_yblock[k] / 8)   as f32 / self.tables[k] as f32).round() as i32;
+                    dct_cb_block[k] = ((dct_cb_block[k] / 8) as f32 / self.tables[64..][k] as f32).round() as i32;
+                    dct_cr_block[k] = ((dct_cr_block[k] / 8) as f32 / self.tables[64..][k] as f32).round() as i32;
+                }
+
+                let huffman = &self.huffman;
+                self.writer.write_block(&dct_yblock, &huffman.luma_dc, &huffman.luma_ac);
+                self.writer.write_block(&dct_cb_block, &huffman.chroma_dc, &huffman.chroma_ac);
+                self.writer.write_block(&dct_cr_block, &huffman.chroma_dc, &huffman.chroma_ac);
             }
+        }
 
-            self.write_bits(block as u16, 10);
+        self.writer.write_final_bits();
 
-            // Speed up with lookup table?
+        let len = self.writer.packet.data.len();
 
-            let n_blocks_x = self.width / self.macroblock_size;
+        // A block too large for a packet of its own is sent in fragments.
+        if self.has(CAPABILITY_FRAGMENTS) & (HEADER_SIZE + len - start > self.max_packet_size) {
+            self.send_fragments(block, start);
+            return len - start
+        }
 
-            let x0 = (block as isize % n_blocks_x) * 16;
-            let y0 = (block as isize / n_blocks_x) * 16;
+        // If the block does not fit, the packet is sent without it and the
+        // block is moved to the start of a new packet.
+        if (len >= self.max_packet_size) & (start > HEADER_SIZE) {
+            let mut next = self.pool.get(OPCODE_SEND_IMAGE_DATA);
+            next.data.extend_from_slice(&self.writer.packet.data[start..]);
 
+            self.writer.packet.data.truncate(start);
 
-            for y in range_step(y0, y0+16, 8) {
-                for x in range_step(x0, x0+16, 8) { // 2 * 4 * 8
-                    let index = self.bpp * (y * self.width + x);
-                    copy_blocks_ycbcr(data, index, self.width, self.bpp, &mut yblock, &mut cb_block, &mut cr_block);
+            let full = mem::replace(&mut self.writer.packet, next);
+            self.send_packet(full);
+        }
 
-                    // Level shift and fdct
-                    // Coeffs are scaled by 8
-                    fdct(&yblock, &mut dct_yblock);
-                    fdct(&cb_block, &mut dct_cb_block);
-                    fdct(&cr_block, &mut dct_cr_block);
+        self.writer.packet.blocks.push(block as u16);
 
-                    // Quantization
-                    for k in 0usize..64 {
-                        dct_yblock[k]   = ((dct_yblock[k] / 8)   as f32 / self.tables[k] as f32).round() as i32;
-                        dct_cb_block[k] = ((dct_cb_block[k] / 8) as f32 / self.tables[64..][k] as f32).round() as i32;
-                        dct_cr_block[k] = ((dct_cr_block[k] / 8) as f32 / self.tables[64..][k] as f32).round() as i32;
-                    }
+        len - start
+    }
 
-                    self.write_block(&dct_yblock, &ld, &la);
-                    self.write_block(&dct_cb_block, &cd, &ca);
-                    self.write_block(&dct_cr_block, &cd, &ca);
-                }
-            }
+    // Moves the encoded block at the end of the current packet into
+    // fragments. Each fragment counts as holding the block, so the loss of
+    // any of them gets the block sent again. Fragments of the smallest
+    // datagrams hold about 200 bytes, so the 255 a u8 counts hold far more
+    // than a macroblock ever encodes to.
+    fn send_fragments(&mut self, block: usize, start: usize)
+    {
+        let encoded = self.writer.packet.data.split_off(start);
 
-            self.write_final_bits();
+        let capacity = self.max_packet_size - HEADER_SIZE - FRAGMENT_HEADER_SIZE;
+        let count = encoded.len().div_ceil(capacity);
 
-            if self.buffer.len() > 0 {
-                self.buffer.shrink_to_fit();
-                self.udp_channel.send(SenderMessage::Macroblock(self.timestamp, self.buffer.clone())).unwrap();
-                self.buffer = Vec::with_capacity(768);
-            }
+        assert!(count <= u8::MAX as usize,
+                "block {} of {} bytes needs {} fragments of {} bytes",
+                block, encoded.len(), count, capacity);
+
+        for (index, part) in encoded.chunks(capacity).enumerate() {
+            let mut fragment = self.pool.get(OPCODE_SEND_IMAGE_FRAGMENT);
+
+            fragment.data.extend_from_slice(&(block as u16).to_be_bytes());
+            fragment.data.push(index as u8);
+            fragment.data.push(count as u8);
+            fragment.data.extend_from_slice(part);
+            fragment.blocks.push(block as u16);
+
+            self.send_packet(fragment);
+        }
+    }
+
+    // The block is in the client's cache, only its position and cache slot
+    // are sent.
+    fn write_cached_block(&mut self, block: u16, slot: u16)
+    {
+        if self.cached_packet.data.len() + 4 >= self.max_packet_size {
+            let next = self.pool.get(OPCODE_SEND_CACHED_BLOCKS);
+            let full = mem::replace(&mut self.cached_packet, next);
+            self.send_packet(full);
+        }
+
+        let packet = &mut self.cached_packet;
+
+        packet.blocks.push(block);
+        packet.data.push((block >> 8) as u8);
+        packet.data.push(block as u8);
+        packet.data.push((slot >> 8) as u8);
+        packet.data.push(slot as u8);
+    }
+
+    // Sends the partially filled packets of the current frame, then the end
+    // of frame record if the frame sent anything.
+    fn end_of_data(&mut self)
+    {
+        if self.cached_packet.has_data() {
+            let next = self.pool.get(OPCODE_SEND_CACHED_BLOCKS);
+            let full = mem::replace(&mut self.cached_packet, next);
+            self.send_packet(full);
+        }
+
+        if self.writer.packet.has_data() {
+            let next = self.pool.get(OPCODE_SEND_IMAGE_DATA);
+            let full = mem::replace(&mut self.writer.packet, next);
+            self.send_packet(full);
+        }
+
+        if (self.frame_packets > 0) & self.has(CAPABILITY_END_OF_FRAME) {
+            let mut end = self.pool.get(OPCODE_SEND_END_OF_FRAME);
+            end.data.push((self.frame_packets >> 8) as u8);
+            end.data.push(self.frame_packets as u8);
 
-            sent += 1;
+            let msg = SenderMessage::Packet(self.timestamp, end);
+            self.udp_channel.send(msg).unwrap();
         }
 
-        self.udp_channel.send(SenderMessage::EndOfData(self.timestamp)).unwrap();
-        sent
+        self.frame_packets = 0;
     }
 
+    fn has(&self, capability: u32) -> bool
+    {
+        self.capabilities & capability != 0
+    }
+
+    fn send_packet(&mut self, packet: Packet)
+    {
+        self.frame_packets += 1;
+
+        let msg = SenderMessage::Packet(self.timestamp, packet);
+        self.udp_channel.send(msg).unwrap();
+    }
+
+    fn set_quality(&mut self, quality: u8)
+    {
+        self.tables = build_quant_tables(quality);
+    }
+
+    // The configured frame budget. While the link loses packets, frames are
+    // also kept to a little more than the link delivers per frame interval.
+    fn budget(&self) -> Option<usize>
+    {
+        let configured = if self.frame_budget > 0 {
+            Some(self.frame_budget)
+        } else {
+            None
+        };
+
+        if self.link_stats.loss_rate <= LOSSY_LINK {
+            return configured
+        }
+
+        let per_frame = self.link_stats.throughput as f64 / 8.0 * self.frame_interval.as_secs_f64();
+        let link = ((per_frame * LINK_HEADROOM) as usize).max(MIN_LINK_BUDGET);
+
+        Some(configured.map_or(link, |budget| budget.min(link)))
+    }
+}
+
+impl BitWriter {
     fn huffman_encode(&mut self, val: u8, table: &[(u8, u16)])
     {
         let (size, code) = table[val as usize];
@@ -341,6 +579,14 @@ impl Encoder {
         dcval
     }
 
+    // The slot precedes the bit-packed block, so it is written while the bit
+    // accumulator is empty.
+    fn write_slot(&mut self, slot: u16)
+    {
+        self.packet.data.push((slot >> 8) as u8);
+        self.packet.data.push(slot as u8);
+    }
+
     fn write_bits(&mut self, bits: u16, size: u8)
     {
         if size == 0 {
@@ -353,7 +599,7 @@ impl Encoder {
         while self.nbits >= 8 {
             let byte = (self.accumulator & 0xFF000000u32) >> 24;
 
-            self.buffer.push(byte as u8);
+            self.packet.data.push(byte as u8);
             self.nbits -= 8;
             self.accumulator <<= 8;
 
@@ -369,7 +615,7 @@ impl Encoder {
 
         while self.nbits >= 8 {
             let byte = (self.accumulator & (0xFFFFFFFFu32 << 24)) >> 24;
-            self.buffer.push(byte as u8);
+            self.packet.data.push(byte as u8);
 
             self.nbits -= 8;
             self.accumulator <<= 8;
@@ -377,10 +623,10 @@ impl Encoder {
 
         if self.nbits != 0 {
             let byte = (self.accumulator & (0xFFFFFFFFu32 << 24)) >> 24;
-            self.buffer.push(byte as u8);
+            self.packet.data.push(byte as u8);
 
             if byte == 0xFF {
-                self.buffer.push(0x00);
+                self.packet.data.push(0x00);
             }
         }
 
@@ -389,6 +635,27 @@ impl Encoder {
     }
 }
 
+// Scales the standard quantization tables as libjpeg does. A quality of 50
+// gives the standard tables, clients rebuild the tables the same way.
+fn build_quant_tables(quality: u8) -> Vec<u8>
+{
+    let quality = quality.clamp(1, 100) as u32;
+
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+
+    let scaled = |&v: &u8| ((v as u32 * scale + 50) / 100).clamp(1, 255) as u8;
+
+    let mut tables = Vec::with_capacity(128);
+    tables.extend(STD_LUMA_QTABLE.iter().map(&scaled));
+    tables.extend(STD_CHROMA_QTABLE.iter().map(&scaled));
+
+    tables
+}
+
 fn copy_blocks_ycbcr(source: *mut i8,
                      index: isize,
                      width: isize,
@@ -448,4 +715,95 @@ fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> (u8, u8, u8)
     let cr =  0.5f32    * r - 0.4187f32 * g - 0.0813f32 * b + 128f32;
 
     (y as u8, cb as u8, cr as u8)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    fn encoder(max_packet_size: usize) -> (Encoder, Receiver<SenderMessage>)
+    {
+        let monitor = MonitorInfo {
+            name: String::new(),
+            width: 64,
+            height: 64,
+            offset_x: 0,
+            offset_y: 0,
+            view_width: 64,
+            view_height: 64,
+            raw_bpp: 4,
+            midpoints_x: Vec::new(),
+            midpoints_y: Vec::new(),
+        };
+
+        let (sender, receiver) = channel();
+        let (_, returned) = channel();
+
+        let mut encoder = Encoder::new(&Config::default(), vec![monitor], sender, PacketPool::new(returned));
+        encoder.max_packet_size = max_packet_size;
+
+        (encoder, receiver)
+    }
+
+    // Appends an encoded block of the given size to the packet being
+    // filled, after a block that fits.
+    fn write_block(encoder: &mut Encoder, size: usize) -> (usize, Vec<u8>)
+    {
+        encoder.writer.packet.data.extend_from_slice(&[0xAA; 20]);
+
+        let start = encoder.writer.packet.data.len();
+        let block: Vec<u8> = (0..size).map(|i| (i * 13) as u8).collect();
+        encoder.writer.packet.data.extend_from_slice(&block);
+
+        (start, block)
+    }
+
+    #[test]
+    fn oversized_block_is_fragmented()
+    {
+        let (mut encoder, sent) = encoder(100);
+        let (start, block) = write_block(&mut encoder, 1000);
+
+        encoder.send_fragments(300, start);
+
+        // The block leaves the packet it was written to.
+        assert_eq!(encoder.writer.packet.data.len(), start);
+
+        let fragments: Vec<Packet> = sent.try_iter()
+            .map(|msg| match msg {
+                SenderMessage::Packet(_, packet) => packet,
+                msg => panic!("unexpected {:?}", msg)
+            })
+            .collect();
+
+        // 87 bytes of the block fit each fragment.
+        assert_eq!(fragments.len(), 12);
+        assert_eq!(encoder.frame_packets, 12);
+
+        let mut reassembled = Vec::new();
+
+        for (index, fragment) in fragments.iter().enumerate() {
+            let header = &fragment.data[HEADER_SIZE..HEADER_SIZE + FRAGMENT_HEADER_SIZE];
+
+            assert_eq!(fragment.data[0], OPCODE_SEND_IMAGE_FRAGMENT);
+            assert!(fragment.data.len() <= 100);
+            assert_eq!(header, [1, 44, index as u8, 12]);
+            assert_eq!(fragment.blocks, [300]);
+
+            reassembled.extend_from_slice(&fragment.data[HEADER_SIZE + FRAGMENT_HEADER_SIZE..]);
+        }
+
+        assert_eq!(reassembled, block);
+    }
+
+    #[test]
+    #[should_panic(expected = "fragments")]
+    fn too_many_fragments()
+    {
+        let (mut encoder, _sent) = encoder(HEADER_SIZE + FRAGMENT_HEADER_SIZE + 1);
+        let (start, _) = write_block(&mut encoder, 256);
+
+        encoder.send_fragments(0, start);
+    }
+}
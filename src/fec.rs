@@ -0,0 +1,250 @@
+use std::str::FromStr;
+
+use super::packet::{Packet, HEADER_SIZE};
+
+use super::protocol::OPCODE_SEND_PARITY;
+
+/// Bytes a parity packet holds in addition to the longest packet of its
+/// group: the first packet id, the number of packets and the XOR of their
+/// lengths.
+pub const PARITY_HEADER_SIZE: usize = 7;
+
+// Group sizes chosen from the loss rate. Below the lowest loss rate no
+// parity is sent, above it groups are sized so that a group rarely loses
+// more than one packet.
+const MIN_LOSS_RATE: f64 = 0.01;
+const MIN_GROUP_SIZE: usize = 2;
+const MAX_GROUP_SIZE: usize = 16;
+
+/// Forward error correction of image packets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FecMode {
+    Off,
+    /// Group size following the measured loss rate.
+    Auto,
+    /// Parity after every given number of packets.
+    Fixed(usize),
+}
+
+impl FromStr for FecMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String>
+    {
+        match s {
+            "off" => Ok(FecMode::Off),
+            "auto" => Ok(FecMode::Auto),
+            _ => match s.parse() {
+                Ok(0) => Ok(FecMode::Off),
+                Ok(size) if size <= MAX_GROUP_SIZE => Ok(FecMode::Fixed(size)),
+                _ => Err(format!("invalid FEC mode '{}'", s))
+            }
+        }
+    }
+}
+
+impl FecMode {
+    /// Packets per parity packet at the given loss rate, 0 for none.
+    pub fn group_size(&self, loss_rate: f64) -> usize
+    {
+        match *self {
+            FecMode::Off => 0,
+            FecMode::Fixed(size) => size,
+            FecMode::Auto if loss_rate < MIN_LOSS_RATE => 0,
+            FecMode::Auto => ((0.5 / loss_rate) as usize).clamp(MIN_GROUP_SIZE, MAX_GROUP_SIZE)
+        }
+    }
+}
+
+/// The XOR of consecutive packets of one timestamp. A parity packet holds,
+/// after the usual header, the u32 id of the first packet, the u8 number of
+/// packets and the u16 XOR of their lengths, then the XOR of the packets,
+/// each padded with zeros to the longest. A client missing one packet of the
+/// group recovers it from the others and the parity.
+#[derive(Debug)]
+pub struct ParityGroup {
+    timestamp: u32,
+    first_id: u32,
+    count: usize,
+    lengths: u16,
+    parity: Vec<u8>,
+}
+
+impl ParityGroup {
+    pub fn new() -> Self
+    {
+        ParityGroup {
+            timestamp: 0,
+            first_id: 0,
+            count: 0,
+            lengths: 0,
+            parity: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.count
+    }
+
+    pub fn timestamp(&self) -> Option<u32>
+    {
+        if self.count > 0 {
+            Some(self.timestamp)
+        } else {
+            None
+        }
+    }
+
+    /// Adds a sent packet, header included.
+    pub fn add(&mut self, timestamp: u32, id: u32, data: &[u8])
+    {
+        if self.count == 0 {
+            self.timestamp = timestamp;
+            self.first_id = id;
+            self.lengths = 0;
+            self.parity.clear();
+        }
+
+        if self.parity.len() < data.len() {
+            self.parity.resize(data.len(), 0);
+        }
+
+        for (p, d) in self.parity.iter_mut().zip(data) {
+            *p ^= d;
+        }
+
+        self.lengths ^= data.len() as u16;
+        self.count += 1;
+    }
+
+    /// The parity packet of the group, which starts a new group.
+    pub fn take(&mut self) -> Packet
+    {
+        let mut data = Vec::with_capacity(HEADER_SIZE + PARITY_HEADER_SIZE + self.parity.len());
+
+        data.push(OPCODE_SEND_PARITY);
+        data.extend_from_slice(&[0u8; HEADER_SIZE - 1]);
+        data.extend_from_slice(&self.first_id.to_be_bytes());
+        data.push(self.count as u8);
+        data.extend_from_slice(&self.lengths.to_be_bytes());
+        data.extend_from_slice(&self.parity);
+
+        self.count = 0;
+
+        Packet {
+            data,
+            blocks: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rebuilds the one packet of the group that is missing as a client does.
+    fn rebuild(parity: &[u8], received: &[Vec<u8>]) -> Vec<u8>
+    {
+        let header = &parity[HEADER_SIZE..HEADER_SIZE + PARITY_HEADER_SIZE];
+        let mut length = u16::from_be_bytes([header[5], header[6]]);
+        let mut data = parity[HEADER_SIZE + PARITY_HEADER_SIZE..].to_vec();
+
+        for packet in received {
+            length ^= packet.len() as u16;
+
+            for (d, p) in data.iter_mut().zip(packet) {
+                *d ^= p;
+            }
+        }
+
+        data.truncate(length as usize);
+        data
+    }
+
+    #[test]
+    fn rebuilds_any_packet_of_uneven_lengths()
+    {
+        let lengths = [HEADER_SIZE + 1, HEADER_SIZE + 700, HEADER_SIZE, HEADER_SIZE + 1184, HEADER_SIZE + 37];
+        let packets: Vec<Vec<u8>> = lengths.iter()
+            .enumerate()
+            .map(|(i, &length)| (0..length).map(|j| (i * 31 + j * 7) as u8 | 1).collect())
+            .collect();
+
+        let mut group = ParityGroup::new();
+        assert_eq!(group.timestamp(), None);
+
+        for (i, packet) in packets.iter().enumerate() {
+            group.add(42, 1000 + i as u32, packet);
+        }
+
+        assert_eq!(group.len(), packets.len());
+        assert_eq!(group.timestamp(), Some(42));
+
+        let parity = group.take().data;
+        assert_eq!(group.len(), 0);
+
+        assert_eq!(parity[0], OPCODE_SEND_PARITY);
+        assert_eq!(parity.len(), HEADER_SIZE + PARITY_HEADER_SIZE + lengths.iter().max().unwrap());
+        assert_eq!(&parity[HEADER_SIZE..HEADER_SIZE + 5], &[0, 0, 3, 232, packets.len() as u8]);
+
+        for lost in 0..packets.len() {
+            let mut received = packets.clone();
+            let missing = received.remove(lost);
+
+            assert_eq!(rebuild(&parity, &received), missing);
+        }
+    }
+
+    #[test]
+    fn take_starts_a_new_group()
+    {
+        let mut group = ParityGroup::new();
+        group.add(1, 5, &[1u8; 40]);
+        group.take();
+
+        group.add(2, 9, &[2u8; 20]);
+        let parity = group.take().data;
+
+        assert_eq!(&parity[HEADER_SIZE..HEADER_SIZE + PARITY_HEADER_SIZE], &[0, 0, 0, 9, 1, 0, 20]);
+        assert_eq!(&parity[HEADER_SIZE + PARITY_HEADER_SIZE..], &[2u8; 20][..]);
+    }
+
+    #[test]
+    fn group_size_bounds()
+    {
+        for &loss_rate in &[0.0, 0.005, 0.01, 0.1, 0.5, 1.0] {
+            assert_eq!(FecMode::Off.group_size(loss_rate), 0);
+            assert_eq!(FecMode::Fixed(4).group_size(loss_rate), 4);
+        }
+
+        // No parity below the lowest loss rate, then from the largest group
+        // down to the smallest.
+        assert_eq!(FecMode::Auto.group_size(0.0), 0);
+        assert_eq!(FecMode::Auto.group_size(MIN_LOSS_RATE * 0.99), 0);
+        assert_eq!(FecMode::Auto.group_size(MIN_LOSS_RATE), MAX_GROUP_SIZE);
+        assert_eq!(FecMode::Auto.group_size(0.1), 5);
+        assert_eq!(FecMode::Auto.group_size(0.5), MIN_GROUP_SIZE);
+        assert_eq!(FecMode::Auto.group_size(1.0), MIN_GROUP_SIZE);
+
+        let mut previous = MAX_GROUP_SIZE;
+
+        for percent in 1..=100 {
+            let size = FecMode::Auto.group_size(percent as f64 / 100.0);
+
+            assert!((MIN_GROUP_SIZE..=previous).contains(&size));
+            previous = size;
+        }
+    }
+
+    #[test]
+    fn parse()
+    {
+        assert_eq!("off".parse(), Ok(FecMode::Off));
+        assert_eq!("auto".parse(), Ok(FecMode::Auto));
+        assert_eq!("0".parse(), Ok(FecMode::Off));
+        assert_eq!("16".parse(), Ok(FecMode::Fixed(MAX_GROUP_SIZE)));
+        assert!("17".parse::<FecMode>().is_err());
+        assert!("-1".parse::<FecMode>().is_err());
+    }
+}
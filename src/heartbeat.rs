@@ -1,7 +1,9 @@
 use super::protocol::{
     MainMessage,
+    MainSender,
     HeartbeatMessage,
-    ReceiverMessage
+    ReceiverMessage,
+    SenderMessage
 };
 
 use std::sync::mpsc::{
@@ -15,50 +17,115 @@ use std::thread::{
     JoinHandle
 };
 
-use std::time::Duration;
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+    UNIX_EPOCH
+};
+
+/// Bounds of the liveness timeout in seconds a client may ask for.
+pub const MIN_LIVENESS_TIMEOUT: u16 = 2;
+pub const MAX_LIVENESS_TIMEOUT: u16 = 60;
+
+// Keepalives sent per liveness timeout, so that a client notices a lost one
+// well before it gives up on the server.
+const KEEPALIVES_PER_TIMEOUT: u32 = 3;
+
+/// The liveness timeout granted to a client that asked for the given one.
+pub fn liveness_timeout(requested: Option<u16>, default: u16) -> u16
+{
+    requested.unwrap_or(default).clamp(MIN_LIVENESS_TIMEOUT, MAX_LIVENESS_TIMEOUT)
+}
 
-pub fn start_heartbeat_thread(to_main: Sender<MainMessage>,
-                              to_udp_receiver: Sender<ReceiverMessage>,
+// The session is watched from the start, with the given timeout until main
+// tells the one negotiated in the handshake. Keepalives go to the client
+// once it is accepted.
+//
+// The receiver of a TCP session ends with it, the UDP receiver is shared by
+// all sessions and has no receiver to tell. Main decides whether a session
+// without heartbeats is closed or waits for its client to resume it.
+pub fn start_heartbeat_thread(to_main: MainSender,
+                              to_receiver: Option<Sender<ReceiverMessage>>,
+                              to_sender: Sender<SenderMessage>,
                               receiver: Receiver<HeartbeatMessage>,
-                              timeout: u64)
+                              timeout: Duration)
     -> JoinHandle<()>
 {
     thread::spawn(move || {
-        // Start heartbeat once first heartbeat is received. As a result, it is optional.
-        match receiver.recv() {
-            Ok(HeartbeatMessage::Close) => {
-                println!("Heartbeat: Close initial");
-                return
-            },
-            Ok(HeartbeatMessage::Heartbeat) => {
-                let timeout_duration = Duration::from_secs(timeout);
-
-                println!("Rec init hb");
-                loop {
-                    match receiver.recv_timeout(timeout_duration) {
-                        Ok(HeartbeatMessage::Heartbeat) => (),
-                        Ok(HeartbeatMessage::Close) => {
-                            println!("Heartbeat: Close loop");
-                            return
-                        },
-                        Err(RecvTimeoutError::Timeout) => {
-                            println!("Heartbeat: Timeout");
-                            to_main.send(MainMessage::Close).unwrap();
-                            to_udp_receiver.send(ReceiverMessage::HeartbeatTimeout).unwrap();
-
-                            return
-                        },
-                        Err(RecvTimeoutError::Disconnected) => {
-                            println!("Heartbeat: Timeout");
-                            to_main.send(MainMessage::Close).unwrap();
-                            return
-                        }
+        let mut timeout = timeout;
+        let mut last_heartbeat = Instant::now();
+        let mut lost = false;
+
+        // Next keepalive and its sequence number, once accepted.
+        let mut next_keepalive: Option<Instant> = None;
+        let mut sequence = 0u32;
+
+        loop {
+            // Without heartbeats only the client coming back is waited for.
+            let message = if lost {
+                receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                let deadline = match next_keepalive {
+                    Some(keepalive) => keepalive.min(last_heartbeat + timeout),
+                    None => last_heartbeat + timeout
+                };
+
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            };
+
+            let now = Instant::now();
+
+            match message {
+                Ok(HeartbeatMessage::Heartbeat) => {
+                    last_heartbeat = now;
+
+                    if lost {
+                        println!("Heartbeat: Resumed");
+                        lost = false;
+                        to_main.send(MainMessage::HeartbeatResumed).unwrap();
                     }
+                },
+                Ok(HeartbeatMessage::Liveness(negotiated, keepalives)) => {
+                    println!("Heartbeat: Timeout of {:?}", negotiated);
+                    timeout = negotiated;
+                    last_heartbeat = now;
+                    next_keepalive = if keepalives { Some(now) } else { None };
+                },
+                Ok(HeartbeatMessage::Close) => {
+                    println!("Heartbeat: Close");
+                    return
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    println!("Heartbeat: Timeout");
+                    to_main.send(MainMessage::Close).unwrap();
+                    return
+                }
+            }
+
+            if lost {
+                continue
+            }
+
+            if now >= last_heartbeat + timeout {
+                println!("Heartbeat: Timeout");
+                lost = true;
+                to_main.send(MainMessage::HeartbeatLost).unwrap();
+
+                if let Some(ref to_receiver) = to_receiver {
+                    let _ = to_receiver.send(ReceiverMessage::HeartbeatTimeout);
                 }
-            },
-            _ => {
-                return
+            } else if next_keepalive.is_some_and(|keepalive| now >= keepalive) {
+                sequence = sequence.wrapping_add(1);
+
+                let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+                // The sender closes before this thread does.
+                let _ = to_sender.send(SenderMessage::Keepalive(time.as_millis() as u64, sequence));
+
+                next_keepalive = Some(now + timeout / KEEPALIVES_PER_TIMEOUT);
             }
         }
     })
-}
\ No newline at end of file
+}
@@ -0,0 +1,170 @@
+use std::collections::VecDeque;
+
+use std::fmt;
+
+use std::time::{
+    Duration,
+    Instant
+};
+
+// Length of the window over which loss and throughput are measured.
+const WINDOW_MS: u64 = 2000;
+
+/// How the link to the client is doing, as measured from acknowledgements.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkStats {
+    pub srtt: Duration,
+    pub rttvar: Duration,
+    pub min_rtt: Duration,
+    // Fraction of packets lost over the window.
+    pub loss_rate: f64,
+    // Bits per second acknowledged over the window.
+    pub throughput: u64,
+}
+
+impl LinkStats {
+    /// The wire format: smoothed RTT, RTT variation and lowest RTT in
+    /// microseconds as u32, the loss rate in units of 1/10000 as u16 and the
+    /// throughput in bits per second as u32.
+    pub fn serialize(&self, out: &mut Vec<u8>)
+    {
+        let micros = |d: Duration| d.as_micros().min(u32::MAX as u128) as u32;
+
+        out.extend_from_slice(&micros(self.srtt).to_be_bytes());
+        out.extend_from_slice(&micros(self.rttvar).to_be_bytes());
+        out.extend_from_slice(&micros(self.min_rtt).to_be_bytes());
+        out.extend_from_slice(&((self.loss_rate * 10000.0).round() as u16).to_be_bytes());
+        out.extend_from_slice(&(self.throughput.min(u32::MAX as u64) as u32).to_be_bytes());
+    }
+}
+
+impl fmt::Display for LinkStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "rtt {:.1} ms (+/- {:.1} ms, min {:.1} ms), loss {:.1}%, {} kbit/s",
+               self.srtt.as_secs_f64() * 1000.0,
+               self.rttvar.as_secs_f64() * 1000.0,
+               self.min_rtt.as_secs_f64() * 1000.0,
+               self.loss_rate * 100.0,
+               self.throughput / 1000)
+    }
+}
+
+/// Keeps the outcome of recent packets to measure loss and throughput.
+#[derive(Debug)]
+pub struct LinkMonitor {
+    // Time, size and whether the packet was delivered.
+    outcomes: VecDeque<(Instant, usize, bool)>,
+    min_rtt: Option<Duration>,
+}
+
+impl LinkMonitor {
+    pub fn new() -> Self
+    {
+        LinkMonitor {
+            outcomes: VecDeque::new(),
+            min_rtt: None,
+        }
+    }
+
+    pub fn delivered(&mut self, size: usize, rtt: Duration, now: Instant)
+    {
+        self.outcomes.push_back((now, size, true));
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+    }
+
+    pub fn lost(&mut self, size: usize, now: Instant)
+    {
+        self.outcomes.push_back((now, size, false));
+    }
+
+    /// The statistics over the window, given the smoothed RTT and its
+    /// variation.
+    pub fn stats(&mut self, srtt: Duration, rttvar: Duration, now: Instant) -> LinkStats
+    {
+        let window = Duration::from_millis(WINDOW_MS);
+
+        while let Some(&(time, _, _)) = self.outcomes.front() {
+            if now.duration_since(time) > window {
+                self.outcomes.pop_front();
+            } else {
+                break
+            }
+        }
+
+        let lost = self.outcomes.iter().filter(|&&(_, _, delivered)| !delivered).count();
+        let bytes: usize = self.outcomes.iter()
+            .filter(|&&(_, _, delivered)| delivered)
+            .map(|&(_, size, _)| size)
+            .sum();
+
+        LinkStats {
+            srtt,
+            rttvar,
+            min_rtt: self.min_rtt.unwrap_or(srtt),
+            loss_rate: if self.outcomes.is_empty() { 0.0 } else { lost as f64 / self.outcomes.len() as f64 },
+            throughput: bytes as u64 * 8 * 1000 / WINDOW_MS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn loss_and_throughput_over_the_window()
+    {
+        let start = Instant::now();
+        let mut monitor = LinkMonitor::new();
+
+        // Nothing measured yet.
+        let stats = monitor.stats(RTT, RTT / 2, start);
+        assert_eq!(stats.loss_rate, 0.0);
+        assert_eq!(stats.min_rtt, RTT);
+
+        for _ in 0..3 {
+            monitor.delivered(1000, RTT, start);
+        }
+        monitor.delivered(1000, RTT / 2, start);
+        monitor.lost(1000, start);
+
+        let stats = monitor.stats(RTT, RTT / 2, start);
+        assert_eq!(stats.loss_rate, 0.2);
+        assert_eq!(stats.throughput, 4000 * 8 * 1000 / WINDOW_MS);
+        assert_eq!(stats.min_rtt, RTT / 2);
+
+        // Outcomes older than the window are forgotten, the lowest RTT is
+        // not.
+        let later = start + Duration::from_millis(WINDOW_MS + 1);
+        monitor.delivered(1000, RTT, later);
+
+        let stats = monitor.stats(RTT, RTT / 2, later);
+        assert_eq!(stats.loss_rate, 0.0);
+        assert_eq!(stats.throughput, 1000 * 8 * 1000 / WINDOW_MS);
+        assert_eq!(stats.min_rtt, RTT / 2);
+    }
+
+    #[test]
+    fn serialize()
+    {
+        let stats = LinkStats {
+            srtt: Duration::from_micros(0x01020304),
+            rttvar: Duration::from_micros(5),
+            min_rtt: Duration::from_secs(5000),
+            loss_rate: 0.1234,
+            throughput: 1 << 40,
+        };
+
+        let mut out = Vec::new();
+        stats.serialize(&mut out);
+
+        assert_eq!(out, [1, 2, 3, 4,
+                         0, 0, 0, 5,
+                         0xFF, 0xFF, 0xFF, 0xFF,
+                         0x04, 0xD2,
+                         0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+}
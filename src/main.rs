@@ -3,307 +3,1219 @@ extern crate num_iter;
 extern crate regex;
 extern crate libxdo;
 
+mod auth;
+mod block_cache;
+mod capture;
+mod codec;
+mod config;
+mod congestion;
 mod context;
+mod control;
+mod crypto;
 mod encoder;
+mod fec;
 mod heartbeat;
+mod link_stats;
+mod metric;
 mod monitor_info;
 mod mouse;
+mod negotiation;
+mod net;
+mod packet;
 mod pending_acks;
 mod protocol;
+mod rate_control;
 mod tables;
+mod tcp;
 mod udp;
 mod util;
+mod websocket;
 mod xinterface;
 
+use auth::{constant_time_eq, Authenticator, Challenge, Role, SESSION_TOKEN_SIZE};
+
+use capture::Capture;
+
+use codec::{Handshake, OPCODE_RECEIVE_EXIT};
+
+use config::{Config, Transport, PROBE_START_SIZE};
+
+use control::ControlCommand;
+
+use link_stats::LinkStats;
+
 use monitor_info::MonitorInfo;
 
+use negotiation::{Agreement, CAPABILITY_DENIED, CAPABILITY_FEC, CAPABILITY_KEEPALIVE, CAPABILITY_LINK_STATS,
+                  CAPABILITY_MTU_PROBE, CAPABILITY_SESSION_RESUME};
+
+use rate_control::RateController;
+
 use protocol::
 {
+    ClientId,
     ContextMessage,
+    HeartbeatMessage,
     MainMessage,
+    MainSender,
     SenderMessage,
+
+    REJECT_BUSY,
+    REJECT_UNSUPPORTED_VERSION,
+    REJECT_AUTH_FAILED,
+    REJECT_TOO_MANY_ATTEMPTS,
+    REJECT_UNKNOWN_SESSION,
 };
 
-use std::str;
+use tcp::TcpClient;
+
+use udp::{KeyExchange, Udp};
+
+use std::collections::HashMap;
+
+use std::net::SocketAddr;
+
+use std::sync::{Arc, Mutex};
 
 use std::sync::mpsc::
 {
     channel,
     Sender,
-    Receiver,
     RecvTimeoutError,
 };
 
-use std::thread::JoinHandle;
+use std::thread::{self, JoinHandle};
 
-use std::time::Duration;
+use std::time::{
+    Duration,
+    Instant
+};
 
 const MIN_SUPPORTED_PROTOCOL_VERSION: u8 = 1;
-const MAX_SUPPORTED_PROTOCOL_VERSION: u8 = 1;
+const MAX_SUPPORTED_PROTOCOL_VERSION: u8 = 2;
+
+// The first version that negotiates capabilities, see negotiation.
+const NEGOTIATED_PROTOCOL_VERSION: u8 = 2;
+
+// Sessions are connected over the transport the server listens on.
+enum Server {
+    Udp(udp::UdpServer),
+    Tcp(tcp::TcpServer),
+}
+
+impl Server {
+    fn close(self)
+    {
+        match self {
+            Server::Udp(server) => server.close(),
+            Server::Tcp(server) => server.close()
+        }
+    }
+}
+
+// How main answers a client that is not accepted yet, until the sender of
+// its session takes over.
+enum Link {
+    Udp(Udp),
+    Tcp(TcpClient),
+}
+
+impl Link {
+    fn send_challenge(&mut self, challenge: &Challenge)
+    {
+        match *self {
+            Link::Udp(ref mut udp) => udp.send_challenge(challenge.protocol_version, &challenge.challenge),
+            Link::Tcp(ref mut tcp) => tcp.send_challenge(challenge.protocol_version, &challenge.challenge)
+        }
+    }
+
+    fn send_reject(&mut self, reason: u8)
+    {
+        match *self {
+            Link::Udp(ref mut udp) => udp.send_reject(reason),
+            Link::Tcp(ref mut tcp) => tcp.send_reject(reason)
+        }
+    }
+}
+
+// What a client was granted in its handshake.
+#[derive(Clone, Copy, Default)]
+struct Terms {
+    // Granted if the client asked for one.
+    liveness_timeout: Option<u16>,
+    // What a version 2 client agreed to.
+    agreement: Option<Agreement>,
+}
+
+// A client that is not accepted yet. Main answers it directly, its threads
+// are only started once it is accepted.
+struct PendingClient {
+    src: SocketAddr,
+    link: Link,
+    challenge: Option<Challenge>,
+    terms: Terms,
+    // Clients that do not handshake in time are dropped.
+    since: Instant,
+}
+
+impl PendingClient {
+    fn new(src: SocketAddr, link: Link) -> Self
+    {
+        PendingClient {
+            src,
+            link,
+            challenge: None,
+            terms: Terms::default(),
+            since: Instant::now(),
+        }
+    }
+}
+
+// How main answers a client that is not accepted yet.
+enum Answer {
+    Reject(u8),
+    Challenge(Challenge),
+    Accept(u8, Option<u16>, Role), // Protocol version, cache size and role
+}
+
+// The threads and state of one client. Each client views and is rated on its
+// own, screenshots are shared through the capture.
+struct Session {
+    src: SocketAddr,
+    handles: Vec<JoinHandle<()>>,
+    context_sender: Sender<ContextMessage>,
+    sender_sender: Sender<SenderMessage>,
+    heartbeat_sender: Sender<HeartbeatMessage>,
+    // Set when one of its threads is gone, main closes the session then.
+    broken: bool,
+    role: Role,
+    terms: Terms,
+    // Largest datagram the client takes, at most the configured one.
+    max_datagram_size: usize,
+    // Issued on accept over UDP, resumes the session from another address.
+    token: Option<[u8; SESSION_TOKEN_SIZE]>,
+    // When a session without heartbeats is closed unless resumed.
+    suspended: Option<Instant>,
+    view: Option<(u8, u8)>, // Screen and segment
+    link_stats: LinkStats,
+    packet_size: usize,
+    fec_group_size: usize,
+    rate_controller: RateController,
+    frame_duration: Duration,
+    next_frame: Instant,
+}
+
+impl Session {
+    // Messages to the threads of the session. Main closes a session whose
+    // threads are gone.
+    fn send_to_context(&mut self, msg: ContextMessage)
+    {
+        self.broken |= self.context_sender.send(msg).is_err();
+    }
+
+    fn send_to_sender(&mut self, msg: SenderMessage)
+    {
+        self.broken |= self.sender_sender.send(msg).is_err();
+    }
+
+    fn send_to_heartbeat(&mut self, msg: HeartbeatMessage)
+    {
+        self.broken |= self.heartbeat_sender.send(msg).is_err();
+    }
+
+    fn update_rate(&mut self)
+    {
+        if self.view.is_none() {
+            return
+        }
+
+        if let Some(decision) = self.rate_controller.update() {
+            println!("Main: Rate {:?}", decision);
+
+            self.frame_duration = decision.frame_interval;
+            self.send_to_context(ContextMessage::Rate(decision));
+        }
+    }
+
+    // Whether the client agreed to a capability. Version 1 clients take none.
+    fn allows(&self, capability: u32) -> bool
+    {
+        self.terms.agreement.is_some_and(|agreement| agreement.has(capability))
+    }
+
+    // Whether the path of the session is probed for larger packets.
+    fn probes_mtu(&self, config: &Config) -> bool
+    {
+        config.probe_mtu & self.allows(CAPABILITY_MTU_PROBE)
+    }
+
+    // Starts over with the packet size a new path gets.
+    fn reset_packet_size(&mut self, config: &Config)
+    {
+        let size = config.initial_packet_size(self.max_datagram_size, self.probes_mtu(config));
+
+        if size != self.packet_size {
+            self.packet_size = size;
+            self.send_to_context(ContextMessage::MaxPacketSize(size));
+        }
+    }
+
+    // Continues the session at the client's new address. The new path is
+    // probed from the start.
+    fn resume(&mut self, config: &Config, src: SocketAddr, key_exchange: Option<KeyExchange>)
+    {
+        self.src = src;
+        self.suspended = None;
+        self.send_to_sender(SenderMessage::Resume(src, key_exchange));
+
+        self.reset_packet_size(config);
+    }
+}
 
 fn main ()
 {
+    let mut config = Config::from_args();
     let xdo_session = mouse::new_session();
     let monitor_info = MonitorInfo::get_all();
-    let mut current_screen = None;
-    let mut current_segment = None;
-
-    //divide fr by 1.003, result is closer to wanted framerate.
-    let mut fr = 10u64;
-    fr = 1_000_000_000_000u64 / (1003u64 * fr);
-    let frame_duration = Duration::new(0, fr as u32);
-
-    // Outer loop.
-    // Break from this loop to exit program.
-    'outer: loop {
-        let mut src = None;
-        let mut has_init = false;
-        let mut protocol_version;
-
-        println!("Start threads.");
-        let (handles, context_sender, udp_sender_sender, main_receiver) =
-            start_threads(&monitor_info, 5);
-
-        // Inner loop.
-        // Update image once per frame duration.
-        // Breaking from this loop will reset all threads.
-        'inner: loop {
-            match main_receiver.recv_timeout(frame_duration) {
-                Ok(MainMessage::Handshake(new_src, min, max))
-                    if src.as_ref().is_none()
-                     & (max >= MIN_SUPPORTED_PROTOCOL_VERSION)
-                     & (min <= MAX_SUPPORTED_PROTOCOL_VERSION) =>
-                {
-                    println!("Main: Accept handshake");
-
-                    // Pick the highest supported protocol version
-                    protocol_version = if max >= MAX_SUPPORTED_PROTOCOL_VERSION {
-                        MAX_SUPPORTED_PROTOCOL_VERSION
-                    } else {
-                        max
-                    };
+    let capture = Arc::new(Mutex::new(Capture::new()));
 
-                    // Set the source to reject future handshake requests.
-                    src = Some(new_src);
+    let mut min_frame_duration = Duration::from_nanos(1_000_000_000 / config.fps);
 
-                    // Acknowledge handshake
-                    let msg = SenderMessage::AcceptHandshake(new_src, protocol_version);
-                    udp_sender_sender.send(msg).unwrap();
-                },
-                Ok(MainMessage::Handshake(new_src, _, _)) => {
-                    println!("Main: Reject handshake");
-                    // reject, there is another active connection or unsupported protocol version.
-                    let msg = SenderMessage::RejectHandshake(new_src);
-                    udp_sender_sender.send(msg).unwrap();
-                },
-                Ok(MainMessage::RequestScreenInfo) => {
-                    if src.as_ref().is_some() {
-                        println!("Main: Request screen info");
-                        let msg = SenderMessage::ScreenInfo(
-                            MonitorInfo::serialize_vec(&monitor_info)
-                        );
-                        udp_sender_sender.send(msg).unwrap();
-                    }
-                },
-                Ok(MainMessage::RequestView(screen, segment)) => {
-                    if src.as_ref().is_some() {
-                        println!("Main: Request view");
+    // Failed attempts are remembered across sessions.
+    let mut authenticator = config.credentials.clone().map(Authenticator::new);
+
+    // Messages from all sessions arrive here, tagged with their client.
+    let (main_sender, main_receiver) = channel();
+
+    let server = match config.transport {
+        Transport::Udp => Server::Udp(udp::init_udp_sockets(&config, main_sender.clone())),
+        Transport::Tcp | Transport::WebSocket => Server::Tcp(tcp::start_listener(&config, main_sender.clone()))
+    };
+
+    let control_server = config.control_socket.clone().map(|path| {
+        match control::start_control_listener(&path, main_sender.clone()) {
+            Ok(control) => control,
+            Err(e) => panic!("Could not bind control socket {}: {}", path.display(), e)
+        }
+    });
+
+    let mut sessions: HashMap<ClientId, Session> = HashMap::new();
+    let mut pending_clients: HashMap<ClientId, PendingClient> = HashMap::new();
+
+    // Screenshots are taken at most once per tick, sessions with a longer
+    // frame interval skip ticks.
+    let mut frame = 0u64;
+    let mut next_tick = Instant::now() + min_frame_duration;
 
-                        current_screen = Some(screen);
-                        current_segment = Some(segment);
+    loop {
+        let timeout = next_tick.saturating_duration_since(Instant::now());
 
-                        let msg = ContextMessage::RequestView(screen, segment);
-                        context_sender.send(msg).unwrap();
+        let (client, message) = match main_receiver.recv_timeout(timeout) {
+            Ok(received) => received,
+            Err(RecvTimeoutError::Timeout) => {
+                frame += 1;
+                next_tick += min_frame_duration;
 
-                        has_init = true;
+                let now = Instant::now();
+                if next_tick < now {
+                    next_tick = now + min_frame_duration;
+                }
+
+                for session in sessions.values_mut() {
+                    let due = session.next_frame <= now;
+
+                    if session.view.is_some() & session.suspended.is_none() & due {
+                        session.next_frame += session.frame_duration;
+
+                        if session.next_frame < now {
+                            session.next_frame = now + session.frame_duration;
+                        }
+
+                        session.send_to_context(ContextMessage::NewScreenshot(frame));
                     }
-                },
-                Ok(MainMessage::Refresh) => {
-                    if src.as_ref().is_some() {
-                        println!("Main: Refresh");
-                        let msg = ContextMessage::Refresh;
-                        context_sender.send(msg).unwrap();
+
+                    session.update_rate();
+                }
+
+                let expired: Vec<ClientId> = sessions.iter()
+                    .filter(|(_, session)| session.suspended.is_some_and(|until| until <= now))
+                    .map(|(&client, _)| client)
+                    .collect();
+
+                for client in expired {
+                    println!("Main: Session {} was not resumed", client);
+                    close_session(sessions.remove(&client).unwrap(), client, &server);
+                }
+
+                let broken: Vec<ClientId> = sessions.iter()
+                    .filter(|(_, session)| session.broken)
+                    .map(|(&client, _)| client)
+                    .collect();
+
+                for client in broken {
+                    println!("Main: Session {} failed", client);
+                    close_session(sessions.remove(&client).unwrap(), client, &server);
+                }
+
+                let handshake_timeout = Duration::from_secs(config.liveness_timeout as u64);
+
+                let expired: Vec<ClientId> = pending_clients.iter()
+                    .filter(|(_, pending)| match pending.challenge {
+                        Some(ref challenge) => challenge.is_expired(now),
+                        None => pending.since + handshake_timeout <= now
+                    })
+                    .map(|(&client, _)| client)
+                    .collect();
+
+                for client in expired {
+                    let mut pending = pending_clients.remove(&client).unwrap();
+
+                    if pending.challenge.is_some() {
+                        println!("Main: Challenge of {} expired", client);
+                        pending.link.send_reject(REJECT_AUTH_FAILED);
+                    } else {
+                        println!("Main: No handshake from {}", client);
                     }
-                },
-                Ok(MainMessage::Close) => {
-                    if src.as_ref().is_some() {
-                        println!("Main: Close");
-                        context_sender.send(ContextMessage::Close).unwrap();
 
-                        join_threads(handles);
+                    close_pending(pending, client, &server);
+                }
+
+                continue;
+            },
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+
+        // New connections wait for their handshake. A client that moved
+        // takes its session along.
+        let mut message = match message {
+            MainMessage::Connected(src, connection) => {
+                pending_clients.insert(client, PendingClient::new(src, Link::Tcp(connection)));
+                continue;
+            },
+            MainMessage::Control(command, reply) => {
+                let exit = control(command, reply, &mut sessions, &mut config, &server, &capture, &monitor_info);
+
+                if exit {
+                    break; // Exit server.
+                }
+
+                min_frame_duration = Duration::from_nanos(1_000_000_000 / config.fps);
+                continue;
+            },
+            MainMessage::Resume(src, token, key_exchange) => {
+                let resumed = sessions.iter()
+                    .find(|(_, session)| session.token.as_ref().is_some_and(|own| constant_time_eq(own, &token)))
+                    .map(|(&resumed, _)| resumed);
+
+                match resumed {
+                    Some(resumed) => {
+                        println!("Main: Resume session {} from {}", resumed, net::display_address(&src));
+
+                        if resumed != client {
+                            if let Server::Udp(ref server) = server {
+                                server.resume(client, resumed);
+                            }
+
+                            // The address had a session of its own.
+                            pending_clients.remove(&client);
+                            retire(&mut sessions, src, resumed, &server);
+                        }
 
-                        break 'inner; // Start waiting for connection
+                        sessions.get_mut(&resumed).unwrap().resume(&config, src, key_exchange);
+                        continue;
+                    },
+                    None => MainMessage::Resume(src, token, key_exchange)
+                }
+            },
+            message => message
+        };
+
+        // Clients are answered by main until they are accepted, their
+        // threads are only started then.
+        if !sessions.contains_key(&client) {
+            let new_client = match message {
+                MainMessage::Handshake(src, _, ref mut key_exchange)
+                    | MainMessage::Resume(src, _, ref mut key_exchange) => Some((src, key_exchange.take())),
+                _ => None
+            };
+
+            // UDP clients are known by their first datagram, TCP clients
+            // since they connected.
+            let mut pending = match (pending_clients.remove(&client), new_client, &server) {
+                (Some(pending), _, _) => pending,
+                (None, Some((src, key_exchange)), Server::Udp(udp_server)) => match udp_server.reply_to(&config, src, key_exchange) {
+                    Some(udp) => PendingClient::new(src, Link::Udp(udp)),
+                    None => {
+                        udp_server.disconnect(client);
+                        continue
                     }
                 },
-                Ok(MainMessage::Exit) => {
-                    if src.as_ref().is_some() {
-                        println!("Main: Exit");
-                        context_sender.send(ContextMessage::Close).unwrap();
+                // Messages of clients that were rejected or closed meanwhile.
+                _ => continue
+            };
 
-                        join_threads(handles);
+            let now = Instant::now();
 
-                        break 'outer; // Exit server.
-                    }
+            let answer = match message {
+                MainMessage::Handshake(src, handshake, _) => {
+                    let busy = is_busy(&sessions, &pending_clients, &config, now);
+                    let (answer, terms) = answer_handshake(&config, &monitor_info, &mut authenticator, busy, (src, &handshake), now);
+
+                    pending.terms = terms;
+                    answer
                 },
-                Ok(MainMessage::LeftClick(x, y)) => {
-                    if src.as_ref().is_some() {
-                        println!("Main: Left Click");
-                        let (offset_x, offset_y) =
-                            get_offset(&monitor_info,
-                                       current_screen.unwrap(),
-                                       current_segment.unwrap());
-
-                        xdo_session.move_mouse(offset_x + x as i32,
-                                               offset_y + y as i32,
-                                               0).unwrap();
-
-                        xdo_session.click(1).unwrap();
+                MainMessage::AuthResponse(src, user, response) => match pending.challenge.take() {
+                    Some(challenge) if challenge.src == src => {
+                        answer_auth(authenticator.as_mut().unwrap(), &config, challenge, (&user, &response), now)
+                    },
+                    other => {
+                        pending.challenge = other;
+                        pending_clients.insert(client, pending);
+                        continue
                     }
                 },
-                Ok(MainMessage::RightClick(x, y)) => {
-                    if src.as_ref().is_some() {
-                        println!("Main: Right Click");
-                        let (offset_x, offset_y) =
-                            get_offset(&monitor_info,
-                                       current_screen.unwrap(),
-                                       current_segment.unwrap());
-
-                        xdo_session.move_mouse(offset_x + x as i32,
-                                               offset_y + y as i32,
-                                               0).unwrap();
-
-                        xdo_session.click(3).unwrap();
-                    }
+                // Nothing to resume.
+                MainMessage::Resume(..) => {
+                    println!("Main: Reject resume");
+                    Answer::Reject(REJECT_UNKNOWN_SESSION)
                 },
-                Ok(MainMessage::DoubleClick(x, y)) => {
-                    if src.as_ref().is_some() {
-                        println!("Main: Double Click");
-                        let (offset_x, offset_y) =
-                            get_offset(&monitor_info,
-                                       current_screen.unwrap(),
-                                       current_segment.unwrap());
-
-                        xdo_session.move_mouse(offset_x + x as i32,
-                                               offset_y + y as i32,
-                                               0).unwrap();
-                        xdo_session.click(1).unwrap();
-
-                        xdo_session.move_mouse(offset_x + x as i32,
-                                               offset_y + y as i32,
-                                               0).unwrap();
-
-                        xdo_session.click(1).unwrap();
-                    }
+                // A TCP client left or was served the viewer page.
+                MainMessage::Close => {
+                    close_pending(pending, client, &server);
+                    continue
                 },
-                Ok(MainMessage::Drag(x0, y0, screen0, segment0, x1, y1, screen1, segment1)) => {
-                    if src.as_ref().is_some() {
-                        println!("Main: Drag");
+                _ => {
+                    pending_clients.insert(client, pending);
+                    continue
+                }
+            };
 
-                        let (offset_x0, offset_y0) = get_offset(&monitor_info, screen0, segment0);
-                        let (offset_x1, offset_y1) = get_offset(&monitor_info, screen1, segment1);
+            match answer {
+                Answer::Reject(reason) => {
+                    pending.link.send_reject(reason);
+                    close_pending(pending, client, &server);
+                },
+                Answer::Challenge(challenge) => {
+                    pending.link.send_challenge(&challenge);
+                    pending.challenge = Some(challenge);
+                    pending_clients.insert(client, pending);
+                },
+                Answer::Accept(protocol_version, cache_size, role) => {
+                    // A client that starts over replaces its old session.
+                    retire(&mut sessions, pending.src, client, &server);
 
-                        xdo_session.move_mouse(offset_x0 + x0 as i32, offset_y0 + y0 as i32, 0).unwrap();
-                        xdo_session.mouse_down(1).unwrap();
-                        std::thread::sleep(std::time::Duration::from_millis(250));
+                    println!("Main: Start session {}", client);
 
-                        xdo_session.move_mouse_relative(offset_x1 + x1 as i32 - offset_x0 - x0 as i32,
-                                                        offset_y1 + y1 as i32 - offset_y0 - y0 as i32).unwrap();
-                        xdo_session.mouse_up(1).unwrap();
-                    }
-                },
-                Ok(MainMessage::Keyboard(data)) => {
-                    if src.as_ref().is_some() {
-                        let msg = str::from_utf8(&(data[1..])).unwrap();
-                        xdo_session.send_keysequence(&msg, 10).unwrap();
+                    let mut session = start_threads(&config,
+                                                    &monitor_info,
+                                                    &capture,
+                                                    &server,
+                                                    (pending.src, pending.link),
+                                                    MainSender::new(client, main_sender.clone()));
+                    session.terms = pending.terms;
+
+                    accept_handshake(&config, protocol_version, cache_size, &mut session, role);
+                    sessions.insert(client, session);
+                }
+            }
+
+            continue;
+        }
+
+        // Messages of sessions that were closed meanwhile are dropped.
+        let mut session = match sessions.remove(&client) {
+            Some(session) => session,
+            None => continue
+        };
+
+        let mut open = true;
+        let mut exit = false;
+
+        // Input of clients that may not control the screen is dropped.
+        let control = session.role.can_control();
+
+        match message {
+            MainMessage::RequestScreenInfo => {
+                println!("Main: Request screen info");
+                let msg = SenderMessage::ScreenInfo(
+                    MonitorInfo::serialize_vec(&monitor_info)
+                );
+                session.send_to_sender(msg);
+            },
+            MainMessage::RequestView(screen, segment) => {
+                if get_offset(&monitor_info, screen, segment).is_none() {
+                    println!("Main: No view {}/{}", screen, segment);
+                } else {
+                    println!("Main: Request view");
+
+                    session.view = Some((screen, segment));
+
+                    // Tell the client the current quality before the
+                    // first image is encoded with it.
+                    let msg = ContextMessage::Rate(session.rate_controller.decision());
+                    session.send_to_context(msg);
+
+                    let msg = ContextMessage::RequestView(screen, segment);
+                    session.send_to_context(msg);
+                }
+            },
+            MainMessage::Refresh => {
+                println!("Main: Refresh");
+                let msg = ContextMessage::Refresh;
+                session.send_to_context(msg);
+            },
+            MainMessage::Close => {
+                println!("Main: Close");
+                open = false;
+            },
+            // The client may come back from another address.
+            MainMessage::HeartbeatLost => {
+                if session.token.is_some() {
+                    println!("Main: Session {} suspended", client);
+                    session.suspended = Some(Instant::now() + Duration::from_secs(config.resume_grace));
+                } else {
+                    println!("Main: Close");
+                    open = false;
+                }
+            },
+            MainMessage::HeartbeatResumed => {
+                session.suspended = None;
+            },
+            MainMessage::Exit => {
+                if session.role.can_exit() {
+                    println!("Main: Exit");
+                    exit = true;
+                } else {
+                    println!("Main: Exit denied to {:?}", session.role);
+
+                    if session.allows(CAPABILITY_DENIED) {
+                        session.send_to_sender(SenderMessage::Denied(OPCODE_RECEIVE_EXIT));
                     }
-                },
-                Err(RecvTimeoutError::Timeout) if has_init => {
-                    if src.as_ref().is_some() {
-                        let msg = ContextMessage::NewScreenshot;
-                        context_sender.send(msg).unwrap();
+                }
+            },
+            MainMessage::LeftClick(x, y) => {
+                let offset = session.view.filter(|_| control)
+                    .and_then(|(screen, segment)| get_offset(&monitor_info, screen, segment));
+
+                if let Some((offset_x, offset_y)) = offset {
+                    println!("Main: Left Click");
+
+                    xdo_session.move_mouse(offset_x + x as i32,
+                                           offset_y + y as i32,
+                                           0).unwrap();
+
+                    xdo_session.click(1).unwrap();
+                }
+            },
+            MainMessage::RightClick(x, y) => {
+                let offset = session.view.filter(|_| control)
+                    .and_then(|(screen, segment)| get_offset(&monitor_info, screen, segment));
+
+                if let Some((offset_x, offset_y)) = offset {
+                    println!("Main: Right Click");
+
+                    xdo_session.move_mouse(offset_x + x as i32,
+                                           offset_y + y as i32,
+                                           0).unwrap();
+
+                    xdo_session.click(3).unwrap();
+                }
+            },
+            MainMessage::DoubleClick(x, y) => {
+                let offset = session.view.filter(|_| control)
+                    .and_then(|(screen, segment)| get_offset(&monitor_info, screen, segment));
+
+                if let Some((offset_x, offset_y)) = offset {
+                    println!("Main: Double Click");
+
+                    xdo_session.move_mouse(offset_x + x as i32,
+                                           offset_y + y as i32,
+                                           0).unwrap();
+                    xdo_session.click(1).unwrap();
+
+                    xdo_session.move_mouse(offset_x + x as i32,
+                                           offset_y + y as i32,
+                                           0).unwrap();
+
+                    xdo_session.click(1).unwrap();
+                }
+            },
+            MainMessage::Drag(x0, y0, screen0, segment0, x1, y1, screen1, segment1) => {
+                let offsets = get_offset(&monitor_info, screen0, segment0)
+                    .zip(get_offset(&monitor_info, screen1, segment1))
+                    .filter(|_| control);
+
+                if let Some(((offset_x0, offset_y0), (offset_x1, offset_y1))) = offsets {
+                    println!("Main: Drag");
+
+                    xdo_session.move_mouse(offset_x0 + x0 as i32, offset_y0 + y0 as i32, 0).unwrap();
+                    xdo_session.mouse_down(1).unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(250));
+
+                    xdo_session.move_mouse_relative(offset_x1 + x1 as i32 - offset_x0 - x0 as i32,
+                                                    offset_y1 + y1 as i32 - offset_y0 - y0 as i32).unwrap();
+                    xdo_session.mouse_up(1).unwrap();
+                }
+            },
+            MainMessage::Keyboard(text) => {
+                if control {
+                    if let Err(e) = xdo_session.send_keysequence(&text, 10) {
+                        println!("Main: Keyboard input failed: {:?}", e);
                     }
-                },
-                Err(_) => (),
+                }
+            },
+            MainMessage::FrameEncoded(bytes) => {
+                session.rate_controller.frame_sent(bytes);
+            },
+            MainMessage::Delivered(bytes) => {
+                session.rate_controller.bytes_delivered(bytes);
+            },
+            MainMessage::LinkStats(stats) => {
+                println!("Main: Link {}", stats);
+
+                session.link_stats = stats;
+                session.rate_controller.link_stats(&stats);
+                session.send_to_context(ContextMessage::LinkStats(stats));
+
+                // More loss, more parity.
+                let group_size = if session.allows(CAPABILITY_FEC) {
+                    config.fec.group_size(stats.loss_rate)
+                } else {
+                    0
+                };
+
+                if group_size != session.fec_group_size {
+                    println!("Main: FEC group size {}", group_size);
+
+                    session.fec_group_size = group_size;
+                    session.send_to_sender(SenderMessage::FecGroupSize(group_size));
+                }
+            },
+            // Probes arrive in any order, the largest one that made it
+            // through sets the packet size.
+            MainMessage::MtuProbeAck(size) => {
+                let size = (size as usize).min(config.packet_size(session.max_datagram_size));
+
+                if session.probes_mtu(&config) & (size > session.packet_size) {
+                    println!("Main: Path carries packets of {} bytes", size);
+
+                    session.packet_size = size;
+                    session.send_to_context(ContextMessage::MaxPacketSize(size));
+                }
+            },
+            // The interface refused a datagram of the session, packets go
+            // back to the size every path carries and grow with the probes.
+            MainMessage::MtuExceeded => {
+                let size = config.packet_size(session.max_datagram_size.min(PROBE_START_SIZE));
+
+                if size < session.packet_size {
+                    println!("Main: Datagrams too large for the interface, packets of {} bytes", size);
+
+                    session.packet_size = size;
+                    session.send_to_context(ContextMessage::MaxPacketSize(size));
+                }
+            },
+            MainMessage::RequestStats => {
+                if session.allows(CAPABILITY_LINK_STATS) {
+                    let msg = SenderMessage::LinkStats(session.link_stats);
+                    session.send_to_sender(msg);
+                }
+            },
+            // Sessions are only started once their handshake is accepted,
+            // see above.
+            MainMessage::Handshake(..)
+                | MainMessage::AuthResponse(..)
+                | MainMessage::Resume(..)
+                | MainMessage::Connected(..)
+                | MainMessage::Control(..) => ()
+        }
+
+        if exit {
+            close_session(session, client, &server);
+
+            for (other, session) in sessions.drain() {
+                close_session(session, other, &server);
             }
+
+            break; // Exit server.
+        } else if open {
+            session.update_rate();
+        }
+
+        if session.broken {
+            println!("Main: Session {} failed", client);
+            close_session(session, client, &server);
+        } else if open {
+            sessions.insert(client, session);
+        } else {
+            close_session(session, client, &server);
         }
     }
 
+    server.close();
+
+    if let Some(control_server) = control_server {
+        control_server.close();
+    }
+
     println!("Closed.");
 }
 
-fn start_threads(monitor_info: &Vec<MonitorInfo>,
-                 heartbeat_timeout: u64)
-    -> (Vec<JoinHandle<()>>,
-        Sender<ContextMessage>,
-        Sender<SenderMessage>,
-        Receiver<MainMessage>)
+// Starts the threads of a new session. The session's messages to main are
+// tagged by the given sender.
+fn start_threads(config: &Config,
+                 monitor_info: &[MonitorInfo],
+                 capture: &Arc<Mutex<Capture>>,
+                 server: &Server,
+                 (src, link): (SocketAddr, Link),
+                 main_sender: MainSender)
+    -> Session
 {
     let mut handles: Vec<JoinHandle<()>> = Vec::with_capacity(6);
 
     // Create channels.
     let (context_sender, context_receiver) = channel();
     let (encoder_sender, encoder_receiver) = channel();
-    let (main_sender, main_receiver) = channel();
-    let (pending_ack_sender, pending_ack_receiver) = channel();
-    let (udp_sender_sender, udp_sender_receiver) = channel();
+    let (pool_sender, pool_receiver) = channel();
+    let (sender_sender, sender_receiver) = channel();
     let (heartbeat_sender, heartbeat_receiver) = channel();
-    let (udp_receiver_sender, udp_receiver_receiver) = channel();
 
     // Start threads
     handles.push(
-        context::start_context_thread(monitor_info.clone(),
+        context::start_context_thread(config.clone(),
+                                      monitor_info.to_vec(),
+                                      capture.clone(),
                                       encoder_sender,
                                       context_receiver));
 
     handles.push(
-        encoder::start_encoder_thread(monitor_info.clone(),
-                                      udp_sender_sender.clone(),
+        encoder::start_encoder_thread(config.clone(),
+                                      monitor_info.to_vec(),
+                                      sender_sender.clone(),
+                                      pool_receiver,
+                                      context_sender.clone(),
+                                      main_sender.clone(),
                                       encoder_receiver));
 
-    handles.push(
-        pending_acks::start_pending_ack_thread(context_sender.clone(),
-                                               pending_ack_receiver));
+    // Over TCP every packet that is written arrives, so there are no
+    // pending acks to keep track of.
+    let receiver_sender = match (server, link) {
+        (Server::Udp(server), Link::Udp(udp)) => {
+            let (pending_ack_sender, pending_ack_receiver) = channel();
+
+            handles.push(
+                pending_acks::start_pending_ack_thread(context_sender.clone(),
+                                                       main_sender.clone(),
+                                                       sender_sender.clone(),
+                                                       pending_ack_receiver));
+
+            server.connect(main_sender.client(), src, heartbeat_sender.clone(), pending_ack_sender.clone());
 
-    let (sender_handle, receiver_handle) =
-        udp::init_udp_sockets(pending_ack_sender,
-                              udp_receiver_receiver,
-                              udp_sender_receiver,
-                              main_sender.clone(),
-                              heartbeat_sender.clone());
+            handles.push(
+                server.start_sender(config,
+                                    udp,
+                                    pending_ack_sender,
+                                    sender_receiver,
+                                    pool_sender,
+                                    (main_sender.clone(), context_sender.clone())));
 
-    handles.push(sender_handle);
-    handles.push(receiver_handle);
+            None
+        },
+        (Server::Tcp(_), Link::Tcp(connection)) => {
+            let (sender_handle, receiver_handle, receiver_sender) =
+                connection.accept(context_sender.clone(),
+                                  (sender_sender.clone(), sender_receiver),
+                                  pool_sender,
+                                  main_sender.clone(),
+                                  heartbeat_sender.clone());
+
+            handles.push(sender_handle);
+            handles.push(receiver_handle);
+
+            Some(receiver_sender)
+        },
+        _ => unreachable!()
+    };
 
     handles.push(
-        heartbeat::start_heartbeat_thread(main_sender.clone(),
-                                          udp_receiver_sender,
+        heartbeat::start_heartbeat_thread(main_sender,
+                                          receiver_sender,
+                                          sender_sender.clone(),
                                           heartbeat_receiver,
-                                          heartbeat_timeout));
+                                          Duration::from_secs(config.liveness_timeout as u64)));
 
-    (handles,
-     context_sender,
-     udp_sender_sender,
-     main_receiver)
+    let min_frame_duration = Duration::from_nanos(1_000_000_000 / config.fps);
+
+    Session {
+        src,
+        handles,
+        context_sender,
+        sender_sender,
+        heartbeat_sender,
+        broken: false,
+        role: config.role,
+        terms: Terms::default(),
+        max_datagram_size: config.max_datagram_size,
+        token: None,
+        suspended: None,
+        view: None,
+        link_stats: LinkStats::default(),
+        packet_size: config.initial_packet_size(config.max_datagram_size, config.probe_mtu),
+        fec_group_size: config.fec.group_size(0.0),
+        rate_controller: RateController::new(config.target_bitrate,
+                                             config.quality,
+                                             config.threshold,
+                                             min_frame_duration),
+        frame_duration: min_frame_duration,
+        next_frame: Instant::now(),
+    }
 }
 
+fn accept_handshake(config: &Config,
+                    protocol_version: u8,
+                    cache_size: Option<u16>,
+                    session: &mut Session,
+                    role: Role)
+{
+    println!("Main: Accept handshake as {:?}", role);
+
+    session.role = role;
+
+    let msg = ContextMessage::CacheSize(cache_size.unwrap_or(0));
+    session.send_to_context(msg);
+
+    let capabilities = session.terms.agreement.map_or(0, |agreement| agreement.capabilities);
+    session.send_to_context(ContextMessage::Capabilities(capabilities));
+
+    // Acknowledge handshake
+    let msg = SenderMessage::AcceptHandshake(protocol_version,
+                                             cache_size,
+                                             session.terms.liveness_timeout,
+                                             session.terms.agreement);
+    session.send_to_sender(msg);
+
+    if let Some(agreement) = session.terms.agreement {
+        session.max_datagram_size = agreement.max_datagram_size as usize;
+    }
+
+    session.reset_packet_size(config);
+
+    if !session.allows(CAPABILITY_FEC) & (session.fec_group_size != 0) {
+        session.fec_group_size = 0;
+        session.send_to_sender(SenderMessage::FecGroupSize(0));
+    }
+
+    let timeout = session.terms.liveness_timeout.unwrap_or(config.liveness_timeout);
+    let msg = HeartbeatMessage::Liveness(Duration::from_secs(timeout as u64),
+                                         session.allows(CAPABILITY_KEEPALIVE));
+    session.send_to_heartbeat(msg);
+
+    // Over UDP the client may take the session to another address.
+    if (config.transport == Transport::Udp) & (config.resume_grace > 0) & session.allows(CAPABILITY_SESSION_RESUME) {
+        match auth::session_token() {
+            Ok(token) => {
+                session.token = Some(token);
+                session.send_to_sender(SenderMessage::SessionToken(token));
+            },
+            Err(e) => println!("Main: No session token: {}", e)
+        }
+    }
+}
+
+// Answers the handshake of a client that is not accepted yet and settles
+// what it is granted.
+fn answer_handshake(config: &Config,
+                    monitor_info: &[MonitorInfo],
+                    authenticator: &mut Option<Authenticator>,
+                    busy: bool,
+                    (src, handshake): (SocketAddr, &Handshake),
+                    now: Instant)
+    -> (Answer, Terms)
+{
+    // Version 2 comes with a negotiation, other handshakes are answered in
+    // version 1.
+    let (min, max) = match handshake.negotiation {
+        Some(_) => (handshake.min_version.max(NEGOTIATED_PROTOCOL_VERSION), handshake.max_version),
+        None => (handshake.min_version, handshake.max_version.min(NEGOTIATED_PROTOCOL_VERSION - 1))
+    };
+
+    let agreement = handshake.negotiation.as_ref().map(|negotiation| {
+        negotiation::negotiate(config, &monitor_info[0], negotiation)
+    });
+
+    let rejection = if busy {
+        Some(REJECT_BUSY)
+    } else if (max < MIN_SUPPORTED_PROTOCOL_VERSION) | (min > MAX_SUPPORTED_PROTOCOL_VERSION) | (min > max) {
+        Some(REJECT_UNSUPPORTED_VERSION)
+    } else if let Some(Err(reason)) = agreement {
+        Some(reason)
+    } else if authenticator.as_mut().is_some_and(|auth| auth.is_locked_out(src, now)) {
+        Some(REJECT_TOO_MANY_ATTEMPTS)
+    } else {
+        None
+    };
+
+    if let Some(reason) = rejection {
+        println!("Main: Reject handshake ({})", reason);
+        return (Answer::Reject(reason), Terms::default())
+    }
+
+    // Pick the highest supported protocol version
+    let protocol_version = if max >= MAX_SUPPORTED_PROTOCOL_VERSION {
+        MAX_SUPPORTED_PROTOCOL_VERSION
+    } else {
+        max
+    };
+
+    // Grant at most the configured number of cache slots.
+    let mut cache_size = handshake.cache_size.map(|size| size.min(config.cache_size));
+
+    let mut terms = Terms {
+        liveness_timeout: handshake.liveness_timeout.map(|timeout| {
+            heartbeat::liveness_timeout(Some(timeout), config.liveness_timeout)
+        }),
+        agreement: None,
+    };
+
+    if let Some(Ok(agreement)) = agreement {
+        println!("Main: Agreed to {:?}", agreement);
+
+        cache_size = Some(agreement.cache_size);
+        terms.liveness_timeout = Some(agreement.liveness_timeout);
+        terms.agreement = Some(agreement);
+    }
+
+    let answer = match *authenticator {
+        Some(ref auth) => {
+            println!("Main: Challenge handshake");
+            Answer::Challenge(auth.challenge(src, protocol_version, cache_size, now).unwrap())
+        },
+        None => Answer::Accept(protocol_version, cache_size, config.role)
+    };
+
+    (answer, terms)
+}
+
+// Answers the response to a challenge. Authenticated users may have a role
+// of their own.
+fn answer_auth(auth: &mut Authenticator,
+               config: &Config,
+               challenge: Challenge,
+               (user, response): (&str, &[u8]),
+               now: Instant)
+    -> Answer
+{
+    if challenge.is_expired(now) {
+        println!("Main: Challenge expired");
+        Answer::Reject(REJECT_AUTH_FAILED)
+    } else if auth.verify(&challenge, user, response, now) {
+        println!("Main: Authenticated '{}'", user);
+
+        let role = auth.role(user).unwrap_or(config.role);
+        Answer::Accept(challenge.protocol_version, challenge.cache_size, role)
+    } else {
+        println!("Main: Authentication of '{}' failed", user);
+        Answer::Reject(REJECT_AUTH_FAILED)
+    }
+}
+
+// Whether the server has as many clients as it takes. Clients answering
+// their challenge count until it times out.
+fn is_busy(sessions: &HashMap<ClientId, Session>,
+           pending_clients: &HashMap<ClientId, PendingClient>,
+           config: &Config,
+           now: Instant)
+    -> bool
+{
+    let pending = pending_clients.values()
+        .filter(|pending| pending.challenge.as_ref().is_some_and(|challenge| !challenge.is_expired(now)))
+        .count();
+
+    sessions.len() + pending >= config.max_clients
+}
+
+// Closes the sessions at the address of a client other than its own, the
+// client started over.
+fn retire(sessions: &mut HashMap<ClientId, Session>, src: SocketAddr, client: ClientId, server: &Server)
+{
+    let replaced: Vec<ClientId> = sessions.iter()
+        .filter(|&(&other, session)| (other != client) & (session.src == src))
+        .map(|(&other, _)| other)
+        .collect();
+
+    for other in replaced {
+        close_session(sessions.remove(&other).unwrap(), other, server);
+    }
+}
+
+// Carries out a command from the control socket, returns whether the server
+// is to exit. A snapshot is answered once written, which may take a while.
+fn control(command: ControlCommand,
+           reply: Sender<Result<String, String>>,
+           sessions: &mut HashMap<ClientId, Session>,
+           config: &mut Config,
+           server: &Server,
+           capture: &Arc<Mutex<Capture>>,
+           monitor_info: &[MonitorInfo])
+    -> bool
+{
+    println!("Main: Control {:?}", command);
+
+    let mut clients: Vec<ClientId> = sessions.keys().cloned().collect();
+    clients.sort();
+
+    let result = match command {
+        ControlCommand::Clients => {
+            Ok(clients.iter().map(|client| {
+                let session = &sessions[client];
+
+                let state = if session.suspended.is_some() {
+                    "suspended"
+                } else {
+                    "accepted"
+                };
+
+                let view = match session.view {
+                    Some((screen, segment)) => format!("view {}/{}", screen, segment),
+                    None => "no view".to_string()
+                };
+
+                format!("{} {} {} {} {}\n", client, net::display_address(&session.src), session.role, state, view)
+            }).collect())
+        },
+        ControlCommand::Stats(Some(client)) if !sessions.contains_key(&client) => Err(format!("no client {}", client)),
+        ControlCommand::Stats(only) => {
+            Ok(clients.iter().filter(|&&client| only.is_none_or(|only| only == client)).map(|client| {
+                let session = &sessions[client];
+                let decision = session.rate_controller.decision();
+
+                format!("{} {}, quality {}, {:.1} fps\n",
+                        client,
+                        session.link_stats,
+                        decision.quality,
+                        1.0 / decision.frame_interval.as_secs_f64())
+            }).collect())
+        },
+        ControlCommand::Kick(client) => match sessions.remove(&client) {
+            Some(session) => {
+                println!("Main: Kick {}", client);
+                close_session(session, client, server);
+                Ok(String::new())
+            },
+            None => Err(format!("no client {}", client))
+        },
+        ControlCommand::Quality(quality) => {
+            config.quality = quality;
+            set_limits(sessions, config);
+            Ok(String::new())
+        },
+        ControlCommand::Fps(fps) => {
+            config.fps = fps;
+            set_limits(sessions, config);
+            Ok(String::new())
+        },
+        ControlCommand::Snapshot(path, screen) => match monitor_info.get(screen as usize) {
+            Some(monitor) => {
+                let area = (monitor.offset_x, monitor.offset_y, monitor.width, monitor.height);
+                let screenshot = capture.lock().unwrap().screenshot(None, area, area);
+
+                thread::spawn(move || {
+                    let result = screenshot.write_ppm(&path)
+                        .map(|_| String::new())
+                        .map_err(|e| format!("could not write {}: {}", path.display(), e));
+
+                    let _ = reply.send(result);
+                });
+
+                return false
+            },
+            None => Err(format!("no screen {}", screen))
+        },
+        ControlCommand::Shutdown => {
+            println!("Main: Shutdown");
+
+            for (client, session) in sessions.drain() {
+                close_session(session, client, server);
+            }
+
+            let _ = reply.send(Ok(String::new()));
+            return true
+        }
+    };
+
+    let _ = reply.send(result);
+
+    false
+}
+
+// New sessions start with the configured quality and fps, running ones move
+// to them.
+fn set_limits(sessions: &mut HashMap<ClientId, Session>, config: &Config)
+{
+    let frame_interval = Duration::from_nanos(1_000_000_000 / config.fps);
+
+    for session in sessions.values_mut() {
+        let decision = session.rate_controller.set_limits(config.quality, frame_interval);
+        session.frame_duration = decision.frame_interval;
+
+        session.send_to_context(ContextMessage::Rate(decision));
+    }
+}
+
+// Stops the threads of the session. Closing the context closes the encoder
+// and sender in turn, which close the rest.
+fn close_session(session: Session, client: ClientId, server: &Server)
+{
+    println!("Main: Close session {}", client);
+
+    let _ = session.context_sender.send(ContextMessage::Close);
+    let _ = session.heartbeat_sender.send(HeartbeatMessage::Close);
+
+    if let Server::Udp(ref server) = *server {
+        server.disconnect(client);
+    }
+
+    join_threads(session.handles);
+}
+
+// Drops a client that is not accepted. Its TCP connection is closed.
+fn close_pending(pending: PendingClient, client: ClientId, server: &Server)
+{
+    match pending.link {
+        Link::Udp(_) => {
+            if let Server::Udp(ref server) = *server {
+                server.disconnect(client);
+            }
+        },
+        Link::Tcp(connection) => connection.close()
+    }
+}
+
+// A thread that panicked took only its own session down.
 fn join_threads(handles: Vec<JoinHandle<()>>)
 {
     for handle in handles {
-        handle.join().unwrap();
+        if handle.join().is_err() {
+            println!("Main: A thread of the session panicked");
+        }
     }
 }
 
-fn get_offset(monitors: &Vec<MonitorInfo>, screen: u8, segment: u8)
-    -> (i32, i32)
+// The top left corner of a segment of a screen, none if the client named a
+// screen or segment that does not exist.
+fn get_offset(monitors: &[MonitorInfo], screen: u8, segment: u8)
+    -> Option<(i32, i32)>
 {
-    let ref current_monitor = monitors[screen as usize];
+    let current_monitor = monitors.get(screen as usize)?;
 
     let n_midpoints_x = current_monitor.midpoints_x.len();
-    let segment_x = (segment % n_midpoints_x as u8) as usize;
-    let segment_y = (segment / n_midpoints_x as u8) as usize;
+    let segment_x = segment as usize % n_midpoints_x;
+    let segment_y = segment as usize / n_midpoints_x;
+
+    let midpoint_y = *current_monitor.midpoints_y.get(segment_y)?;
 
     let offset_x = current_monitor.offset_x + (current_monitor.midpoints_x[segment_x] - current_monitor.view_width / 2) as i32;
-    let offset_y = current_monitor.offset_y + (current_monitor.midpoints_y[segment_y] - current_monitor.view_height / 2) as i32;
+    let offset_y = current_monitor.offset_y + (midpoint_y - current_monitor.view_height / 2) as i32;
 
-    (offset_x, offset_y)
-}
\ No newline at end of file
+    Some((offset_x, offset_y))
+}
@@ -0,0 +1,229 @@
+use std::str::FromStr;
+
+// SSIM stabilisation constants for 8 bit samples: (0.01 * 255)^2 and
+// (0.03 * 255)^2.
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+
+// 1 - SSIM is scaled by this factor to obtain an integer error.
+const SSIM_SCALE: f64 = 10000.0;
+
+/// The measure used to decide how much a macroblock differs from the state
+/// the client is known to have.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    /// Sum of squared RGB differences.
+    Sse,
+    /// Largest absolute difference of any channel of any pixel.
+    MaxAbsDiff,
+    /// Sum of squared RGB differences, weighted by each channel's
+    /// contribution to luma.
+    LumaWeighted,
+    /// 1 - SSIM of the luma channel, scaled by 10000.
+    Ssim,
+}
+
+impl Metric {
+    pub fn default_threshold(&self) -> i64
+    {
+        match *self {
+            Metric::Sse => 10000,
+            Metric::MaxAbsDiff => 32,
+            Metric::LumaWeighted => 3000,
+            Metric::Ssim => 50,
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s {
+            "sse" => Ok(Metric::Sse),
+            "max-abs" => Ok(Metric::MaxAbsDiff),
+            "luma" => Ok(Metric::LumaWeighted),
+            "ssim" => Ok(Metric::Ssim),
+            _ => Err(format!("unknown metric '{}', expected one of sse, max-abs, luma, ssim", s))
+        }
+    }
+}
+
+/// Accumulates the pixel differences of a single macroblock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockStats {
+    sse: i64,
+    weighted_sse: i64,
+    max_abs_diff: u8,
+
+    n: i64,
+    sum_old: i64,
+    sum_new: i64,
+    sum_old_sq: i64,
+    sum_new_sq: i64,
+    sum_cross: i64,
+}
+
+impl BlockStats {
+    pub fn reset(&mut self)
+    {
+        *self = BlockStats::default();
+    }
+
+    /// Add a single pixel, given as (r, g, b) as last sent to the client and
+    /// as currently on screen.
+    #[inline(always)]
+    pub fn add(&mut self, metric: Metric, old: (u8, u8, u8), new: (u8, u8, u8))
+    {
+        let d_r = new.0 as i64 - old.0 as i64;
+        let d_g = new.1 as i64 - old.1 as i64;
+        let d_b = new.2 as i64 - old.2 as i64;
+
+        let max_abs = d_r.abs().max(d_g.abs()).max(d_b.abs()) as u8;
+
+        if max_abs > self.max_abs_diff {
+            self.max_abs_diff = max_abs;
+        }
+
+        match metric {
+            Metric::Sse => {
+                self.sse += d_r * d_r + d_g * d_g + d_b * d_b;
+            },
+            Metric::MaxAbsDiff => (),
+            Metric::LumaWeighted => {
+                self.weighted_sse += 77 * d_r * d_r + 150 * d_g * d_g + 29 * d_b * d_b;
+            },
+            Metric::Ssim => {
+                let y_old = luma(old);
+                let y_new = luma(new);
+
+                self.n += 1;
+                self.sum_old += y_old;
+                self.sum_new += y_new;
+                self.sum_old_sq += y_old * y_old;
+                self.sum_new_sq += y_new * y_new;
+                self.sum_cross += y_old * y_new;
+            },
+        }
+    }
+
+    pub fn max_abs_diff(&self) -> u8
+    {
+        self.max_abs_diff
+    }
+
+    pub fn error(&self, metric: Metric) -> i64
+    {
+        match metric {
+            Metric::Sse => self.sse,
+            Metric::MaxAbsDiff => self.max_abs_diff as i64,
+            Metric::LumaWeighted => self.weighted_sse >> 8,
+            Metric::Ssim => {
+                if (self.n == 0) | (self.max_abs_diff == 0) {
+                    return 0
+                }
+
+                let n = self.n as f64;
+                let mean_old = self.sum_old as f64 / n;
+                let mean_new = self.sum_new as f64 / n;
+                let var_old = self.sum_old_sq as f64 / n - mean_old * mean_old;
+                let var_new = self.sum_new_sq as f64 / n - mean_new * mean_new;
+                let covariance = self.sum_cross as f64 / n - mean_old * mean_new;
+
+                let ssim = ((2.0 * mean_old * mean_new + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                    / ((mean_old * mean_old + mean_new * mean_new + SSIM_C1) * (var_old + var_new + SSIM_C2));
+
+                ((1.0 - ssim) * SSIM_SCALE).max(0.0) as i64
+            },
+        }
+    }
+}
+
+#[inline(always)]
+fn luma(pixel: (u8, u8, u8)) -> i64
+{
+    (77 * pixel.0 as i64 + 150 * pixel.1 as i64 + 29 * pixel.2 as i64) >> 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METRICS: [Metric; 4] = [Metric::Sse, Metric::MaxAbsDiff, Metric::LumaWeighted, Metric::Ssim];
+
+    // The stats of a block of 256 pixels, all changed from old to new.
+    fn block(metric: Metric, old: (u8, u8, u8), new: (u8, u8, u8)) -> BlockStats
+    {
+        let mut stats = BlockStats::default();
+
+        for _ in 0..256 {
+            stats.add(metric, old, new);
+        }
+
+        stats
+    }
+
+    #[test]
+    fn unchanged_blocks_have_no_error()
+    {
+        for &metric in &METRICS {
+            let stats = block(metric, (10, 20, 30), (10, 20, 30));
+
+            assert_eq!(stats.error(metric), 0);
+            assert_eq!(stats.max_abs_diff(), 0);
+        }
+    }
+
+    #[test]
+    fn errors()
+    {
+        let old = (10, 20, 30);
+        let new = (13, 16, 30);
+
+        assert_eq!(block(Metric::Sse, old, new).error(Metric::Sse), 256 * (9 + 16));
+        assert_eq!(block(Metric::MaxAbsDiff, old, new).error(Metric::MaxAbsDiff), 4);
+        assert_eq!(block(Metric::LumaWeighted, old, new).error(Metric::LumaWeighted),
+                   (256 * (77 * 9 + 150 * 16)) >> 8);
+
+        // Every metric tracks the largest difference.
+        for &metric in &METRICS {
+            assert_eq!(block(metric, old, new).max_abs_diff(), 4);
+        }
+
+        let mut stats = block(Metric::Sse, old, new);
+        stats.reset();
+        assert_eq!(stats.error(Metric::Sse), 0);
+    }
+
+    #[test]
+    fn ssim_follows_structure()
+    {
+        // A uniform change of brightness costs less than a new pattern of
+        // the same strength.
+        let mut brighter = BlockStats::default();
+        let mut pattern = BlockStats::default();
+
+        for i in 0..256 {
+            let old = if i % 2 == 0 { (100, 100, 100) } else { (140, 140, 140) };
+            let shifted = if i % 2 == 0 { (120, 120, 120) } else { (160, 160, 160) };
+            let flipped = if i % 2 == 0 { (140, 140, 140) } else { (100, 100, 100) };
+
+            brighter.add(Metric::Ssim, old, shifted);
+            pattern.add(Metric::Ssim, old, flipped);
+        }
+
+        assert!(brighter.error(Metric::Ssim) > 0);
+        assert!(pattern.error(Metric::Ssim) > brighter.error(Metric::Ssim));
+    }
+
+    #[test]
+    fn from_str()
+    {
+        assert_eq!("sse".parse(), Ok(Metric::Sse));
+        assert_eq!("max-abs".parse(), Ok(Metric::MaxAbsDiff));
+        assert_eq!("luma".parse(), Ok(Metric::LumaWeighted));
+        assert_eq!("ssim".parse(), Ok(Metric::Ssim));
+        assert!("psnr".parse::<Metric>().is_err());
+    }
+}
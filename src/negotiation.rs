@@ -0,0 +1,257 @@
+// Version 2 handshakes negotiate what a session uses. The client sends the
+// u32 set of capabilities it supports and parameters, each a u8 id and a u16
+// value, see codec. The server answers with the capabilities both support
+// and every parameter it settled on, in the same format and in order of id.
+// Parameters the server does not know are ignored.
+//
+// For the codec and the subsampling, the client sends the set of those it
+// decodes, bit n standing for value n, and the server answers with the one
+// it picked. The client's viewport is only advisory, views are of a fixed
+// size that the server answers with. A client without a common codec or
+// subsampling, or that cannot take the smallest datagrams, is rejected.
+//
+// Messages that version 1 does not know are only sent to clients that agreed
+// to the capability for them. Version 1 clients get none of them, apart from
+// the cached blocks of a cache they asked for.
+
+use super::codec::Negotiation;
+
+use super::config::{Config, Transport, MIN_DATAGRAM_SIZE};
+
+use super::fec::FecMode;
+
+use super::heartbeat;
+
+use super::monitor_info::MonitorInfo;
+
+use super::protocol::REJECT_UNSUPPORTED_PARAMS;
+
+pub const CAPABILITY_BLOCK_CACHE: u32           = 1 << 0;
+pub const CAPABILITY_FEC: u32                   = 1 << 1;
+pub const CAPABILITY_MTU_PROBE: u32             = 1 << 2;
+pub const CAPABILITY_SESSION_RESUME: u32        = 1 << 3;
+pub const CAPABILITY_RATE_INFO: u32             = 1 << 4;
+pub const CAPABILITY_LINK_STATS: u32            = 1 << 5;
+pub const CAPABILITY_FRAGMENTS: u32             = 1 << 6;
+pub const CAPABILITY_END_OF_FRAME: u32          = 1 << 7;
+pub const CAPABILITY_KEEPALIVE: u32             = 1 << 8;
+pub const CAPABILITY_CLOSE: u32                 = 1 << 9;
+pub const CAPABILITY_DENIED: u32                = 1 << 10;
+
+pub const PARAM_CODEC: u8                       = 0;
+pub const PARAM_SUBSAMPLING: u8                 = 1;
+pub const PARAM_MAX_DATAGRAM_SIZE: u8           = 2;
+pub const PARAM_VIEWPORT_WIDTH: u8              = 3;
+pub const PARAM_VIEWPORT_HEIGHT: u8             = 4;
+pub const PARAM_CACHE_SIZE: u8                  = 5; // Slots, with the block cache
+pub const PARAM_LIVENESS_TIMEOUT: u8            = 6; // Seconds
+
+pub const CODEC_JPEG: u16                       = 0;
+
+// Subsampling is 0 for 4:4:4, 1 for 4:2:2 and 2 for 4:2:0.
+pub const SUBSAMPLING_444: u16                  = 0;
+
+/// What the server settled on with a version 2 client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Agreement {
+    pub capabilities: u32,
+    pub codec: u16,
+    pub subsampling: u16,
+    pub max_datagram_size: u16,
+    pub viewport: (u16, u16), // Width and height
+    pub cache_size: u16,
+    pub liveness_timeout: u16,
+}
+
+impl Agreement {
+    pub fn has(&self, capability: u32) -> bool
+    {
+        self.capabilities & capability != 0
+    }
+
+    /// The wire format: the u32 capabilities, the u8 number of parameters
+    /// and the parameters.
+    pub fn serialize(&self, out: &mut Vec<u8>)
+    {
+        let params = [
+            (PARAM_CODEC, self.codec),
+            (PARAM_SUBSAMPLING, self.subsampling),
+            (PARAM_MAX_DATAGRAM_SIZE, self.max_datagram_size),
+            (PARAM_VIEWPORT_WIDTH, self.viewport.0),
+            (PARAM_VIEWPORT_HEIGHT, self.viewport.1),
+            (PARAM_CACHE_SIZE, self.cache_size),
+            (PARAM_LIVENESS_TIMEOUT, self.liveness_timeout),
+        ];
+
+        out.extend_from_slice(&self.capabilities.to_be_bytes());
+        out.push(params.len() as u8);
+
+        for &(id, value) in &params {
+            out.push(id);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+/// Capabilities the server is configured for.
+pub fn capabilities(config: &Config) -> u32
+{
+    let mut capabilities = CAPABILITY_RATE_INFO | CAPABILITY_LINK_STATS | CAPABILITY_END_OF_FRAME |
+                           CAPABILITY_KEEPALIVE | CAPABILITY_CLOSE | CAPABILITY_DENIED;
+
+    if config.cache_size > 0 {
+        capabilities |= CAPABILITY_BLOCK_CACHE;
+    }
+
+    if config.fec != FecMode::Off {
+        capabilities |= CAPABILITY_FEC;
+    }
+
+    if config.probe_mtu {
+        capabilities |= CAPABILITY_MTU_PROBE;
+    }
+
+    if (config.transport == Transport::Udp) & (config.resume_grace > 0) {
+        capabilities |= CAPABILITY_SESSION_RESUME;
+    }
+
+    if config.fragments() {
+        capabilities |= CAPABILITY_FRAGMENTS;
+    }
+
+    capabilities
+}
+
+/// Settles what a session uses, or the reason to reject the client.
+pub fn negotiate(config: &Config, monitor: &MonitorInfo, request: &Negotiation) -> Result<Agreement, u8>
+{
+    let mut capabilities = capabilities(config) & request.capabilities;
+
+    // Only JPEG with full chroma resolution is encoded.
+    let codec = pick(request.param(PARAM_CODEC), CODEC_JPEG)?;
+    let subsampling = pick(request.param(PARAM_SUBSAMPLING), SUBSAMPLING_444)?;
+
+    let max_datagram_size = match request.param(PARAM_MAX_DATAGRAM_SIZE) {
+        Some(size) => (size as usize).min(config.max_datagram_size),
+        None => config.max_datagram_size
+    };
+
+    if max_datagram_size < MIN_DATAGRAM_SIZE {
+        return Err(REJECT_UNSUPPORTED_PARAMS)
+    }
+
+    let cache_size = if capabilities & CAPABILITY_BLOCK_CACHE != 0 {
+        request.param(PARAM_CACHE_SIZE).unwrap_or(0).min(config.cache_size)
+    } else {
+        0
+    };
+
+    if cache_size == 0 {
+        capabilities &= !CAPABILITY_BLOCK_CACHE;
+    }
+
+    Ok(Agreement {
+        capabilities,
+        codec,
+        subsampling,
+        max_datagram_size: max_datagram_size as u16,
+        viewport: (monitor.view_width as u16, monitor.view_height as u16),
+        cache_size,
+        liveness_timeout: heartbeat::liveness_timeout(request.param(PARAM_LIVENESS_TIMEOUT),
+                                                      config.liveness_timeout),
+    })
+}
+
+// The value the server supports, if the client takes it. A client that does
+// not say takes it.
+fn pick(offered: Option<u16>, supported: u16) -> Result<u16, u8>
+{
+    match offered {
+        Some(values) if values & (1 << supported) == 0 => Err(REJECT_UNSUPPORTED_PARAMS),
+        _ => Ok(supported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> MonitorInfo
+    {
+        MonitorInfo::new("test".to_string(), 1920, 1080, 0, 0)
+    }
+
+    fn request(capabilities: u32, params: &[(u8, u16)]) -> Negotiation
+    {
+        Negotiation {
+            capabilities,
+            params: params.to_vec(),
+        }
+    }
+
+    #[test]
+    fn capabilities_both_support()
+    {
+        let config = Config::default();
+        let supported = capabilities(&config);
+
+        assert_eq!(supported & (CAPABILITY_FEC | CAPABILITY_MTU_PROBE), 0);
+
+        // A newer client offers capabilities the server does not know.
+        let agreement = negotiate(&config, &monitor(), &request(!0, &[(PARAM_CACHE_SIZE, 64)])).unwrap();
+        assert_eq!(agreement.capabilities, supported);
+
+        // An older client knows fewer, the server uses only those.
+        let offered = CAPABILITY_FEC | CAPABILITY_RATE_INFO | CAPABILITY_CLOSE;
+        let agreement = negotiate(&config, &monitor(), &request(offered, &[])).unwrap();
+        assert_eq!(agreement.capabilities, CAPABILITY_RATE_INFO | CAPABILITY_CLOSE);
+
+        // Over TCP messages are not split and sessions are not resumed.
+        let config = Config {
+            transport: Transport::Tcp,
+            ..Config::default()
+        };
+        let agreement = negotiate(&config, &monitor(), &request(!0, &[])).unwrap();
+        assert_eq!(agreement.capabilities & (CAPABILITY_FRAGMENTS | CAPABILITY_SESSION_RESUME), 0);
+    }
+
+    #[test]
+    fn block_cache()
+    {
+        let config = Config::default();
+
+        // Without a size the cache is not used.
+        let agreement = negotiate(&config, &monitor(), &request(CAPABILITY_BLOCK_CACHE, &[])).unwrap();
+        assert!(!agreement.has(CAPABILITY_BLOCK_CACHE));
+        assert_eq!(agreement.cache_size, 0);
+
+        let agreement = negotiate(&config, &monitor(), &request(CAPABILITY_BLOCK_CACHE, &[(PARAM_CACHE_SIZE, 60000)])).unwrap();
+        assert!(agreement.has(CAPABILITY_BLOCK_CACHE));
+        assert_eq!(agreement.cache_size, config.cache_size);
+    }
+
+    #[test]
+    fn params()
+    {
+        let config = Config::default();
+
+        let agreement = negotiate(&config, &monitor(), &request(0, &[(PARAM_MAX_DATAGRAM_SIZE, 600)])).unwrap();
+        assert_eq!(agreement.max_datagram_size, 600);
+        assert_eq!(agreement.codec, CODEC_JPEG);
+        assert_eq!(agreement.viewport, (640, 368));
+
+        let agreement = negotiate(&config, &monitor(), &request(0, &[(PARAM_MAX_DATAGRAM_SIZE, 60000)])).unwrap();
+        assert_eq!(agreement.max_datagram_size as usize, config.max_datagram_size);
+
+        // Clients that cannot take what the server sends.
+        let rejected = [
+            (PARAM_MAX_DATAGRAM_SIZE, MIN_DATAGRAM_SIZE as u16 - 1),
+            (PARAM_CODEC, 1 << 1),
+            (PARAM_SUBSAMPLING, 1 << 2),
+        ];
+
+        for &param in &rejected {
+            assert_eq!(negotiate(&config, &monitor(), &request(0, &[param])), Err(REJECT_UNSUPPORTED_PARAMS));
+        }
+    }
+}
@@ -0,0 +1,247 @@
+extern crate libc;
+
+use std::io::{
+    Error,
+    Result
+};
+
+use std::mem;
+
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+    SocketAddr,
+    SocketAddrV6,
+    TcpListener,
+    UdpSocket
+};
+
+use std::os::unix::io::{
+    AsRawFd,
+    FromRawFd,
+    OwnedFd
+};
+
+use std::str::FromStr;
+
+const LISTEN_BACKLOG: libc::c_int = 16;
+
+/// The address the sockets are bound to. An IPv6 address may carry the
+/// scope id of an interface, as in fe80::1%2, which link-local addresses
+/// need.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BindAddress {
+    pub ip: IpAddr,
+    pub scope_id: u32,
+}
+
+impl BindAddress {
+    /// The unspecified IPv6 address, which accepts IPv4 clients as well
+    /// unless the socket is IPv6 only.
+    pub fn any() -> Self
+    {
+        BindAddress {
+            ip: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            scope_id: 0,
+        }
+    }
+
+    pub fn with_port(&self, port: u16) -> SocketAddr
+    {
+        match self.ip {
+            IpAddr::V4(ip) => SocketAddr::new(IpAddr::V4(ip), port),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, self.scope_id))
+        }
+    }
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, String>
+    {
+        let (ip, scope_id) = match s.find('%') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None)
+        };
+
+        let ip = ip.parse::<IpAddr>().map_err(|_| format!("invalid address '{}'", ip))?;
+
+        let scope_id = match (ip, scope_id) {
+            (_, None) => 0,
+            (IpAddr::V6(_), Some(id)) => id.parse().map_err(|_| format!("invalid scope id '{}'", id))?,
+            (IpAddr::V4(_), Some(_)) => return Err("IPv4 addresses have no scope id".to_string())
+        };
+
+        Ok(BindAddress {
+            ip,
+            scope_id,
+        })
+    }
+}
+
+/// Binds a UDP socket. IPv6 sockets also receive IPv4 traffic, as mapped
+/// addresses, unless v6_only is set. When IPv6 is not available on the
+/// host the unspecified IPv6 address falls back to the unspecified IPv4
+/// address.
+pub fn bind_udp(address: SocketAddr, v6_only: bool) -> Result<UdpSocket>
+{
+    let v6 = match address {
+        SocketAddr::V4(_) => return UdpSocket::bind(address),
+        SocketAddr::V6(v6) => v6
+    };
+
+    match bind_v6(&v6, v6_only, libc::SOCK_DGRAM) {
+        Ok(fd) => Ok(UdpSocket::from(fd)),
+        Err(e) => match ipv4_fallback(&v6, &e) {
+            Some(address) => UdpSocket::bind(address),
+            None => Err(e)
+        }
+    }
+}
+
+/// Binds a listening TCP socket, in the same way as bind_udp.
+pub fn bind_tcp(address: SocketAddr, v6_only: bool) -> Result<TcpListener>
+{
+    let v6 = match address {
+        SocketAddr::V4(_) => return TcpListener::bind(address),
+        SocketAddr::V6(v6) => v6
+    };
+
+    let fd = match bind_v6(&v6, v6_only, libc::SOCK_STREAM) {
+        Ok(fd) => fd,
+        Err(e) => return match ipv4_fallback(&v6, &e) {
+            Some(address) => TcpListener::bind(address),
+            None => Err(e)
+        }
+    };
+
+    if unsafe { libc::listen(fd.as_raw_fd(), LISTEN_BACKLOG) } < 0 {
+        return Err(Error::last_os_error())
+    }
+
+    Ok(TcpListener::from(fd))
+}
+
+/// Sets the don't fragment bit on datagrams sent through the socket,
+/// regardless of the path MTU the kernel assumes, so that probes of the
+/// path MTU are not fragmented.
+pub fn set_dont_fragment(socket: &UdpSocket) -> Result<()>
+{
+    let ipv4 = set_int_option(socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE);
+    let ipv6 = set_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE);
+
+    // A dual-stack socket takes both, others only one of them.
+    ipv4.or(ipv6)
+}
+
+/// Whether a send failed because the datagram exceeds the MTU of the
+/// interface, which only happens with the don't fragment bit set.
+pub fn is_too_large(error: &Error) -> bool
+{
+    error.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+fn set_int_option(socket: &UdpSocket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> Result<()>
+{
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(),
+                         level,
+                         name,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Creates an IPv6 socket of the given type and binds it.
+fn bind_v6(address: &SocketAddrV6, v6_only: bool, socket_type: libc::c_int) -> Result<OwnedFd>
+{
+    let fd = unsafe { libc::socket(libc::AF_INET6, socket_type | libc::SOCK_CLOEXEC, 0) };
+
+    if fd < 0 {
+        return Err(Error::last_os_error())
+    }
+
+    // Owning the descriptor closes it on the error paths below.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let only: libc::c_int = v6_only as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(fd.as_raw_fd(),
+                         libc::IPPROTO_IPV6,
+                         libc::IPV6_V6ONLY,
+                         &only as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+
+    if result < 0 {
+        return Err(Error::last_os_error())
+    }
+
+    // A restarted server must not wait for connections of the previous
+    // session to time out.
+    if socket_type == libc::SOCK_STREAM {
+        let reuse: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(fd.as_raw_fd(),
+                             libc::SOL_SOCKET,
+                             libc::SO_REUSEADDR,
+                             &reuse as *const libc::c_int as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+
+        if result < 0 {
+            return Err(Error::last_os_error())
+        }
+    }
+
+    let mut raw: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    raw.sin6_port = address.port().to_be();
+    raw.sin6_flowinfo = address.flowinfo();
+    raw.sin6_addr.s6_addr = address.ip().octets();
+    raw.sin6_scope_id = address.scope_id();
+
+    let result = unsafe {
+        libc::bind(fd.as_raw_fd(),
+                   &raw as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                   mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t)
+    };
+
+    if result < 0 {
+        return Err(Error::last_os_error())
+    }
+
+    Ok(fd)
+}
+
+// The IPv4 address to bind to instead when IPv6 is unavailable.
+fn ipv4_fallback(address: &SocketAddrV6, error: &Error) -> Option<SocketAddr>
+{
+    if (error.raw_os_error() == Some(libc::EAFNOSUPPORT)) & address.ip().is_unspecified() {
+        println!("Net: IPv6 unavailable, binding to IPv4");
+        Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), address.port()))
+    } else {
+        None
+    }
+}
+
+/// IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6
+/// addresses, print them as plain IPv4.
+pub fn display_address(address: &SocketAddr) -> String
+{
+    match *address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()).to_string(),
+            None => address.to_string()
+        },
+        SocketAddr::V4(_) => address.to_string()
+    }
+}
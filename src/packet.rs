@@ -0,0 +1,84 @@
+use std::sync::mpsc::Receiver;
+
+/// Largest datagram sent unless configured otherwise.
+pub const DEFAULT_PACKET_SIZE: usize = 1000;
+
+// Opcode, timestamp and packet id.
+pub const HEADER_SIZE: usize = 9;
+
+// Block id, fragment index and fragment count in front of a fragment.
+pub const FRAGMENT_HEADER_SIZE: usize = 4;
+
+// Number of blocks a packet is expected to hold at most.
+const BLOCKS_CAPACITY: usize = 100;
+
+/// A packet of image data or cached block references, together with the
+/// ids of the blocks it contains. The timestamp and packet id in the header
+/// are filled in by the sender.
+#[derive(Debug)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    pub blocks: Vec<u16>,
+}
+
+impl Packet {
+    fn new() -> Self
+    {
+        // Leave room for a macroblock that overflows the packet, it is moved
+        // to the next packet once it is complete.
+        Packet {
+            data: Vec::with_capacity(2 * DEFAULT_PACKET_SIZE),
+            blocks: Vec::with_capacity(BLOCKS_CAPACITY),
+        }
+    }
+
+    fn reset(&mut self, opcode: u8)
+    {
+        self.data.clear();
+        self.blocks.clear();
+
+        self.data.push(opcode);
+        self.data.extend_from_slice(&[0u8; HEADER_SIZE - 1]);
+    }
+
+    pub fn has_data(&self) -> bool
+    {
+        self.data.len() > HEADER_SIZE
+    }
+
+    pub fn set_header(&mut self, timestamp: u32, id: u32)
+    {
+        for i in 0..4 {
+            self.data[1 + i] = (timestamp >> (24 - i * 8)) as u8;
+            self.data[5 + i] = (id >> (24 - i * 8)) as u8;
+        }
+    }
+}
+
+/// Packets are handed back by the sender once they are sent, so after the
+/// first few frames no new buffers are allocated.
+#[derive(Debug)]
+pub struct PacketPool {
+    returned: Receiver<Packet>,
+}
+
+impl PacketPool {
+    pub fn new(returned: Receiver<Packet>) -> Self
+    {
+        PacketPool {
+            returned,
+        }
+    }
+
+    /// An empty packet with the given opcode.
+    pub fn get(&self, opcode: u8) -> Packet
+    {
+        let mut packet = match self.returned.try_recv() {
+            Ok(packet) => packet,
+            Err(_) => Packet::new()
+        };
+
+        packet.reset(opcode);
+        packet
+    }
+}
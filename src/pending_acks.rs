@@ -1,8 +1,14 @@
-use std::collections::HashMap;
+use std::cmp;
+
+use std::collections::{
+    HashMap,
+    VecDeque
+};
 
 use std::sync::mpsc::{
     Sender,
-    Receiver
+    Receiver,
+    RecvTimeoutError
 };
 
 use std::thread::{
@@ -10,36 +16,198 @@ use std::thread::{
     JoinHandle
 };
 
-use super::protocol::{ContextMessage, PendingAckMessage};
+use std::time::{
+    Duration,
+    Instant
+};
+
+use super::link_stats::LinkMonitor;
+
+use super::protocol::{ContextMessage, MainMessage, MainSender, PendingAckMessage, SenderMessage};
+
+// How often expired packets are looked for when no messages arrive.
+const TICK_MS: u64 = 20;
+
+// How often link statistics are reported to main.
+const STATS_INTERVAL_MS: u64 = 1000;
+
+// Retransmission timeout bounds and initial value, as in RFC 6298 but with
+// a lower minimum since the client acknowledges every packet immediately.
+const INITIAL_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 100;
+const MAX_RTO_MS: u64 = 5000;
+
+/// Smoothed round trip time and retransmission timeout, following RFC 6298.
+#[derive(Debug)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self
+    {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            rto: Duration::from_millis(INITIAL_RTO_MS),
+        }
+    }
+
+    pub fn rto(&self) -> Duration
+    {
+        self.rto
+    }
+
+    /// The smoothed round trip time, or the initial timeout before the
+    /// first sample.
+    pub fn srtt(&self) -> Duration
+    {
+        self.srtt.unwrap_or(self.rto)
+    }
+
+    pub fn rttvar(&self) -> Duration
+    {
+        self.rttvar
+    }
+
+    pub fn sample(&mut self, rtt: Duration)
+    {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            },
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(rtt);
+
+                self.rttvar = (self.rttvar * 3 + deviation) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+
+        self.rto = clamp_rto(self.srtt.unwrap() + self.rttvar * 4);
+    }
+
+    /// Back off after a timeout.
+    pub fn backoff(&mut self)
+    {
+        self.rto = clamp_rto(self.rto * 2);
+    }
+}
+
+fn clamp_rto(rto: Duration) -> Duration
+{
+    cmp::min(cmp::max(rto, Duration::from_millis(MIN_RTO_MS)),
+             Duration::from_millis(MAX_RTO_MS))
+}
 
 pub fn start_pending_ack_thread(to_context: Sender<ContextMessage>,
+                                to_main: MainSender,
+                                to_sender: Sender<SenderMessage>,
                                 receiver: Receiver<PendingAckMessage>)
     -> JoinHandle<()>
 {
     thread::spawn(move || {
         let mut packet_map = HashMap::new();
 
+        // Packet ids in the order they were sent, to find expired packets
+        // without scanning the map. Acknowledged ids are skipped lazily.
+        let mut send_order = VecDeque::new();
+        let mut rtt = RttEstimator::new();
+        let mut monitor = LinkMonitor::new();
+        let mut next_stats = Instant::now() + Duration::from_millis(STATS_INTERVAL_MS);
+
         loop {
-            match receiver.recv() {
-                Ok(PendingAckMessage::NewSend(timestamp, packet_id, ref present_ids)) => {
-                    packet_map.insert(packet_id, (timestamp, present_ids.clone()));
+            match receiver.recv_timeout(Duration::from_millis(TICK_MS)) {
+                Ok(PendingAckMessage::NewSend(timestamp, packet_id, ref present_ids, size)) => {
+                    let now = Instant::now();
+
+                    packet_map.insert(packet_id, (timestamp, present_ids.clone(), size, now));
+                    send_order.push_back((now, packet_id));
                 },
                 Ok(PendingAckMessage::NewReceive(packet_ids)) => {
+                    let now = Instant::now();
+
                     for packet_id in &packet_ids {
                         match packet_map.remove(packet_id) {
-                            Some((timestamp, ref ids)) => {;
+                            Some((timestamp, ref ids, size, sent_at)) => {
+                                let sample = now.duration_since(sent_at);
+                                rtt.sample(sample);
+                                monitor.delivered(size, sample, now);
+
                                 to_context.send(ContextMessage::AckPackets(timestamp, ids.clone())).unwrap();
+                                to_main.send(MainMessage::Delivered(size)).unwrap();
+
+                                // The sender closes before this thread does.
+                                let _ = to_sender.send(SenderMessage::Acked(*packet_id, size, sample));
                             },
                             None => ()
                         }
                     }
                 },
+                // Packets sent to the old address are lost, but not to the
+                // new path, which starts with fresh estimates.
+                Ok(PendingAckMessage::Resume) => {
+                    println!("PendingAcks: Resume, {} packets lost", packet_map.len());
+
+                    for (_, packet_id) in send_order.drain(..) {
+                        if let Some((timestamp, ids, size, _)) = packet_map.remove(&packet_id) {
+                            if to_context.send(ContextMessage::LostPackets(timestamp, ids)).is_err() {
+                                return
+                            }
+
+                            let _ = to_sender.send(SenderMessage::Lost(packet_id, size));
+                        }
+                    }
+
+                    rtt = RttEstimator::new();
+                },
                 Ok(PendingAckMessage::Close) => {
                     println!("PendingAcks: Close");
                     return;
                 },
-                _ => panic!()
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return
             };
+
+            // Packets that were not acknowledged within the retransmission
+            // timeout are considered lost. Their blocks are marked dirty so
+            // they are encoded again from the current frame.
+            let now = Instant::now();
+            let mut expired = false;
+
+            while let Some(&(sent_at, packet_id)) = send_order.front() {
+                if now.duration_since(sent_at) < rtt.rto() {
+                    break
+                }
+
+                send_order.pop_front();
+
+                if let Some((timestamp, ids, size, _)) = packet_map.remove(&packet_id) {
+                    // The context closes before this thread does.
+                    if to_context.send(ContextMessage::LostPackets(timestamp, ids)).is_err() {
+                        return
+                    }
+
+                    let _ = to_sender.send(SenderMessage::Lost(packet_id, size));
+                    monitor.lost(size, now);
+
+                    expired = true;
+                }
+            }
+
+            if expired {
+                rtt.backoff();
+            }
+
+            if now >= next_stats {
+                next_stats = now + Duration::from_millis(STATS_INTERVAL_MS);
+
+                let stats = monitor.stats(rtt.srtt(), rtt.rttvar(), now);
+                to_main.send(MainMessage::LinkStats(stats)).unwrap();
+            }
         };
     })
-}
\ No newline at end of file
+}
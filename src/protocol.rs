@@ -1,38 +1,156 @@
 use std::net::SocketAddr;
 
+use std::sync::mpsc::{SendError, Sender};
+
+use std::time::Duration;
+
+use super::auth::SESSION_TOKEN_SIZE;
+
+use super::codec::Handshake;
+
+use super::control::ControlCommand;
+
+use super::link_stats::LinkStats;
+
+use super::negotiation::Agreement;
+
+use super::packet::Packet;
+
+use super::rate_control::RateDecision;
+
+use super::tcp::TcpClient;
+
+use super::udp::KeyExchange;
+
 use super::util::DataBox;
 
-pub const OPCODE_RECEIVE_HANDSHAKE: u8              = 0;
-pub const OPCODE_RECEIVE_REQUEST_SCREEN_INFO: u8    = 1;
-pub const OPCODE_RECEIVE_REQUEST_VIEW: u8           = 2;
-pub const OPCODE_RECEIVE_REFRESH: u8                = 3;
-pub const OPCODE_RECEIVE_CLOSE: u8                  = 4;
-pub const OPCODE_RECEIVE_EXIT: u8                   = 5;
-pub const OPCODE_RECEIVE_LEFT_CLICK: u8             = 6;
-pub const OPCODE_RECEIVE_RIGHT_CLICK: u8            = 7;
-pub const OPCODE_RECEIVE_DOUBLE_CLICK: u8           = 8;
-pub const OPCODE_RECEIVE_DRAG: u8                   = 9;
-pub const OPCODE_RECEIVE_KEYBOARD: u8               = 10;
-pub const OPCODE_RECEIVE_ACK: u8                    = 11;
-pub const OPCODE_RECEIVE_HEARTBEAT: u8              = 12;
+// Messages from the client and their opcodes are in codec.
 
 pub const OPCODE_SEND_HANDSHAKE_ACK: u8          = 0;
 pub const OPCODE_SEND_SCREEN_INFO: u8            = 1;
 pub const OPCODE_SEND_IMAGE_DATA: u8             = 2;
+pub const OPCODE_SEND_CLOSE: u8                  = 3;
+pub const OPCODE_SEND_RATE_INFO: u8              = 4;
+pub const OPCODE_SEND_CACHED_BLOCKS: u8          = 5;
+pub const OPCODE_SEND_AUTH_CHALLENGE: u8         = 6;
+pub const OPCODE_SEND_HANDSHAKE_REJECT: u8       = 7;
+pub const OPCODE_SEND_LINK_STATS: u8             = 8; // See LinkStats::serialize
+pub const OPCODE_SEND_IMAGE_FRAGMENT: u8         = 9;
+pub const OPCODE_SEND_MTU_PROBE: u8              = 10;
+pub const OPCODE_SEND_PARITY: u8                 = 11; // See fec::ParityGroup
+pub const OPCODE_SEND_END_OF_FRAME: u8           = 12;
+pub const OPCODE_SEND_DENIED: u8                 = 13; // Opcode the client's role does not allow
+pub const OPCODE_SEND_SESSION_TOKEN: u8          = 14;
+pub const OPCODE_SEND_RESUME_ACK: u8             = 15;
+pub const OPCODE_SEND_KEEPALIVE: u8              = 16;
+
+// Over UDP, a macroblock too large for a datagram of its own is split into
+// image fragments. After the usual header each holds the u16 block id, the
+// u8 index of the fragment and the u8 number of fragments, then its part of
+// the encoded block as it would appear in image data, slot included. The
+// client decodes the block once it has all fragments of a timestamp.
+//
+// With --probe-mtu, the server sends MTU probes of decreasing size after the
+// handshake: the u16 packet size the probe allows, then padding up to the
+// size of the datagram. The client answers each with an ack holding the same
+// u16, the server then sends packets up to the largest size acknowledged.
+//
+// Every frame that sent anything ends with an end of frame record. After the
+// usual header, with the frame's timestamp, it holds the u16 number of image
+// data, cached blocks and image fragment packets of the frame. A client that
+// has them all presents the frame at once, one that misses some knows the
+// frame is incomplete. Clients acknowledge the record like image data, over
+// UDP the server sends it again if it is lost.
+//
+// With --fec, a group of packets of one timestamp is followed by a parity
+// packet. After the usual header it holds the u32 id of the first packet of
+// the group, the u8 number of packets and the u16 XOR of their lengths, then
+// the XOR of the packets, header included and each padded with zeros to the
+// longest. A client missing a single packet of the group rebuilds it from
+// the others and the parity, and acknowledges it as if it had arrived.
+// Parity packets are acknowledged as well.
+
+// With authentication, the server answers a handshake with a challenge: the
+// protocol version and 32 random bytes. The client replies with the length
+// of its user name as a u8, the user name and the 32 byte HMAC, see auth.
+// A rejected handshake gets one of these codes.
+//
+// The role of a client is decided when it is accepted. Input from view-only
+// clients is dropped. An exit from a client that is not an admin is answered
+// with a denied message holding the exit opcode, and the session goes on.
+pub const REJECT_BUSY: u8                        = 0;
+pub const REJECT_UNSUPPORTED_VERSION: u8         = 1;
+pub const REJECT_AUTH_FAILED: u8                 = 2;
+pub const REJECT_TOO_MANY_ATTEMPTS: u8           = 3;
+pub const REJECT_UNKNOWN_SESSION: u8             = 4;
+pub const REJECT_UNSUPPORTED_PARAMS: u8          = 5; // See negotiation
+
+// Over UDP with --resume-grace, an accepted client gets a session token: the
+// opcode followed by 32 random bytes. A client whose address changed sends a
+// resume holding the token from its new address, with --secure after a new
+// key exchange. The server moves the session there and answers with a resume
+// ack, packets that were not acknowledged count as lost so only the blocks
+// the client missed are sent again. A client that stopped sending heartbeats
+// keeps its session for the grace period. An unknown token is rejected, the
+// client then starts over with a handshake.
+
+// With --secure, datagrams in both directions are wrapped. A key exchange
+// message is the opcode followed by a Noise handshake message, the client's
+// carries the plain handshake as its payload. Every other datagram is the
+// opcode, a u64 counter and the sealed message, see crypto.
+pub const OPCODE_SECURE_HANDSHAKE: u8            = 0x80;
+pub const OPCODE_SECURE_DATA: u8                 = 0x81;
+
+// A client can ask for a liveness timeout by appending the u16 number of
+// seconds to a handshake with a cache size, 0 slots if it wants no cache. The
+// handshake ack then carries the timeout granted after the cache size, see
+// heartbeat. Without it the server's default applies. A session whose client
+// sends no heartbeat within the timeout, counted from the handshake, is
+// closed or waits to be resumed. Once accepted the server sends keepalives,
+// three per timeout: the u64 server time in milliseconds since the Unix
+// epoch and a u32 sequence number starting at 1.
+
+// Version 2 replaces the cache size and liveness timeout of the handshake
+// with capabilities and parameters, see codec and negotiation. The handshake
+// ack holds the protocol version followed by what the server agreed to in
+// the same format. A handshake without them is answered in version 1 as
+// before, whatever versions it names.
+
+// A client can ask for a macroblock cache by appending the number of slots it
+// wants as a u16 to the handshake. The handshake ack then carries the number
+// of slots granted. With the cache enabled, every macroblock in image data is
+// preceded by the u16 slot to store it in, or 0xFFFF if it is not cached.
+// Cached blocks packets hold (u16 block id, u16 slot) pairs of blocks that are
+// to be copied from the cache.
 
 #[derive(Debug)]
 pub enum ContextMessage {
     RequestView(u8, u8),
     Close,
     Refresh,
-    NewScreenshot,
-    AckPackets(u32, Vec<u16>)
+    NewScreenshot(u64), // Frame number, see capture
+    AckPackets(u32, Vec<u16>),
+    LostPackets(u32, Vec<u16>),
+    Rate(RateDecision),
+    CacheSize(u16),
+    Capabilities(u32), // Agreed in the handshake, see negotiation
+    LinkStats(LinkStats),
+    MaxPacketSize(usize),
+    Backlog(bool), // Whether the sender has more queued than the path takes, see udp
+    Encoded(u32, Vec<usize>), // From the encoder, timestamp and blocks that were sent
 }
 
 #[derive(Debug)]
 pub enum EncoderMessage {
     FirstImage(DataBox),
-    DataAndErrors(DataBox, Vec<(i64, usize)>),
+    // Image, blocks to encode, their cache slots if the cache is enabled and
+    // blocks to send as references to the cache
+    DataAndErrors(DataBox, Vec<(i64, usize)>, Vec<u16>, Vec<(usize, u16)>),
+    Rate(RateDecision),
+    CacheSize(u16),
+    Capabilities(u32), // Agreed in the handshake, see negotiation
+    LinkStats(LinkStats),
+    MaxPacketSize(usize),
     Close
 }
 
@@ -40,44 +158,116 @@ pub enum EncoderMessage {
 #[derive(Debug)]
 pub enum HeartbeatMessage {
     Heartbeat,
+    Liveness(Duration, bool), // Timeout negotiated in the handshake, whether keepalives are sent
     Close,
 }
 
+/// Identifies the session of a client. Sessions are numbered by the
+/// transport as clients show up.
+pub type ClientId = u32;
+
 #[derive(Debug)]
 pub enum MainMessage {
-    Handshake(SocketAddr, u8, u8),
+    // Source, the handshake and, over UDP with --secure, the key exchange it
+    // came with
+    Handshake(SocketAddr, Handshake, Option<KeyExchange>),
+    Resume(SocketAddr, [u8; SESSION_TOKEN_SIZE], Option<KeyExchange>), // Source, session token and key exchange
+    Connected(SocketAddr, TcpClient), // A new TCP connection
+    AuthResponse(SocketAddr, String, Vec<u8>), // Source, user name and HMAC
     RequestScreenInfo,
     RequestView(u8, u8),
     Refresh,
     Close,
     Exit,
+    HeartbeatLost,
+    HeartbeatResumed,
 
     LeftClick(u16, u16),
     RightClick(u16, u16),
     DoubleClick(u16, u16),
     Drag(u16, u16, u8, u8, u16, u16, u8, u8),
 
-    Keyboard(Vec<u8>),
+    Keyboard(String),
+
+    FrameEncoded(usize), // Bytes of image data in a frame
+    Delivered(usize), // Bytes acknowledged by the client
+    LinkStats(LinkStats),
+    RequestStats,
+    MtuProbeAck(u16), // Packet size the probe allows
+    MtuExceeded, // A datagram was too large for the interface
+
+    // A command from the control socket and where its output goes
+    Control(ControlCommand, Sender<Result<String, String>>),
+}
+
+/// Sends messages to main on behalf of the session of one client.
+#[derive(Debug, Clone)]
+pub struct MainSender {
+    client: ClientId,
+    sender: Sender<(ClientId, MainMessage)>,
+}
+
+impl MainSender {
+    pub fn new(client: ClientId, sender: Sender<(ClientId, MainMessage)>) -> Self
+    {
+        MainSender {
+            client,
+            sender,
+        }
+    }
+
+    pub fn client(&self) -> ClientId
+    {
+        self.client
+    }
+
+    /// Fails when main is gone. The message is dropped then, it is too
+    /// large to hand back in the error.
+    pub fn send(&self, msg: MainMessage) -> Result<(), SendError<()>>
+    {
+        self.sender.send((self.client, msg)).map_err(|_| SendError(()))
+    }
 }
 
 #[derive(Debug)]
 pub enum PendingAckMessage {
-    NewSend(u32, u32, Vec<u16>),
+    NewSend(u32, u32, Vec<u16>, usize), // Timestamp, packet id, blocks and packet size
     NewReceive(Vec<u32>),
+    Resume, // The client moved, packets in flight are lost
     Close
 }
 
 #[derive(Debug)]
 pub enum ReceiverMessage {
-    HeartbeatTimeout
+    HeartbeatTimeout,
+    // The TCP client was accepted, its messages go to the session, see tcp
+    Accept(Sender<SenderMessage>, Sender<HeartbeatMessage>),
+    // Datagrams from the address go to the session of the client, see udp
+    Connect(ClientId, SocketAddr, Sender<HeartbeatMessage>, Sender<PendingAckMessage>),
+    Disconnect(ClientId),
+    // The address of the first client now belongs to the session of the
+    // second, see udp
+    Resume(ClientId, ClientId),
+    Close
 }
 
 #[derive(Debug)]
 pub enum SenderMessage {
-    AcceptHandshake(SocketAddr, u8), // Address to send to and protocol version
-    RejectHandshake(SocketAddr),
+    // Protocol version, cache size and liveness timeout, or in version 2 the
+    // agreement
+    AcceptHandshake(u8, Option<u16>, Option<u16>, Option<Agreement>),
+    SessionToken([u8; SESSION_TOKEN_SIZE]),
+    Keepalive(u64, u32), // Server time in milliseconds and sequence number
+    Resume(SocketAddr, Option<KeyExchange>), // New address and key exchange
     ScreenInfo(Vec<u8>),
-    EndOfData(u32),
-    Macroblock(u32, Vec<u8>),
+    Packet(u32, Packet), // Timestamp and image data or cached blocks
+    RateInfo(u32, RateDecision), // First timestamp the decision applies to
+    Acked(u32, usize, Duration), // Packet id, size and round trip time
+    Lost(u32, usize), // Packet id and size
+    LinkStats(LinkStats),
+    FecGroupSize(usize), // Packets per parity packet, 0 for none
+    Denied(u8), // Opcode of the message that was not allowed
+    Pong(Vec<u8>), // Payload of a WebSocket ping
+    CloseFrame(Vec<u8>), // Payload of a WebSocket close, the connection is shut after the answer
     Close
 }
\ No newline at end of file
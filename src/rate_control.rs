@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+
+use super::link_stats::LinkStats;
+
+use std::time::{
+    Duration,
+    Instant
+};
+
+// Decisions are revised at most once per control interval.
+const CONTROL_INTERVAL_MS: u64 = 1000;
+
+// Length of the window over which sent and delivered bitrates are measured.
+const WINDOW_MS: u64 = 2000;
+
+// Sending more than this fraction of the target counts as overshoot,
+// sending less than the undershoot fraction leaves room to improve.
+const OVERSHOOT: f64 = 1.05;
+const UNDERSHOOT: f64 = 0.8;
+
+// Without a configured target, probe this much above the delivered bitrate.
+const PROBE_FACTOR: f64 = 1.25;
+const MIN_ESTIMATED_BITRATE: u64 = 256_000;
+
+// Losing more than this fraction of packets counts as overshoot, whatever
+// the bitrate.
+const MAX_LOSS_RATE: f64 = 0.05;
+
+const MIN_QUALITY: u8 = 10;
+const QUALITY_STEP: u8 = 5;
+
+const MAX_THRESHOLD_FACTOR: i64 = 8;
+const MAX_FRAME_INTERVAL_FACTOR: u32 = 5;
+
+/// The current choices of the rate controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateDecision {
+    pub target_bitrate: u64,
+    pub quality: u8,
+    pub threshold: i64,
+    pub frame_interval: Duration,
+}
+
+/// Adjusts the change threshold, quantization quality and frame interval so
+/// the bitrate of the image data follows a target. The target is either
+/// fixed, or estimated from the bitrate acknowledged by the client.
+///
+/// When over target, the threshold is raised first, then the quality is
+/// lowered and finally the frame interval is lengthened. When there is room
+/// to spare these steps are undone in reverse order.
+#[derive(Debug)]
+pub struct RateController {
+    fixed_target: Option<u64>,
+    max_quality: u8,
+    min_threshold: i64,
+    min_frame_interval: Duration,
+
+    decision: RateDecision,
+
+    sent: VecDeque<(Instant, usize)>,
+    delivered: VecDeque<(Instant, usize)>,
+    loss_rate: f64,
+    last_update: Instant,
+}
+
+impl RateController {
+    /// A target bitrate of 0 means the target is estimated from the
+    /// delivered bitrate.
+    pub fn new(target_bitrate: u64,
+               quality: u8,
+               threshold: i64,
+               frame_interval: Duration) -> Self
+    {
+        let fixed_target = if target_bitrate > 0 {
+            Some(target_bitrate)
+        } else {
+            None
+        };
+
+        RateController {
+            fixed_target,
+            max_quality: quality,
+            min_threshold: threshold,
+            min_frame_interval: frame_interval,
+            decision: RateDecision {
+                target_bitrate: fixed_target.unwrap_or(MIN_ESTIMATED_BITRATE),
+                quality,
+                threshold,
+                frame_interval,
+            },
+            sent: VecDeque::new(),
+            delivered: VecDeque::new(),
+            loss_rate: 0.0,
+            last_update: Instant::now(),
+        }
+    }
+
+    pub fn decision(&self) -> RateDecision
+    {
+        self.decision
+    }
+
+    /// Change the highest quality and the shortest frame interval. The
+    /// decisions move to them at once and are revised from there.
+    pub fn set_limits(&mut self, quality: u8, frame_interval: Duration) -> RateDecision
+    {
+        self.max_quality = quality;
+        self.min_frame_interval = frame_interval;
+
+        self.decision.quality = quality;
+        self.decision.frame_interval = frame_interval;
+
+        self.decision
+    }
+
+    /// Record the size of an encoded frame.
+    pub fn frame_sent(&mut self, bytes: usize)
+    {
+        self.sent.push_back((Instant::now(), bytes));
+    }
+
+    /// Record bytes acknowledged by the client.
+    pub fn bytes_delivered(&mut self, bytes: usize)
+    {
+        self.delivered.push_back((Instant::now(), bytes));
+    }
+
+    /// Record the latest link statistics.
+    pub fn link_stats(&mut self, stats: &LinkStats)
+    {
+        self.loss_rate = stats.loss_rate;
+    }
+
+    /// Revise the decisions if the control interval has passed. Returns the
+    /// new decisions if they changed.
+    pub fn update(&mut self) -> Option<RateDecision>
+    {
+        let now = Instant::now();
+
+        if now.duration_since(self.last_update) < Duration::from_millis(CONTROL_INTERVAL_MS) {
+            return None
+        }
+
+        self.last_update = now;
+
+        let sent_bitrate = window_bitrate(&mut self.sent, now);
+        let delivered_bitrate = window_bitrate(&mut self.delivered, now);
+
+        let target = match self.fixed_target {
+            Some(target) => target,
+            None => MIN_ESTIMATED_BITRATE.max((delivered_bitrate as f64 * PROBE_FACTOR) as u64)
+        };
+
+        let previous = self.decision;
+        self.decision.target_bitrate = target;
+
+        if (sent_bitrate as f64 > target as f64 * OVERSHOOT) | (self.loss_rate > MAX_LOSS_RATE) {
+            self.decrease();
+        } else if (sent_bitrate as f64) < target as f64 * UNDERSHOOT {
+            self.increase();
+        }
+
+        if self.decision != previous {
+            Some(self.decision)
+        } else {
+            None
+        }
+    }
+
+    fn decrease(&mut self)
+    {
+        let decision = &mut self.decision;
+
+        if decision.threshold < self.min_threshold * MAX_THRESHOLD_FACTOR {
+            decision.threshold = (decision.threshold * 2).min(self.min_threshold * MAX_THRESHOLD_FACTOR);
+        } else if decision.quality > MIN_QUALITY {
+            decision.quality = decision.quality.saturating_sub(QUALITY_STEP).max(MIN_QUALITY);
+        } else if decision.frame_interval < self.min_frame_interval * MAX_FRAME_INTERVAL_FACTOR {
+            decision.frame_interval = (decision.frame_interval * 3 / 2)
+                .min(self.min_frame_interval * MAX_FRAME_INTERVAL_FACTOR);
+        }
+    }
+
+    fn increase(&mut self)
+    {
+        let decision = &mut self.decision;
+
+        if decision.frame_interval > self.min_frame_interval {
+            decision.frame_interval = (decision.frame_interval * 2 / 3).max(self.min_frame_interval);
+        } else if decision.quality < self.max_quality {
+            decision.quality = (decision.quality + QUALITY_STEP).min(self.max_quality);
+        } else if decision.threshold > self.min_threshold {
+            decision.threshold = (decision.threshold / 2).max(self.min_threshold);
+        }
+    }
+}
+
+// Drops samples older than the window and returns the bitrate of the rest.
+fn window_bitrate(samples: &mut VecDeque<(Instant, usize)>, now: Instant) -> u64
+{
+    let window = Duration::from_millis(WINDOW_MS);
+
+    while let Some(&(time, _)) = samples.front() {
+        if now.duration_since(time) > window {
+            samples.pop_front();
+        } else {
+            break
+        }
+    }
+
+    let bytes: usize = samples.iter().map(|&(_, bytes)| bytes).sum();
+
+    bytes as u64 * 8 * 1000 / WINDOW_MS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(100);
+
+    fn rate_controller() -> RateController
+    {
+        RateController::new(100_000, 50, 100, INTERVAL)
+    }
+
+    // Lets the control interval pass without waiting for it.
+    fn elapse(controller: &mut RateController)
+    {
+        controller.last_update -= Duration::from_millis(CONTROL_INTERVAL_MS);
+    }
+
+    #[test]
+    fn decrease_raises_threshold_then_lowers_quality_then_slows_down()
+    {
+        let mut controller = rate_controller();
+
+        for threshold in &[200, 400, 800] {
+            controller.decrease();
+            assert_eq!(controller.decision.threshold, *threshold);
+            assert_eq!(controller.decision.quality, 50);
+        }
+
+        for quality in &[45, 40, 35, 30, 25, 20, 15, 10] {
+            controller.decrease();
+            assert_eq!(controller.decision.quality, *quality);
+            assert_eq!(controller.decision.frame_interval, INTERVAL);
+        }
+
+        controller.decrease();
+        assert_eq!(controller.decision.frame_interval, INTERVAL * 3 / 2);
+
+        for _ in 0..10 {
+            controller.decrease();
+        }
+
+        let lowest = RateDecision {
+            target_bitrate: 100_000,
+            quality: MIN_QUALITY,
+            threshold: 800,
+            frame_interval: INTERVAL * MAX_FRAME_INTERVAL_FACTOR,
+        };
+        assert_eq!(controller.decision, lowest);
+    }
+
+    #[test]
+    fn increase_undoes_the_steps_in_reverse()
+    {
+        let mut controller = rate_controller();
+
+        for _ in 0..20 {
+            controller.decrease();
+        }
+
+        // The frame interval recovers first, then the quality.
+        controller.increase();
+        assert!(controller.decision.frame_interval < INTERVAL * MAX_FRAME_INTERVAL_FACTOR);
+        assert_eq!(controller.decision.quality, MIN_QUALITY);
+
+        while controller.decision.frame_interval > INTERVAL {
+            controller.increase();
+            assert_eq!(controller.decision.quality, MIN_QUALITY);
+        }
+
+        controller.increase();
+        assert_eq!(controller.decision.quality, MIN_QUALITY + QUALITY_STEP);
+        assert_eq!(controller.decision.threshold, 800);
+
+        while controller.decision.quality < 50 {
+            controller.increase();
+            assert_eq!(controller.decision.threshold, 800);
+        }
+
+        for threshold in &[400, 200, 100, 100] {
+            controller.increase();
+            assert_eq!(controller.decision.threshold, *threshold);
+        }
+
+        assert_eq!(controller.decision, rate_controller().decision);
+    }
+
+    #[test]
+    fn update_follows_the_target()
+    {
+        let mut controller = rate_controller();
+
+        // 4 Mbit/s over the window is far above the target, but nothing is
+        // revised before the control interval passed.
+        controller.frame_sent(1_000_000);
+        assert_eq!(controller.update(), None);
+
+        elapse(&mut controller);
+        assert_eq!(controller.update().map(|decision| decision.threshold), Some(200));
+
+        // Losses count as overshoot whatever the bitrate.
+        let mut controller = rate_controller();
+        controller.link_stats(&LinkStats { loss_rate: 0.1, ..LinkStats::default() });
+
+        elapse(&mut controller);
+        assert_eq!(controller.update().map(|decision| decision.threshold), Some(200));
+
+        // With room to spare, the threshold comes back down.
+        controller.link_stats(&LinkStats::default());
+
+        elapse(&mut controller);
+        assert_eq!(controller.update().map(|decision| decision.threshold), Some(100));
+
+        elapse(&mut controller);
+        assert_eq!(controller.update(), None);
+    }
+
+    #[test]
+    fn estimated_target()
+    {
+        let mut controller = RateController::new(0, 50, 100, INTERVAL);
+        assert_eq!(controller.decision().target_bitrate, MIN_ESTIMATED_BITRATE);
+
+        // 1 Mbit/s delivered over the window.
+        controller.bytes_delivered(250_000);
+
+        elapse(&mut controller);
+        controller.update();
+        assert_eq!(controller.decision().target_bitrate, (1_000_000.0 * PROBE_FACTOR) as u64);
+    }
+}
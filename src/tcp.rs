@@ -0,0 +1,595 @@
+// Transport over TCP, one connection per client, for networks that block
+// UDP.
+//
+// Messages in both directions are the same as over UDP, each prefixed with
+// its length as a big endian u32, or in a WebSocket binary frame for the
+// browser viewer. Since TCP delivers everything that was written or drops
+// the connection, packets count as acknowledged once they are written and
+// the client does not need to send acks.
+
+use std::io::{
+    self,
+    ErrorKind,
+    Read,
+    Write
+};
+
+use std::net::{
+    Shutdown,
+    SocketAddr,
+    TcpStream
+};
+
+use std::sync::mpsc::{
+    channel,
+    Sender,
+    Receiver,
+    TryRecvError
+};
+
+use std::thread::{
+    self,
+    JoinHandle
+};
+
+use std::time::Duration;
+
+use super::auth::CHALLENGE_SIZE;
+
+use super::config::{Config, Transport};
+
+use super::negotiation::CAPABILITY_CLOSE;
+
+use super::net::{bind_tcp, display_address};
+
+use super::packet::Packet;
+
+use super::protocol::{
+    ClientId,
+    ContextMessage,
+    SenderMessage,
+    MainMessage,
+    MainSender,
+    HeartbeatMessage,
+    ReceiverMessage,
+
+    OPCODE_SEND_CLOSE,
+    OPCODE_SEND_HANDSHAKE_REJECT,
+    OPCODE_SEND_DENIED,
+};
+
+use super::udp::{
+    auth_challenge_reply,
+    dispatch,
+    handshake_reply,
+    keepalive_reply,
+    link_stats_reply,
+    screen_info_reply,
+    rate_info_reply
+};
+
+use super::util::u8s_to_u32;
+
+use super::websocket::{
+    decode_frame,
+    encode_close,
+    encode_frame,
+    encode_pong,
+    forbidden_response,
+    http_response,
+    parse_request,
+    upgrade_response,
+    Frame,
+    Request,
+    WEBSOCKET_PATH
+};
+
+// Size of the length prefix.
+const LENGTH_SIZE: usize = 4;
+
+// Largest message accepted from the client.
+const MAX_MESSAGE_SIZE: usize = 65536;
+
+// How often the receiver looks for connections and messages from the other
+// threads while it has nothing to read.
+const POLL_MS: u64 = 100;
+
+/// Accepts connections and hands them to main, which answers each until it
+/// accepts its session.
+pub struct TcpServer {
+    to_listener: Sender<ReceiverMessage>,
+    handle: JoinHandle<()>,
+}
+
+impl TcpServer {
+    pub fn close(self)
+    {
+        self.to_listener.send(ReceiverMessage::Close).unwrap();
+        self.handle.join().unwrap();
+    }
+}
+
+/// A connection whose client is not accepted yet. Its receiver passes the
+/// messages of the client to main, which answers through it until the
+/// sender of the session takes the connection over.
+#[derive(Debug)]
+pub struct TcpClient {
+    stream: Option<TcpStream>,
+    websocket: bool,
+    to_receiver: Sender<ReceiverMessage>,
+    handle: JoinHandle<()>,
+}
+
+impl TcpClient {
+    fn start(websocket: bool,
+             origins: &[String],
+             (stream, src): (TcpStream, SocketAddr),
+             main_sender: MainSender)
+        -> io::Result<Self>
+    {
+        let writer = stream.try_clone()?;
+        let (to_receiver, receiver) = channel();
+
+        Ok(TcpClient {
+            stream: Some(writer),
+            websocket,
+            to_receiver,
+            handle: start_receiver_thread(websocket, origins.to_vec(), (stream, src), main_sender, receiver),
+        })
+    }
+
+    /// Asks the client to authenticate.
+    pub fn send_challenge(&mut self, protocol_version: u8, challenge: &[u8; CHALLENGE_SIZE])
+    {
+        let reply = auth_challenge_reply(protocol_version, challenge);
+        write_message(&mut self.stream, &mut Vec::new(), self.websocket, &reply);
+    }
+
+    /// Tells the client why it is not accepted.
+    pub fn send_reject(&mut self, reason: u8)
+    {
+        write_message(&mut self.stream, &mut Vec::new(), self.websocket, &[OPCODE_SEND_HANDSHAKE_REJECT, reason]);
+    }
+
+    /// Closes the connection of a client that is not accepted, its receiver
+    /// ends with it.
+    pub fn close(self)
+    {
+        if let Some(ref stream) = self.stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Starts the sender of the accepted session, the receiver passes the
+    /// messages of the client on to the session from now on. The receiver
+    /// ends when told through the returned channel.
+    pub fn accept(self,
+                  to_context: Sender<ContextMessage>,
+                  (tcp_sender_sender, tcp_sender_receiver): (Sender<SenderMessage>, Receiver<SenderMessage>),
+                  to_pool: Sender<Packet>,
+                  main_sender: MainSender,
+                  heartbeat_sender: Sender<HeartbeatMessage>)
+        -> (JoinHandle<()>, JoinHandle<()>, Sender<ReceiverMessage>)
+    {
+        // The receiver is gone if the client already left, the session
+        // closes on the message it left behind.
+        let _ = self.to_receiver.send(ReceiverMessage::Accept(tcp_sender_sender, heartbeat_sender));
+
+        let s_handle = start_sender_thread(
+            self.websocket,
+            self.stream,
+            to_context,
+            main_sender,
+            self.to_receiver.clone(),
+            tcp_sender_receiver,
+            to_pool
+        );
+
+        (s_handle, self.handle, self.to_receiver)
+    }
+}
+
+pub fn start_listener(config: &Config, main_sender: Sender<(ClientId, MainMessage)>) -> TcpServer
+{
+    let address = config.bind_address.with_port(config.port);
+    let websocket = config.transport == Transport::WebSocket;
+    let origins = config.allowed_origins.clone();
+    let poll = Duration::from_millis(POLL_MS);
+
+    let listener = match bind_tcp(address, config.v6_only) {
+        Ok(l) => l,
+        Err(e) => panic!("Could not bind socket to {}: {}", address, e)
+    };
+
+    listener.set_nonblocking(true).unwrap();
+
+    let (to_listener, listener_receiver) = channel();
+
+    let handle = thread::spawn(move || {
+        let mut next_client: ClientId = 0;
+
+        loop {
+            if let Ok(ReceiverMessage::Close) = listener_receiver.try_recv() {
+                return
+            }
+
+            match listener.accept() {
+                Ok((stream, src)) => {
+                    println!("TCP Listener: Connection from {}", display_address(&src));
+
+                    let client = MainSender::new(next_client, main_sender.clone());
+                    let connection = configure(&stream, poll)
+                        .and_then(|_| TcpClient::start(websocket, &origins, (stream, src), client));
+
+                    match connection {
+                        Ok(connection) => {
+                            main_sender.send((next_client, MainMessage::Connected(src, connection))).unwrap();
+                            next_client = next_client.wrapping_add(1);
+                        },
+                        Err(e) => println!("TCP Listener: Dropped connection from {}: {}", display_address(&src), e)
+                    }
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(poll),
+                Err(e) => {
+                    println!("TCP Listener: Accept failed: {}", e);
+                    thread::sleep(poll);
+                }
+            }
+        }
+    });
+
+    TcpServer {
+        to_listener,
+        handle,
+    }
+}
+
+// The receiver reads with a timeout, so that it notices messages from the
+// other threads.
+fn configure(stream: &TcpStream, poll: Duration) -> io::Result<()>
+{
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(poll))?;
+    stream.set_nodelay(true)
+}
+
+fn start_sender_thread(websocket: bool,
+                       mut stream: Option<TcpStream>,
+                       to_context: Sender<ContextMessage>,
+                       to_main: MainSender,
+                       to_receiver: Sender<ReceiverMessage>,
+                       tcp_sender_receiver: Receiver<SenderMessage>,
+                       to_pool: Sender<Packet>)
+    -> JoinHandle<()>
+{
+    thread::spawn(move || {
+        let mut id = 0u32;
+        let mut frame = Vec::new();
+
+        // Agreed in the handshake, see negotiation.
+        let mut capabilities = 0;
+
+        loop {
+            match tcp_sender_receiver.recv() {
+                Ok(SenderMessage::AcceptHandshake(protocol_version, cache_size, liveness_timeout, agreement)) => {
+                    println!("TCP Sender: Accept handshake");
+
+                    let reply = handshake_reply(protocol_version, cache_size, liveness_timeout, agreement.as_ref());
+                    write_message(&mut stream, &mut frame, websocket, &reply);
+
+                    capabilities = agreement.map_or(0, |agreement| agreement.capabilities);
+                },
+                Ok(SenderMessage::ScreenInfo(info)) => {
+                    println!("TCP Sender: Screen Info");
+                    write_message(&mut stream, &mut frame, websocket, &screen_info_reply(info));
+                },
+                // Packets count as delivered once they are written.
+                Ok(SenderMessage::Packet(timestamp, mut packet)) => {
+                    if stream.is_some() {
+                        packet.set_header(timestamp, id);
+
+                        if write_message(&mut stream, &mut frame, websocket, &packet.data) {
+                            let _ = to_context.send(ContextMessage::AckPackets(timestamp, packet.blocks.clone()));
+                            let _ = to_main.send(MainMessage::Delivered(packet.data.len()));
+                        }
+
+                        id += 1;
+                    }
+
+                    // The encoder may already be gone.
+                    let _ = to_pool.send(packet);
+                },
+                Ok(SenderMessage::RateInfo(timestamp, decision)) => {
+                    write_message(&mut stream, &mut frame, websocket, &rate_info_reply(timestamp, decision));
+                },
+                // TCP does its own congestion control and loses nothing.
+                // Sessions are not resumed over TCP, see main.
+                Ok(SenderMessage::Acked(..))
+                    | Ok(SenderMessage::Lost(..))
+                    | Ok(SenderMessage::FecGroupSize(_))
+                    | Ok(SenderMessage::SessionToken(_))
+                    | Ok(SenderMessage::Resume(..)) => (),
+                Ok(SenderMessage::LinkStats(stats)) => {
+                    write_message(&mut stream, &mut frame, websocket, &link_stats_reply(&stats));
+                },
+                Ok(SenderMessage::Keepalive(time, sequence)) => {
+                    write_message(&mut stream, &mut frame, websocket, &keepalive_reply(time, sequence));
+                },
+                Ok(SenderMessage::Denied(opcode)) => {
+                    write_message(&mut stream, &mut frame, websocket, &[OPCODE_SEND_DENIED, opcode]);
+                },
+                Ok(SenderMessage::Pong(payload)) => {
+                    frame.clear();
+                    encode_pong(&mut frame, &payload);
+                    write_frame(&mut stream, &frame);
+                },
+                Ok(SenderMessage::CloseFrame(payload)) => {
+                    println!("TCP Sender: Close frame");
+
+                    let mut closed = stream.take();
+
+                    frame.clear();
+                    encode_close(&mut frame, &payload);
+                    write_frame(&mut closed, &frame);
+
+                    if let Some(closed) = closed {
+                        let _ = closed.shutdown(Shutdown::Both);
+                    }
+                },
+                Ok(SenderMessage::Close) => {
+                    println!("TCP Sender: Close");
+
+                    if capabilities & CAPABILITY_CLOSE != 0 {
+                        write_message(&mut stream, &mut frame, websocket, &[OPCODE_SEND_CLOSE]);
+                    }
+
+                    if let Some(ref stream) = stream {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+
+                    let _ = to_receiver.send(ReceiverMessage::Close);
+                    return;
+                },
+                Err(_) => return
+            };
+        };
+    })
+}
+
+// Writes a message with its length prefix or as a WebSocket frame. The
+// connection is dropped when writing fails, the receiver notices it as well
+// and closes the session.
+fn write_message(stream: &mut Option<TcpStream>,
+                 frame: &mut Vec<u8>,
+                 websocket: bool,
+                 message: &[u8])
+    -> bool
+{
+    frame.clear();
+
+    if websocket {
+        encode_frame(frame, message);
+    } else {
+        frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frame.extend_from_slice(message);
+    }
+
+    write_frame(stream, frame)
+}
+
+fn write_frame(stream: &mut Option<TcpStream>, frame: &[u8]) -> bool
+{
+    let result = match *stream {
+        Some(ref mut s) => s.write_all(frame),
+        None => return false
+    };
+
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            println!("TCP Sender: Connection lost: {}", e);
+            *stream = None;
+            false
+        }
+    }
+}
+
+// Reads the messages of the client. Until it is accepted they only go to
+// main, and the receiver answers pings and closes itself.
+fn start_receiver_thread(websocket: bool,
+                         origins: Vec<String>,
+                         connection: (TcpStream, SocketAddr),
+                         main_sender: MainSender,
+                         tcp_receiver_receiver: Receiver<ReceiverMessage>)
+    -> JoinHandle<()>
+{
+    let poll = Duration::from_millis(POLL_MS);
+
+    thread::spawn(move || {
+        let mut connection = Some(connection);
+        let mut upgraded = false;
+        let mut pending = Vec::new();
+        let mut buf = [0u8; 4096];
+
+        // The sender and heartbeat of the session, once accepted.
+        let mut session: Option<(Sender<SenderMessage>, Sender<HeartbeatMessage>)> = None;
+
+        loop {
+            // The session ends on a heartbeat timeout or when the sender is
+            // gone, a client that is not accepted when main drops it.
+            match tcp_receiver_receiver.try_recv() {
+                Ok(ReceiverMessage::Accept(to_sender, heartbeat_sender)) => session = Some((to_sender, heartbeat_sender)),
+                Err(TryRecvError::Empty) => (),
+                _ => return
+            }
+
+            // Once the connection is gone, the receiver waits for the
+            // session to end, if there is one.
+            let amt = match connection {
+                None if session.is_none() => return,
+                None => {
+                    thread::sleep(poll);
+                    continue
+                },
+                Some((ref mut stream, _)) => stream.read(&mut buf)
+            };
+
+            let src = connection.as_ref().unwrap().1;
+
+            match amt {
+                Ok(0) => {
+                    println!("TCP Receiver: Connection closed");
+                    let _ = main_sender.send(MainMessage::Close);
+                    connection = None;
+                },
+                Ok(amt) => {
+                    pending.extend_from_slice(&buf[..amt]);
+
+                    // A browser first asks for the viewer page, or to
+                    // switch the connection to WebSocket.
+                    if websocket & !upgraded {
+                        let stream = &mut connection.as_mut().unwrap().0;
+
+                        match parse_request(&mut pending) {
+                            Ok(None) => continue,
+                            // Pages of other sites must not open sessions
+                            // in the name of the browser's user.
+                            Ok(Some(ref request)) if request.is_upgrade() & !request.origin_allowed(&origins) => {
+                                println!("TCP Receiver: WebSocket upgrade from an origin not allowed");
+
+                                let _ = stream.write_all(&forbidden_response());
+                                let _ = stream.shutdown(Shutdown::Both);
+                                let _ = main_sender.send(MainMessage::Close);
+                                connection = None;
+                                continue
+                            },
+                            Ok(Some(Request { ref path, key: Some(ref key), .. })) if path == WEBSOCKET_PATH => {
+                                println!("TCP Receiver: WebSocket upgrade");
+
+                                if stream.write_all(&upgrade_response(key)).is_err() {
+                                    let _ = main_sender.send(MainMessage::Close);
+                                    connection = None;
+                                    continue
+                                }
+
+                                upgraded = true;
+                            },
+                            Ok(Some(Request { ref path, .. })) => {
+                                println!("TCP Receiver: HTTP request for {}", path);
+
+                                let _ = stream.write_all(&http_response(path));
+                                let _ = stream.shutdown(Shutdown::Both);
+                                let _ = main_sender.send(MainMessage::Close);
+                                connection = None;
+                                continue
+                            },
+                            Err(e) => {
+                                println!("TCP Receiver: Invalid HTTP request: {}", e);
+                                let _ = main_sender.send(MainMessage::Close);
+                                connection = None;
+                                continue
+                            }
+                        }
+                    }
+
+                    loop {
+                        match next_message(&mut pending, websocket) {
+                            // Nothing more is read from a client that closed
+                            // the session, the sender ends this thread.
+                            Ok(Some(Frame::Message(message))) => {
+                                let heartbeat_sender = session.as_ref().map(|(_, heartbeat_sender)| heartbeat_sender);
+
+                                if dispatch(&message, src, None, &main_sender, heartbeat_sender, None) {
+                                    connection = None;
+                                    break
+                                }
+                            },
+                            // The sender of a session answers, so that its
+                            // frames are not interleaved with the answer.
+                            Ok(Some(Frame::Ping(payload))) => match session {
+                                Some((ref to_sender, _)) => {
+                                    let _ = to_sender.send(SenderMessage::Pong(payload));
+                                },
+                                None => {
+                                    let mut frame = Vec::new();
+                                    encode_pong(&mut frame, &payload);
+
+                                    let _ = connection.as_mut().unwrap().0.write_all(&frame);
+                                }
+                            },
+                            Ok(Some(Frame::Close(payload))) => {
+                                println!("TCP Receiver: Closed by client");
+
+                                match session {
+                                    Some((ref to_sender, _)) => {
+                                        let _ = to_sender.send(SenderMessage::CloseFrame(payload));
+                                    },
+                                    None => {
+                                        let mut frame = Vec::new();
+                                        encode_close(&mut frame, &payload);
+
+                                        let stream = &mut connection.as_mut().unwrap().0;
+                                        let _ = stream.write_all(&frame);
+                                        let _ = stream.shutdown(Shutdown::Both);
+                                    }
+                                }
+
+                                let _ = main_sender.send(MainMessage::Close);
+                                connection = None;
+                                break
+                            },
+                            Ok(None) => break,
+                            Err(e) => {
+                                println!("TCP Receiver: {}", e);
+                                let _ = main_sender.send(MainMessage::Close);
+                                connection = None;
+                                break
+                            }
+                        }
+                    }
+                },
+                Err(ref e) if (e.kind() == ErrorKind::WouldBlock) | (e.kind() == ErrorKind::TimedOut) => (),
+                Err(e) => {
+                    println!("TCP Receiver: Connection lost: {}", e);
+                    let _ = main_sender.send(MainMessage::Close);
+                    connection = None;
+                }
+            }
+        };
+    })
+}
+
+// Takes the next complete message, or WebSocket ping or close, from the
+// start of the buffer. Empty messages are skipped.
+fn next_message(pending: &mut Vec<u8>, websocket: bool) -> Result<Option<Frame>, String>
+{
+    loop {
+        let message = if websocket {
+            decode_frame(pending, MAX_MESSAGE_SIZE)?
+        } else {
+            if pending.len() < LENGTH_SIZE {
+                return Ok(None)
+            }
+
+            let len = u8s_to_u32(pending[0], pending[1], pending[2], pending[3]) as usize;
+
+            if len > MAX_MESSAGE_SIZE {
+                return Err(format!("Invalid message length {}", len))
+            }
+
+            if pending.len() < LENGTH_SIZE + len {
+                return Ok(None)
+            }
+
+            let message = pending[LENGTH_SIZE..LENGTH_SIZE + len].to_vec();
+            pending.drain(..LENGTH_SIZE + len);
+
+            Some(Frame::Message(message))
+        };
+
+        match message {
+            Some(Frame::Message(ref message)) if message.is_empty() => (),
+            _ => return Ok(message)
+        }
+    }
+}
@@ -4,8 +4,11 @@ use std::net::
     UdpSocket
 };
 
+use std::mem;
+
 use std::sync::mpsc::
 {
+    channel,
     Sender,
     Receiver,
 };
@@ -16,231 +19,392 @@ use std::thread::
     JoinHandle
 };
 
-use std::time::Duration;
+use std::collections::{
+    HashMap,
+    VecDeque
+};
+
+use std::time::{
+    Duration,
+    Instant
+};
 
 use std::io::{
     Result,
     ErrorKind
 };
 
+use super::auth::CHALLENGE_SIZE;
+
+use super::codec::{
+    ClientMessage,
+    OPCODE_RECEIVE_AUTH_RESPONSE,
+    OPCODE_RECEIVE_HANDSHAKE,
+    OPCODE_RECEIVE_RESUME
+};
+
+use super::config::{Config, ReplyMode, PROBE_START_SIZE};
+
+use super::congestion::CongestionController;
+
+use super::crypto::{
+    respond,
+    KeyPair,
+    Opener,
+    Sealer
+};
+
+use super::fec::ParityGroup;
+
+use super::link_stats::LinkStats;
+
+use super::negotiation::{Agreement, CAPABILITY_CLOSE, CAPABILITY_MTU_PROBE};
+
+use super::net::{bind_udp, display_address, is_too_large, set_dont_fragment};
+
+use super::packet::Packet;
+
+use super::rate_control::RateDecision;
+
 use super::protocol::
 {
+    ClientId,
+    ContextMessage,
     SenderMessage,
     MainMessage,
+    MainSender,
     PendingAckMessage,
     HeartbeatMessage,
     ReceiverMessage,
 
-    OPCODE_RECEIVE_HANDSHAKE,
-    OPCODE_RECEIVE_REQUEST_SCREEN_INFO,
-    OPCODE_RECEIVE_REQUEST_VIEW,
-    OPCODE_RECEIVE_REFRESH,
-    OPCODE_RECEIVE_CLOSE,
-    OPCODE_RECEIVE_EXIT,
-    OPCODE_RECEIVE_LEFT_CLICK,
-    OPCODE_RECEIVE_RIGHT_CLICK,
-    OPCODE_RECEIVE_DOUBLE_CLICK,
-    OPCODE_RECEIVE_DRAG,
-    OPCODE_RECEIVE_KEYBOARD,
-    OPCODE_RECEIVE_ACK,
-    OPCODE_RECEIVE_HEARTBEAT,
 
     OPCODE_SEND_HANDSHAKE_ACK,
     OPCODE_SEND_SCREEN_INFO,
-    OPCODE_SEND_IMAGE_DATA,
+    OPCODE_SEND_CLOSE,
+    OPCODE_SEND_RATE_INFO,
+    OPCODE_SEND_AUTH_CHALLENGE,
+    OPCODE_SEND_HANDSHAKE_REJECT,
+    OPCODE_SEND_LINK_STATS,
+    OPCODE_SEND_MTU_PROBE,
+    OPCODE_SEND_END_OF_FRAME,
+    OPCODE_SEND_DENIED,
+    OPCODE_SEND_SESSION_TOKEN,
+    OPCODE_SEND_RESUME_ACK,
+    OPCODE_SEND_KEEPALIVE,
+
+    OPCODE_SECURE_HANDSHAKE,
+    OPCODE_SECURE_DATA,
 };
 
-use super::util::
-{
-    u8s_to_u16,
-    u8s_to_u32,
-};
 
-const MAX_BUFFER_SIZE: usize = 1000;
+// Longest time the sender waits for messages while packets are queued.
+const IDLE_MS: u64 = 20;
+
+// How often the receiver looks for messages from main while no datagrams
+// arrive.
+const POLL_MS: u64 = 100;
+
+// Bytes the queue of a session holds at most. The context stops sending
+// frames long before, see Backlog, only full images get this far. Packets
+// beyond it are dropped as lost.
+const MAX_QUEUED: usize = 8 * 1024 * 1024;
 
+// End of frame records kept to be sent again if lost. Older frames have
+// been replaced on the client by the time a loss is noticed.
+const MAX_END_OF_FRAMES: usize = 32;
+
+// Datagram sizes tried when probing the path MTU: Ethernet jumbo frames,
+// Ethernet over IPv4 and IPv6, and common sizes inside tunnels.
+const PROBE_SIZES: [usize; 6] = [8972, 1472, 1452, 1420, 1400, 1350];
 
 pub struct Udp {
     socket: UdpSocket,
-    client: SocketAddr
+    // The address replies go to.
+    client: SocketAddr,
+    sealer: Option<Sealer>,
+    sealed: Vec<u8>,
 }
 
-pub fn init_udp_sockets(pending_ack_sender: Sender<PendingAckMessage>,
-                        udp_receiver_receiver: Receiver<ReceiverMessage>,
-                        udp_sender_receiver: Receiver<SenderMessage>,
-                        main_sender: Sender<MainMessage>,
-                        heartbeat_sender: Sender<HeartbeatMessage>)
-    -> (JoinHandle<()>, JoinHandle<()>)
+/// The server's half of a key exchange completed by the receiver: the
+/// reply that completes it and the key the session's sender seals with.
+#[derive(Debug)]
+pub struct KeyExchange {
+    reply: Vec<u8>,
+    sealer: Sealer,
+}
+
+/// The receiving socket is shared by all sessions, and so is the socket
+/// replies are sent from. Sessions are started and ended by main.
+pub struct UdpServer {
+    data: UdpSocket,
+    to_receiver: Sender<ReceiverMessage>,
+    handle: JoinHandle<()>,
+}
+
+// Where the receiver passes datagrams from an address on to. Only the
+// handshake, a resume and the auth response get through until main has
+// accepted the client and started its session.
+struct Route {
+    client: ClientId,
+    main_sender: MainSender,
+    opener: Option<Opener>,
+    heartbeat_sender: Option<Sender<HeartbeatMessage>>,
+    pending_ack_sender: Option<Sender<PendingAckMessage>>,
+}
+
+pub fn init_udp_sockets(config: &Config, main_sender: Sender<(ClientId, MainMessage)>) -> UdpServer
 {
-    let s_handle = Udp::start_sender_thread(
-        pending_ack_sender.clone(),
-        udp_sender_receiver
-    );
+    let address = config.bind_address.with_port(config.port);
+
+    let sock = match bind_udp(address, config.v6_only) {
+        Ok(s) => s,
+        Err(e) => panic!("Could not bind socket to {}: {}", address, e)
+    };
+
+    // Senders reply through the receiving socket in same-socket mode.
+    let data = Udp::reply_socket(config, &sock);
 
-    let r_handle = Udp::start_receiver_thread(
-        pending_ack_sender,
+    let (to_receiver, udp_receiver_receiver) = channel();
+
+    let handle = Udp::start_receiver_thread(
+        sock,
+        config.receive_buffer,
+        config.server_keys.clone(),
         main_sender,
-        heartbeat_sender,
         udp_receiver_receiver
     );
 
-    (s_handle, r_handle)
+    UdpServer {
+        data,
+        to_receiver,
+        handle,
+    }
+}
+
+impl UdpServer {
+    /// Routes the datagrams from the address to the session of the client.
+    pub fn connect(&self,
+                   client: ClientId,
+                   src: SocketAddr,
+                   heartbeat_sender: Sender<HeartbeatMessage>,
+                   pending_ack_sender: Sender<PendingAckMessage>)
+    {
+        let msg = ReceiverMessage::Connect(client, src, heartbeat_sender, pending_ack_sender);
+        self.to_receiver.send(msg).unwrap();
+    }
+
+    /// Replies to a client that is not accepted yet, none if it came
+    /// without the key exchange --secure takes. Main answers the client
+    /// through it until the sender of its session takes it over.
+    pub fn reply_to(&self, config: &Config, src: SocketAddr, key_exchange: Option<KeyExchange>) -> Option<Udp>
+    {
+        Udp::connect(src, &self.data, config, key_exchange)
+    }
+
+    /// Starts the sender of an accepted session.
+    pub fn start_sender(&self,
+                        config: &Config,
+                        udp: Udp,
+                        pending_ack_sender: Sender<PendingAckMessage>,
+                        udp_sender_receiver: Receiver<SenderMessage>,
+                        to_pool: Sender<Packet>,
+                        session: (MainSender, Sender<ContextMessage>))
+        -> JoinHandle<()>
+    {
+        Udp::start_sender_thread(
+            config.clone(),
+            udp,
+            pending_ack_sender,
+            udp_sender_receiver,
+            to_pool,
+            session
+        )
+    }
+
+    /// Moves the session of a client to the address the other client sent
+    /// from.
+    pub fn resume(&self, from: ClientId, to: ClientId)
+    {
+        self.to_receiver.send(ReceiverMessage::Resume(from, to)).unwrap();
+    }
+
+    pub fn disconnect(&self, client: ClientId)
+    {
+        self.to_receiver.send(ReceiverMessage::Disconnect(client)).unwrap();
+    }
+
+    pub fn close(self)
+    {
+        self.to_receiver.send(ReceiverMessage::Close).unwrap();
+        self.handle.join().unwrap();
+    }
 }
 
 impl Udp {
-    fn start_sender_thread(to_pending_ack: Sender<PendingAckMessage>,
-                           udp_sender_receiver: Receiver<SenderMessage>)
+    fn start_sender_thread(config: Config,
+                           mut udp: Udp,
+                           to_pending_ack: Sender<PendingAckMessage>,
+                           udp_sender_receiver: Receiver<SenderMessage>,
+                           to_pool: Sender<Packet>,
+                           (to_main, to_context): (MainSender, Sender<ContextMessage>))
         -> JoinHandle<()>
     {
         // Spawn the sender thread.
         thread::spawn(move || {
             // The packet id is a 32 bit unsigned integer
-            // Create a buffer for sending data
-            // The vector of ids present in the current packet
             let mut id = 0u32;
-            let mut buffer = Vec::with_capacity(MAX_BUFFER_SIZE);
-            let mut present_ids = Vec::with_capacity(100);
 
-            let mut new_len;
+            // Packets wait here until the congestion controller lets them go.
+            // The context withholds frames while the queue is backlogged.
+            let mut queue: VecDeque<(u32, Packet)> = VecDeque::new();
+            let mut queued = 0;
+            let mut backlogged = false;
+            let mut congestion = CongestionController::new();
+            let mut frame_interval = Duration::from_nanos(1_000_000_000 / config.fps);
+
+            // Sent packets a parity packet will cover, 0 per group for none.
+            let mut parity = ParityGroup::new();
+            let mut fec_group_size = config.fec.group_size(0.0);
+
+            // The largest datagram the path is probed for, decided on accept.
+            let mut probe_size = None;
+
+            // Agreed in the handshake, see negotiation.
+            let mut capabilities = 0;
 
-            // Create a new UDP socket and await handshake
-            let mut udp = None;
+            // Set once a datagram could not be sent, main closes the session.
+            let mut failed = false;
 
-            // Set the intial packet id
-            set_packet_id(&mut buffer, id);
+            // Id, timestamp and data of the end of frame records sent.
+            let mut end_of_frames: VecDeque<(u32, u32, Vec<u8>)> = VecDeque::new();
 
             // Start the event loop
             loop {
-                match udp_sender_receiver.recv() {
-                    // We received the last block to send. If the buffer is
-                    // longer than 4 bytes, there is data present which must
-                    // be sent.
+                // Wake up when the next packet is due. With a full window,
+                // acknowledgements and losses wake the thread up.
+                let timeout = match queue.front() {
+                    Some((_, packet)) => match congestion.send_time(packet.data.len()) {
+                        Some(time) => time.saturating_duration_since(Instant::now()),
+                        None => Duration::from_millis(IDLE_MS)
+                    },
+                    None => Duration::from_millis(IDLE_MS)
+                };
+
+                match udp_sender_receiver.recv_timeout(timeout) {
                     Ok(SenderMessage::AcceptHandshake(
-                           src,
-                           protocol_version))
+                           protocol_version,
+                           cache_size,
+                           liveness_timeout,
+                           agreement))
                     => {
                         println!("UDP Sender: Accept handshake");
 
-                        let reply = vec![
-                            OPCODE_SEND_HANDSHAKE_ACK,
-                            protocol_version
-                        ];
+                        let reply = handshake_reply(protocol_version, cache_size, liveness_timeout, agreement.as_ref());
 
-                        udp = Some(Self::new_sender(src));
-                        udp.as_ref()
-                            .unwrap()
-                            .send(reply.as_slice())
-                            .unwrap();
-                    },
-                    Ok(SenderMessage::ScreenInfo(info))
-                    => {
-                        println!("UDP Receiver: Screen Info");
+                        // Probes go up to the datagram size the client
+                        // agreed to.
+                        probe_size = agreement
+                            .filter(|agreement| agreement.has(CAPABILITY_MTU_PROBE))
+                            .map(|agreement| agreement.max_datagram_size as usize);
 
-                        if udp.as_ref().is_some() {
-                            let len = info.len() + 1;
+                        capabilities = agreement.map_or(0, |agreement| agreement.capabilities);
 
-                            let mut i = 1;
-                            let mut reply = vec![0u8; len];
+                        check_sent(udp.send(reply.as_slice()), &to_main, &mut failed);
 
-                            reply[0] = OPCODE_SEND_SCREEN_INFO;
+                        if let Some(max_datagram_size) = probe_size {
+                            udp.probe_mtu(&config, max_datagram_size);
+                        }
+                    },
+                    Ok(SenderMessage::Keepalive(time, sequence)) => {
+                        check_sent(udp.send(&keepalive_reply(time, sequence)), &to_main, &mut failed);
+                    },
+                    Ok(SenderMessage::SessionToken(token)) => {
+                        let mut reply = vec![OPCODE_SEND_SESSION_TOKEN];
+                        reply.extend_from_slice(&token);
 
-                            for v in info {
-                                reply[i] = v;
-                                i += 1;
-                            }
+                        check_sent(udp.send(&reply), &to_main, &mut failed);
+                    },
+                    // The client moved to another address. Packets in flight
+                    // are lost, the path is probed anew.
+                    Ok(SenderMessage::Resume(src, key_exchange)) => {
+                        println!("UDP Sender: Resume");
+
+                        if let Some(resumed) = Self::connect(src, &udp.socket, &config, key_exchange) {
+                            udp = resumed;
+                        }
 
-                            udp.as_ref()
-                                .unwrap()
-                                .send(reply.as_slice())
-                                .unwrap();
+                        check_sent(udp.send(&[OPCODE_SEND_RESUME_ACK]), &to_main, &mut failed);
+
+                        if let Some(max_datagram_size) = probe_size {
+                            udp.probe_mtu(&config, max_datagram_size);
                         }
+
+                        congestion.restart(id);
+
+                        to_pending_ack
+                            .send(PendingAckMessage::Resume)
+                            .unwrap();
                     },
-                    Ok(SenderMessage::EndOfData(timestamp))
+                    Ok(SenderMessage::ScreenInfo(info))
                     => {
-                        if (&udp).is_some() {
-                            if buffer.len() > 10 {
-                                buffer[1] = (timestamp >> 24) as u8;
-                                buffer[2] = (timestamp >> 16) as u8;
-                                buffer[3] = (timestamp >> 8) as u8;
-                                buffer[4] = timestamp as u8;
-
-                                udp.as_ref()
-                                    .unwrap()
-                                    .send(
-                                        buffer.as_slice()
-                                    ).unwrap();
-
-                                to_pending_ack
-                                    .send(
-                                        PendingAckMessage::NewSend(
-                                            timestamp,
-                                            id,
-                                            present_ids.clone()
-                                        )
-                                    ).unwrap();
-
-                                present_ids.clear();
-
-                                // Increment packet id.
-                                id += 1;
-                            }
+                        println!("UDP Receiver: Screen Info");
 
-                            // Clear buffer and set appropriate packet id.
-                            buffer.clear();
-                            set_packet_id(&mut buffer, id);
-                        }
+                        let reply = screen_info_reply(info);
+                        check_sent(udp.send(reply.as_slice()), &to_main, &mut failed);
                     },
-                    // We received a new encoded macroblock to send. If the
-                    // data is too long for the current buffer, its current
-                    // contents are sent, the buffer is cleared and
-                    // reinitialized, the blocks present are added to the map
-                    // of unacknowledged packets and the current list is
-                    // cleared. The block id is added to the current id list.
-                    Ok(SenderMessage::Macroblock(timestamp, data))
+                    // A packet of image data or cached blocks from the
+                    // encoder, queued until it may be sent.
+                    Ok(SenderMessage::Packet(timestamp, packet))
                     => {
-                        if (&udp).is_some() {
-                            new_len = buffer.len() + data.len();
-
-                            if (new_len) >= MAX_BUFFER_SIZE {
-                                buffer[1] = (timestamp >> 24) as u8;
-                                buffer[2] = (timestamp >> 16) as u8;
-                                buffer[3] = (timestamp >> 8) as u8;
-                                buffer[4] = timestamp as u8;
-
-                                udp.as_ref()
-                                    .unwrap()
-                                    .send(
-                                        buffer.as_slice()
-                                    ).unwrap();
-
-                                to_pending_ack
-                                    .send(
-                                        PendingAckMessage::NewSend(
-                                            timestamp,
-                                            id,
-                                            present_ids.clone()
-                                        )
-                                    ).unwrap();
-
-                                buffer.clear();
-                                present_ids.clear();
-
-                                id += 1;
-                                set_packet_id(&mut buffer, id);
-                            }
+                        if queued + packet.data.len() > MAX_QUEUED {
+                            let _ = to_context.send(ContextMessage::LostPackets(timestamp, packet.blocks.clone()));
+                            let _ = to_pool.send(packet);
+                        } else {
+                            queued += packet.data.len();
+                            queue.push_back((timestamp, packet));
+                        }
+                    },
+                    Ok(SenderMessage::Acked(packet_id, size, rtt)) => {
+                        congestion.on_ack(packet_id, size, rtt, id, Instant::now());
+                        end_of_frames.retain(|&(end_id, _, _)| end_id != packet_id);
+                    },
+                    // A lost end of frame record is sent again ahead of the
+                    // queued packets.
+                    Ok(SenderMessage::Lost(packet_id, size)) => {
+                        congestion.on_loss(packet_id, size, id);
+
+                        if let Some(i) = end_of_frames.iter().position(|&(end_id, _, _)| end_id == packet_id) {
+                            let (_, timestamp, data) = end_of_frames.remove(i).unwrap();
 
-                            // Add to packet id list.
-                            present_ids.push(get_block_id(&data));
-                            buffer.extend(data.iter().cloned());
+                            queued += data.len();
+                            queue.push_front((timestamp, Packet { data, blocks: Vec::new() }));
                         }
                     },
+                    Ok(SenderMessage::LinkStats(stats)) => {
+                        check_sent(udp.send(&link_stats_reply(&stats)), &to_main, &mut failed);
+                    },
+                    Ok(SenderMessage::FecGroupSize(size)) => {
+                        fec_group_size = size;
+                    },
+                    Ok(SenderMessage::Denied(opcode)) => {
+                        check_sent(udp.send(&[OPCODE_SEND_DENIED, opcode]), &to_main, &mut failed);
+                    },
+                    // The rate controller changed its decisions. The client
+                    // needs the quality to rebuild its quantization tables
+                    // from the given timestamp on.
+                    Ok(SenderMessage::RateInfo(timestamp, decision))
+                    => {
+                        frame_interval = decision.frame_interval;
+
+                        let reply = rate_info_reply(timestamp, decision);
+                        check_sent(udp.send(reply.as_slice()), &to_main, &mut failed);
+                    },
                     Ok(SenderMessage::Close) => {
                         println!("UDP Sender: Close");
-                        let reply = vec![3];
 
-                        udp.as_ref()
-                            .unwrap()
-                            .send(reply.as_slice())
-                            .unwrap();
+                        if capabilities & CAPABILITY_CLOSE != 0 {
+                            let _ = udp.send(&[OPCODE_SEND_CLOSE]);
+                        }
 
                         to_pending_ack
                             .send(PendingAckMessage::Close)
@@ -249,241 +413,743 @@ impl Udp {
                     }
                     _ => ()
                 };
+
+                // Send the queued packets that are due. Each is sent with the
+                // next packet id, added to the map of unacknowledged packets
+                // and handed back to the encoder for reuse.
+                let now = Instant::now();
+
+                while let Some(size) = queue.front().map(|(_, packet)| packet.data.len()) {
+                    match congestion.send_time(size) {
+                        Some(time) if time <= now => (),
+                        _ => break
+                    }
+
+                    let (timestamp, mut packet) = queue.pop_front().unwrap();
+                    queued -= size;
+
+                    // A parity group covers packets of one timestamp.
+                    if parity.timestamp().is_some_and(|t| t != timestamp) {
+                        let size = udp.send_parity(&mut parity, id, &to_pending_ack);
+                        congestion.on_send(size, queued, frame_interval, now);
+                        id += 1;
+                    }
+
+                    packet.set_header(timestamp, id);
+                    check_sent(udp.send(packet.data.as_slice()), &to_main, &mut failed);
+
+                    to_pending_ack
+                        .send(
+                            PendingAckMessage::NewSend(
+                                timestamp,
+                                id,
+                                packet.blocks.clone(),
+                                size
+                            )
+                        ).unwrap();
+
+                    congestion.on_send(size, queued, frame_interval, now);
+
+                    if packet.data[0] == OPCODE_SEND_END_OF_FRAME {
+                        if end_of_frames.len() == MAX_END_OF_FRAMES {
+                            end_of_frames.pop_front();
+                        }
+
+                        end_of_frames.push_back((id, timestamp, packet.data.clone()));
+                    }
+
+                    if fec_group_size > 0 {
+                        parity.add(timestamp, id, &packet.data);
+                    }
+
+                    id += 1;
+
+                    // The encoder sends a frame at once, so an empty
+                    // queue usually means the frame is complete.
+                    if (parity.len() > 0) & ((parity.len() >= fec_group_size) | queue.is_empty()) {
+                        let size = udp.send_parity(&mut parity, id, &to_pending_ack);
+                        congestion.on_send(size, queued, frame_interval, now);
+                        id += 1;
+                    }
+
+                    // The encoder may already be gone.
+                    let _ = to_pool.send(packet);
+                }
+
+                // More queued than a window means the path does not keep
+                // up with the frames.
+                if (queued > congestion.window()) != backlogged {
+                    backlogged = !backlogged;
+                    let _ = to_context.send(ContextMessage::Backlog(backlogged));
+                }
             };
         })
     }
 
-    fn start_receiver_thread(to_pending_ack: Sender<PendingAckMessage>,
-                             main_sender: Sender<MainMessage>,
-                             heartbeat_sender: Sender<HeartbeatMessage>,
+    // Receives the datagrams of all clients and passes them on to the
+    // sessions they belong to.
+    fn start_receiver_thread(sock: UdpSocket,
+                             receive_buffer: usize,
+                             keys: Option<KeyPair>,
+                             main_sender: Sender<(ClientId, MainMessage)>,
                              udp_receiver_receiver: Receiver<ReceiverMessage>)
         -> JoinHandle<()>
     {
-        let sock = match UdpSocket::bind("0.0.0.0:9998") {
-            Ok(s) => s,
-            Err(e) => panic!("Could not bind socket: {}", e)
-        };
-
-        sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(POLL_MS))).unwrap();
 
         thread::spawn(move || {
+            let mut routes: HashMap<SocketAddr, Route> = HashMap::new();
+            let mut next_client: ClientId = 0;
+
+            // With --secure, a key exchange from an address that has a
+            // session waits here until main accepts it, so that a forged
+            // one cannot take the session's route.
+            let mut replacing: HashMap<SocketAddr, Route> = HashMap::new();
+
             loop {
-                let mut buf = vec![0u8; 800];
-
-                match sock.recv_from(buf.as_mut_slice()) {
-                    Ok((amt, src)) => {
-                        match buf[0] {
-                            OPCODE_RECEIVE_HANDSHAKE
-                                if amt == 3
-                            => {
-                                println!("UDP Receiver: Handshake");
-                                main_sender
-                                    .send(MainMessage::Handshake(
-                                        src,
-                                        buf[1],
-                                        buf[2])
-                                    ).unwrap();
-                            },
-
-                            OPCODE_RECEIVE_REQUEST_SCREEN_INFO
-                                if amt == 1
-                            => {
-                                println!("UDP Receiver: Request screen info");
-                                main_sender
-                                    .send(MainMessage::RequestScreenInfo)
-                                    .unwrap();
-                            },
-
-                            OPCODE_RECEIVE_REQUEST_VIEW
-                                if amt == 3
-                            => {
-                                println!("UDP Receiver: Request view");
-                                main_sender
-                                    .send(MainMessage::RequestView(
-                                        buf[1],
-                                        buf[2])
-                                    ).unwrap();
-                            },
-
-                            OPCODE_RECEIVE_REFRESH
-                                if amt == 1
-                            => {
-                                println!("UDP Receiver: Refresh");
-                                main_sender
-                                    .send(MainMessage::Refresh)
-                                    .unwrap();
-                            },
-
-                            OPCODE_RECEIVE_CLOSE
-                                if amt == 1
-                            => {
-                                println!("UDP Receiver: Close");
-                                main_sender
-                                    .send(MainMessage::Close)
-                                    .unwrap();
-
-                               // heartbeat_sender
-                                //    .send(HeartbeatMessage::Close)
-                                //    .unwrap();
-                                return;
-                            },
-
-                            OPCODE_RECEIVE_EXIT
-                                if amt == 1
-                            => {
-                                println!("UDP Receiver: Exit");
-                                main_sender
-                                    .send(MainMessage::Exit)
-                                    .unwrap();
-
-                               // heartbeat_sender
-                                //    .send(HeartbeatMessage::Close)
-                                //    .unwrap();
-                                return;
-                            },
-
-                            OPCODE_RECEIVE_LEFT_CLICK
-                                if amt == 5
-                            => {
-                                main_sender
-                                    .send(MainMessage::LeftClick(
-                                        u8s_to_u16(buf[1], buf[2]),
-                                        u8s_to_u16(buf[3], buf[4]))
-                                    ).unwrap();
-                            },
-
-                            OPCODE_RECEIVE_RIGHT_CLICK
-                                if amt == 5
-                            => {
-                                main_sender
-                                    .send(MainMessage::RightClick(
-                                        u8s_to_u16(buf[1], buf[2]),
-                                        u8s_to_u16(buf[3], buf[4]))
-                                    ).unwrap();
-                            },
-
-                            OPCODE_RECEIVE_DOUBLE_CLICK
-                                if amt == 5
-                            => {
-                                main_sender
-                                    .send(MainMessage::DoubleClick(
-                                        u8s_to_u16(buf[1], buf[2]),
-                                        u8s_to_u16(buf[3], buf[4]))
-                                    ).unwrap();
-                            },
-
-                            OPCODE_RECEIVE_DRAG
-                                if amt == 13
-                            => {
-                                main_sender
-                                    .send(MainMessage::Drag(
-                                        u8s_to_u16(buf[1], buf[2]),
-                                        u8s_to_u16(buf[3], buf[4]),
-                                        buf[5], buf[6],
-                                        u8s_to_u16(buf[7], buf[8]),
-                                        u8s_to_u16(buf[9], buf[10]),
-                                        buf[11], buf[12])
-                                    ).unwrap();
-                            },
-
-                            OPCODE_RECEIVE_KEYBOARD
-                            => {
-                                main_sender
-                                    .send(MainMessage::Keyboard(buf))
-                                    .unwrap();
-                            },
-
-                            OPCODE_RECEIVE_ACK
-                            => {
-                                to_pending_ack
-                                    .send(PendingAckMessage::NewReceive(
-                                        get_packet_ids(&buf))
-                                    ).unwrap();
-
-                                buf.clear();
-                            },
-
-                            OPCODE_RECEIVE_HEARTBEAT
-                                if amt == 1
-                            => {
-                                heartbeat_sender
-                                    .send(HeartbeatMessage::Heartbeat)
-                                    .unwrap();
-                            },
-
-                            _ => {
-                                println!(" ???" );
+                for msg in udp_receiver_receiver.try_iter() {
+                    match msg {
+                        // The old session at the address is retired by main.
+                        ReceiverMessage::Connect(client, src, heartbeat_sender, pending_ack_sender) => {
+                            if replacing.get(&src).is_some_and(|route| route.client == client) {
+                                routes.insert(src, replacing.remove(&src).unwrap());
                             }
-                        };
-                    },
-                    Err(e)  => {
-                        if e.kind() == ErrorKind::WouldBlock {
-                            match udp_receiver_receiver.try_recv() {
-                                Ok(ReceiverMessage::HeartbeatTimeout) => return,
-                                _ => ()
+
+                            if let Some(route) = routes.get_mut(&src).filter(|route| route.client == client) {
+                                route.heartbeat_sender = Some(heartbeat_sender);
+                                route.pending_ack_sender = Some(pending_ack_sender);
                             }
-                        } else {
-                            panic!(e);
-                        }
+                        },
+                        ReceiverMessage::Disconnect(client) => {
+                            routes.retain(|_, route| route.client != client);
+                            replacing.retain(|_, route| route.client != client);
+                        },
+                        // The old addresses of the session are dropped, the
+                        // new one keeps the opener of its key exchange.
+                        ReceiverMessage::Resume(from, to) => {
+                            let resumed: Vec<SocketAddr> = replacing.iter()
+                                .filter(|(_, route)| route.client == from)
+                                .map(|(&src, _)| src)
+                                .collect();
+
+                            for src in resumed {
+                                routes.insert(src, replacing.remove(&src).unwrap());
+                            }
+
+                            let old: Vec<SocketAddr> = routes.iter()
+                                .filter(|(_, route)| route.client == to)
+                                .map(|(&src, _)| src)
+                                .collect();
+
+                            let mut senders = None;
+
+                            for src in old {
+                                let route = routes.remove(&src).unwrap();
+                                senders = Some((route.main_sender, route.heartbeat_sender, route.pending_ack_sender));
+                            }
+
+                            if let Some((main_sender, heartbeat_sender, pending_ack_sender)) = senders {
+                                for route in routes.values_mut().filter(|route| route.client == from) {
+                                    route.client = to;
+                                    route.main_sender = main_sender.clone();
+                                    route.heartbeat_sender = heartbeat_sender.clone();
+                                    route.pending_ack_sender = pending_ack_sender.clone();
+                                }
+                            }
+                        },
+                        ReceiverMessage::Close => return,
+                        // Main ends the session, UDP sessions are connected
+                        // on accept above.
+                        ReceiverMessage::HeartbeatTimeout | ReceiverMessage::Accept(..) => ()
                     }
+                }
+
+                let mut buf = vec![0u8; receive_buffer];
+
+                let (amt, src) = match sock.recv_from(buf.as_mut_slice()) {
+                    Ok(received) => received,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => panic!("{}", e)
                 };
+
+                if amt == 0 {
+                    continue
+                }
+
+                buf.truncate(amt);
+
+                let (buf, key_exchange, route) = match route((&mut routes, &mut replacing), keys.as_ref(), &mut next_client, &main_sender, src, buf) {
+                    Some(opened) => opened,
+                    None => continue
+                };
+
+                let opens = matches!(buf.first(),
+                                     Some(&OPCODE_RECEIVE_HANDSHAKE) | Some(&OPCODE_RECEIVE_RESUME) | Some(&OPCODE_RECEIVE_AUTH_RESPONSE));
+
+                if route.heartbeat_sender.is_none() & !opens {
+                    continue
+                }
+
+                dispatch(&buf,
+                         src,
+                         key_exchange,
+                         &route.main_sender,
+                         route.heartbeat_sender.as_ref(),
+                         route.pending_ack_sender.as_ref());
             };
         })
     }
 
-    fn new_sender(mut src: SocketAddr)
-        -> Self
+    // With --probe-mtu every datagram of every session goes out with the
+    // don't fragment bit, the option belongs to the socket the sessions
+    // share. Datagrams too large for the interface then fail to send, see
+    // check_sent.
+    fn reply_socket(config: &Config, shared: &UdpSocket) -> UdpSocket
     {
-        let sock = match UdpSocket::bind("0.0.0.0:9999") {
-            Ok(s) => s,
-            Err(e) => panic!("Could not bind socket: {}", e)
+        let data_address = config.bind_address.with_port(config.data_port);
+
+        let socket = match config.reply {
+            ReplyMode::SameSocket => shared.try_clone().unwrap(),
+            ReplyMode::Port(_) | ReplyMode::Source => match bind_udp(data_address, config.v6_only) {
+                Ok(s) => s,
+                Err(e) => panic!("Could not bind socket to {}: {}", data_address, e)
+            }
         };
 
-        src.set_port(36492);
-        Udp {socket: sock, client: src}
+        if config.probe_mtu {
+            if let Err(e) = set_dont_fragment(&socket) {
+                panic!("Could not set the don't fragment bit to probe the path MTU: {}", e)
+            }
+        }
+
+        socket
+    }
+
+    // Replies to the client through the socket. With --secure the reply to
+    // the client's key exchange goes out unsealed first and everything after
+    // it sealed.
+    fn connect(src: SocketAddr,
+               sock: &UdpSocket,
+               config: &Config,
+               key_exchange: Option<KeyExchange>)
+        -> Option<Self>
+    {
+        let mut client = src;
+
+        if let ReplyMode::Port(port) = config.reply {
+            client.set_port(port);
+        }
+
+        let (sealer, reply) = match key_exchange {
+            Some(KeyExchange { reply, sealer }) => (Some(sealer), Some(reply)),
+            None if config.secure => {
+                println!("UDP Sender: No secure session for {}", display_address(&src));
+                return None
+            },
+            None => (None, None)
+        };
+
+        println!("UDP Sender: Replying to {}", display_address(&client));
+
+        let replied = sock.try_clone().and_then(|socket| {
+            let udp = Udp {socket, client, sealer, sealed: Vec::new()};
+
+            if let Some(reply) = reply {
+                udp.send_key_exchange(&reply)?;
+            }
+
+            Ok(udp)
+        });
+
+        match replied {
+            Ok(udp) => Some(udp),
+            Err(e) => {
+                println!("UDP Sender: Cannot reply to {}: {}", display_address(&client), e);
+                None
+            }
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> Result<usize>
+    {
+        match self.sealer {
+            Some(ref mut sealer) => {
+                self.sealed.clear();
+                self.sealed.push(OPCODE_SECURE_DATA);
+                sealer.seal(buf, &mut self.sealed);
+
+                self.socket.send_to(&self.sealed, self.client)
+            },
+            None => self.socket.send_to(buf, self.client)
+        }
+    }
+
+    /// Asks a client that is not accepted yet to authenticate.
+    pub fn send_challenge(&mut self, protocol_version: u8, challenge: &[u8; CHALLENGE_SIZE])
+    {
+        let _ = self.send(&auth_challenge_reply(protocol_version, challenge));
+    }
+
+    /// Tells a client that is not accepted why.
+    pub fn send_reject(&mut self, reason: u8)
+    {
+        let _ = self.send(&[OPCODE_SEND_HANDSHAKE_REJECT, reason]);
+    }
+
+    // Sends probes for the sizes between the start size and the largest
+    // datagram the session allows. The socket does not fragment them, see
+    // reply_socket. Probes too large for the interface fail to send, which
+    // only means the probe failed.
+    fn probe_mtu(&mut self, config: &Config, max_datagram_size: usize)
+    {
+        let sizes = PROBE_SIZES.iter()
+            .cloned()
+            .chain(Some(max_datagram_size))
+            .filter(|&size| (size > PROBE_START_SIZE) & (size <= max_datagram_size));
+
+        for size in sizes {
+            // The probe fills the datagram, the client acknowledges the
+            // packet size it allows.
+            let packet_size = config.packet_size(size);
+
+            let mut probe = vec![0u8; config.plaintext_size(size)];
+            probe[0] = OPCODE_SEND_MTU_PROBE;
+            probe[1] = (packet_size >> 8) as u8;
+            probe[2] = packet_size as u8;
+
+            let _ = self.send(&probe);
+        }
+    }
+
+    /// Sends the parity packet of the group with the given id. It is
+    /// tracked like image data, without blocks, so that losing it counts
+    /// for congestion control. Returns its size.
+    fn send_parity(&mut self,
+                   parity: &mut ParityGroup,
+                   id: u32,
+                   to_pending_ack: &Sender<PendingAckMessage>)
+        -> usize
+    {
+        let timestamp = parity.timestamp().unwrap_or(0);
+
+        let mut packet = parity.take();
+        packet.set_header(timestamp, id);
+
+        let _ = self.send(packet.data.as_slice());
+
+        to_pending_ack
+            .send(PendingAckMessage::NewSend(timestamp, id, Vec::new(), packet.data.len()))
+            .unwrap();
+
+        packet.data.len()
     }
 
-    fn send(&self, buf: &[u8]) -> Result<usize>
+    fn send_key_exchange(&self, reply: &[u8]) -> Result<usize>
     {
-        Ok(try!(self.socket.send_to(buf, self.client)))
+        let mut datagram = Vec::with_capacity(reply.len() + 1);
+        datagram.push(OPCODE_SECURE_HANDSHAKE);
+        datagram.extend_from_slice(reply);
+
+        self.socket.send_to(&datagram, self.client)
     }
 }
 
-fn set_packet_id(buffer: &mut Vec<u8>, id: u32)
+// Finds the session a datagram belongs to and opens it with --secure. A
+// handshake or resume from an address without a session gets a new client
+// id, with --secure every key exchange does. A key exchange from an address
+// with an accepted session is kept apart until main accepts it. Returns the
+// message, the key exchange it came with and its route, or None if the
+// datagram is dropped.
+fn route<'a>((routes, replacing): (&'a mut HashMap<SocketAddr, Route>, &'a mut HashMap<SocketAddr, Route>),
+             keys: Option<&KeyPair>,
+             next_client: &mut ClientId,
+             main_sender: &Sender<(ClientId, MainMessage)>,
+             src: SocketAddr,
+             datagram: Vec<u8>)
+    -> Option<(Vec<u8>, Option<KeyExchange>, &'a Route)>
 {
-    buffer.push(OPCODE_SEND_IMAGE_DATA);
+    let mut new_route = |opener| {
+        let client = *next_client;
+        *next_client = next_client.wrapping_add(1);
+
+        Route {
+            client,
+            main_sender: MainSender::new(client, main_sender.clone()),
+            opener,
+            heartbeat_sender: None,
+            pending_ack_sender: None,
+        }
+    };
+
+    let keys = match keys {
+        Some(keys) => keys,
+        None => {
+            let opens = (datagram[0] == OPCODE_RECEIVE_HANDSHAKE) | (datagram[0] == OPCODE_RECEIVE_RESUME);
+
+            if !routes.contains_key(&src) & opens {
+                routes.insert(src, new_route(None));
+            }
+
+            return routes.get(&src).map(|route| (datagram, None, route))
+        }
+    };
+
+    match datagram[0] {
+        OPCODE_SECURE_HANDSHAKE => {
+            let mut session = match respond(keys, &datagram[1..]) {
+                Some(session) => session,
+                None => {
+                    println!("UDP Receiver: Invalid key exchange from {}", display_address(&src));
+                    return None
+                }
+            };
 
-    for _ in 0..4 {
-        buffer.push(0u8);
+            let payload = mem::take(&mut session.payload);
+            let route = new_route(Some(session.opener));
+
+            let connected = routes.get(&src).is_some_and(|route| route.heartbeat_sender.is_some());
+
+            let route = if connected {
+                replacing.insert(src, route);
+                &replacing[&src]
+            } else {
+                routes.insert(src, route);
+                &routes[&src]
+            };
+
+            Some((payload, Some(KeyExchange { reply: session.reply, sealer: session.sealer }), route))
+        },
+        // Datagrams that the session at the address cannot open may be from
+        // the client replacing it.
+        OPCODE_SECURE_DATA => {
+            let (prefix, sealed) = datagram.split_at(1);
+
+            for routes in [routes, replacing] {
+                if let Some(route) = routes.get_mut(&src) {
+                    if let Some(plaintext) = route.opener.as_mut().and_then(|opener| opener.open(prefix, sealed)) {
+                        return if plaintext.is_empty() { None } else { Some((plaintext, None, &*route)) }
+                    }
+                }
+            }
+
+            None
+        },
+        _ => {
+            println!("UDP Receiver: Unsealed datagram from {}", display_address(&src));
+            None
+        }
     }
+}
+
+/// Passes a message received from the client on to the thread handling it.
+/// Malformed messages are logged and dropped. Returns true when the client
+/// closed the session.
+pub fn dispatch(buf: &[u8],
+                src: SocketAddr,
+                key_exchange: Option<KeyExchange>,
+                main_sender: &MainSender,
+                heartbeat_sender: Option<&Sender<HeartbeatMessage>>,
+                to_pending_ack: Option<&Sender<PendingAckMessage>>)
+    -> bool
+{
+    let message = match ClientMessage::decode(buf) {
+        Ok(message) => message,
+        Err(e) => {
+            println!("Receiver: Dropped message from {}: {}", display_address(&src), e);
+            return false
+        }
+    };
+
+    let msg = match message {
+        ClientMessage::Handshake(handshake) => {
+            println!("Receiver: Handshake from {}", display_address(&src));
+            MainMessage::Handshake(src, handshake, key_exchange)
+        },
+        ClientMessage::Resume(token) => {
+            println!("Receiver: Resume from {}", display_address(&src));
+            MainMessage::Resume(src, token, key_exchange)
+        },
+        ClientMessage::RequestScreenInfo => {
+            println!("Receiver: Request screen info");
+            MainMessage::RequestScreenInfo
+        },
+        ClientMessage::RequestView(screen, segment) => {
+            println!("Receiver: Request view");
+            MainMessage::RequestView(screen, segment)
+        },
+        ClientMessage::Refresh => {
+            println!("Receiver: Refresh");
+            MainMessage::Refresh
+        },
+        ClientMessage::Close => {
+            println!("Receiver: Close");
+            main_sender.send(MainMessage::Close).unwrap();
+            return true
+        },
+        // The session goes on if the client may not stop the server.
+        ClientMessage::Exit => {
+            println!("Receiver: Exit");
+            MainMessage::Exit
+        },
+        ClientMessage::LeftClick(click) => MainMessage::LeftClick(click.x, click.y),
+        ClientMessage::RightClick(click) => MainMessage::RightClick(click.x, click.y),
+        ClientMessage::DoubleClick(click) => MainMessage::DoubleClick(click.x, click.y),
+        ClientMessage::Drag(from, to) => {
+            MainMessage::Drag(from.x, from.y, from.screen, from.segment,
+                              to.x, to.y, to.screen, to.segment)
+        },
+        ClientMessage::Keyboard(text) => MainMessage::Keyboard(text),
+        // Transports that are reliable themselves have no pending acks. The
+        // threads of a session that is closing may be gone already.
+        ClientMessage::Ack(ids) => {
+            if let Some(to_pending_ack) = to_pending_ack {
+                let _ = to_pending_ack.send(PendingAckMessage::NewReceive(ids));
+            }
+
+            return false
+        },
+        ClientMessage::Heartbeat => {
+            if let Some(heartbeat_sender) = heartbeat_sender {
+                let _ = heartbeat_sender.send(HeartbeatMessage::Heartbeat);
+            }
+
+            return false
+        },
+        ClientMessage::MtuProbeAck(size) => MainMessage::MtuProbeAck(size),
+        ClientMessage::RequestStats => {
+            println!("Receiver: Request stats");
+            MainMessage::RequestStats
+        },
+        ClientMessage::AuthResponse(auth) => {
+            println!("Receiver: Auth response from {}", display_address(&src));
+            MainMessage::AuthResponse(src, auth.user, auth.response.to_vec())
+        }
+    };
+
+    main_sender.send(msg).unwrap();
+
+    false
+}
 
-    for i in 0..4 {
-        buffer.push(
-            (id >> (24 - (3 - i)*8)) as u8
-        );
-    }}
+// A datagram that cannot be sent closes the session, the sessions of other
+// clients go on. One too large for the interface only tells main to shrink
+// the packets of the session, it is lost like any other.
+fn check_sent(result: Result<usize>, to_main: &MainSender, failed: &mut bool)
+{
+    if let Err(e) = result {
+        if is_too_large(&e) {
+            let _ = to_main.send(MainMessage::MtuExceeded);
+            return
+        }
+
+        if !*failed {
+            println!("UDP Sender: Send failed: {}", e);
+            let _ = to_main.send(MainMessage::Close);
+        }
+
+        *failed = true;
+    }
+}
 
-fn get_packet_ids(buffer: &Vec<u8>) -> Vec<u32>
+pub fn handshake_reply(protocol_version: u8,
+                       cache_size: Option<u16>,
+                       liveness_timeout: Option<u16>,
+                       agreement: Option<&Agreement>)
+    -> Vec<u8>
 {
-    let n_ids = buffer[1] as usize;
-    let mut result = Vec::with_capacity(n_ids);
-
-    for i in 0..n_ids {
-        result.push(
-            u8s_to_u32(
-                buffer[4 * i + 2],
-                buffer[4 * i + 3],
-                buffer[4 * i + 4],
-                buffer[4 * i + 5]
-            )
-        );
+    let mut reply = vec![
+        OPCODE_SEND_HANDSHAKE_ACK,
+        protocol_version
+    ];
+
+    // Version 2 clients are told everything at once.
+    if let Some(agreement) = agreement {
+        agreement.serialize(&mut reply);
+        return reply
+    }
+
+    // Clients that asked for a cache are told its size.
+    if let Some(size) = cache_size {
+        reply.push((size >> 8) as u8);
+        reply.push(size as u8);
     }
 
-    result
+    // Likewise for the liveness timeout, which comes with a cache size.
+    if let Some(timeout) = liveness_timeout {
+        reply.extend_from_slice(&timeout.to_be_bytes());
+    }
+
+    reply
+}
+
+pub fn keepalive_reply(time: u64, sequence: u32) -> Vec<u8>
+{
+    let mut reply = vec![OPCODE_SEND_KEEPALIVE];
+    reply.extend_from_slice(&time.to_be_bytes());
+    push_u32(&mut reply, sequence);
+
+    reply
+}
+
+pub fn auth_challenge_reply(protocol_version: u8, challenge: &[u8; CHALLENGE_SIZE]) -> Vec<u8>
+{
+    let mut reply = vec![
+        OPCODE_SEND_AUTH_CHALLENGE,
+        protocol_version
+    ];
+
+    reply.extend_from_slice(challenge);
+
+    reply
+}
+
+pub fn link_stats_reply(stats: &LinkStats) -> Vec<u8>
+{
+    let mut reply = vec![OPCODE_SEND_LINK_STATS];
+    stats.serialize(&mut reply);
+
+    reply
+}
+
+pub fn screen_info_reply(info: Vec<u8>) -> Vec<u8>
+{
+    let mut reply = Vec::with_capacity(info.len() + 1);
+
+    reply.push(OPCODE_SEND_SCREEN_INFO);
+    reply.extend(info);
+
+    reply
+}
+
+pub fn rate_info_reply(timestamp: u32, decision: RateDecision) -> Vec<u8>
+{
+    let threshold = decision.threshold.min(u32::MAX as i64) as u32;
+    let interval = decision.frame_interval.as_secs() * 1000
+        + decision.frame_interval.subsec_nanos() as u64 / 1_000_000;
+    let bitrate = (decision.target_bitrate / 1000).min(u32::MAX as u64) as u32;
+
+    let mut reply = Vec::with_capacity(16);
+    reply.push(OPCODE_SEND_RATE_INFO);
+    push_u32(&mut reply, timestamp);
+    reply.push(decision.quality);
+    push_u32(&mut reply, threshold);
+    reply.push((interval >> 8) as u8);
+    reply.push(interval as u8);
+    push_u32(&mut reply, bitrate);
+
+    reply
 }
 
-#[inline(always)]
-fn get_block_id(data: &Vec<u8>) -> u16
+fn push_u32(buffer: &mut Vec<u8>, value: u32)
 {
-    ((data[0] as u16) << 2) | ((data[1] as u16) >> 6)
-}
\ No newline at end of file
+    buffer.push((value >> 24) as u8);
+    buffer.push((value >> 16) as u8);
+    buffer.push((value >> 8) as u8);
+    buffer.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::super::crypto::Initiator;
+
+    const SRC: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+
+    // The routing state of the receiver with --secure.
+    struct Receiver {
+        routes: HashMap<SocketAddr, Route>,
+        replacing: HashMap<SocketAddr, Route>,
+        keys: KeyPair,
+        next_client: ClientId,
+        main_sender: Sender<(ClientId, MainMessage)>,
+    }
+
+    impl Receiver {
+        fn new() -> Self
+        {
+            Receiver {
+                routes: HashMap::new(),
+                replacing: HashMap::new(),
+                keys: KeyPair::generate().unwrap(),
+                next_client: 0,
+                main_sender: channel().0,
+            }
+        }
+
+        // The message and the client it was routed to.
+        fn receive(&mut self, datagram: Vec<u8>) -> Option<(Vec<u8>, ClientId)>
+        {
+            route((&mut self.routes, &mut self.replacing), Some(&self.keys), &mut self.next_client, &self.main_sender, SRC, datagram)
+                .map(|(message, _, route)| (message, route.client))
+        }
+
+        // Goes through a key exchange from the address, returns the client
+        // it was routed to and the client's sealer.
+        fn key_exchange(&mut self) -> (ClientId, Sealer)
+        {
+            let (initiator, message) = Initiator::start(&self.keys.public, &[OPCODE_RECEIVE_HANDSHAKE]).unwrap();
+
+            let mut datagram = vec![OPCODE_SECURE_HANDSHAKE];
+            datagram.extend_from_slice(&message);
+
+            let (client, reply) = {
+                let (_, key_exchange, route) = route((&mut self.routes, &mut self.replacing),
+                                                     Some(&self.keys),
+                                                     &mut self.next_client,
+                                                     &self.main_sender,
+                                                     SRC,
+                                                     datagram).unwrap();
+
+                (route.client, key_exchange.unwrap().reply)
+            };
+
+            let (sealer, _) = initiator.finish(&reply).unwrap();
+
+            (client, sealer)
+        }
+    }
+
+    fn sealed(sealer: &mut Sealer, message: &[u8]) -> Vec<u8>
+    {
+        let mut datagram = vec![OPCODE_SECURE_DATA];
+        sealer.seal(message, &mut datagram);
+
+        datagram
+    }
+
+    #[test]
+    fn key_exchange_keeps_the_session_route()
+    {
+        let mut receiver = Receiver::new();
+
+        let (session, mut session_sealer) = receiver.key_exchange();
+        receiver.routes.get_mut(&SRC).unwrap().heartbeat_sender = Some(channel().0);
+
+        let (replacing, mut replacing_sealer) = receiver.key_exchange();
+
+        assert_ne!(session, replacing);
+        assert_eq!(receiver.routes[&SRC].client, session);
+        assert_eq!(receiver.replacing[&SRC].client, replacing);
+
+        // Each client's datagrams reach its own route.
+        assert_eq!(receiver.receive(sealed(&mut session_sealer, &[1])), Some((vec![1], session)));
+        assert_eq!(receiver.receive(sealed(&mut replacing_sealer, &[2])), Some((vec![2], replacing)));
+    }
+
+    #[test]
+    fn key_exchange_replaces_a_route_not_connected()
+    {
+        let mut receiver = Receiver::new();
+
+        let (_, mut old_sealer) = receiver.key_exchange();
+        let (client, _) = receiver.key_exchange();
+
+        assert_eq!(receiver.routes[&SRC].client, client);
+        assert!(receiver.replacing.is_empty());
+
+        assert_eq!(receiver.receive(sealed(&mut old_sealer, &[1])), None);
+    }
+}
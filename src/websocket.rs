@@ -0,0 +1,568 @@
+// WebSocket framing and the minimal HTTP handling in front of it, so that a
+// browser can load the bundled viewer and connect back to the same port.
+//
+// Each protocol message travels in one binary frame. Frames from the client
+// are masked, frames to the client are not (RFC 6455). Pings are answered
+// with a pong carrying the same payload, a close with a close frame before
+// the connection is shut.
+
+// The page served at /, it connects to /ws on the same host and port.
+pub const VIEWER_PAGE: &str = include_str!("../static/viewer.html");
+
+pub const WEBSOCKET_PATH: &str = "/ws";
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Largest HTTP request head accepted.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+
+// Longest payload of a control frame.
+const MAX_CONTROL_SIZE: usize = 125;
+
+/// A frame from the client the connection acts on.
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Message(Vec<u8>),
+    Ping(Vec<u8>),
+    Close(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub path: String,
+    // Sec-WebSocket-Key of an upgrade request.
+    pub key: Option<String>,
+    host: Option<String>,
+    origin: Option<String>,
+}
+
+impl Request {
+    /// Whether the request asks to switch to WebSocket.
+    pub fn is_upgrade(&self) -> bool
+    {
+        self.key.is_some() & (self.path == WEBSOCKET_PATH)
+    }
+
+    /// Whether the page that opened the connection may use the session.
+    /// Browsers name it in the Origin header, which must be the server
+    /// itself or one of the allowed origins. Other clients send none.
+    pub fn origin_allowed(&self, allowed: &[String]) -> bool
+    {
+        let origin = match self.origin {
+            Some(ref origin) => origin,
+            None => return true
+        };
+
+        if allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            return true
+        }
+
+        let host = origin.split("://").nth(1);
+
+        match (host, self.host.as_ref()) {
+            (Some(host), Some(own)) => host.eq_ignore_ascii_case(own),
+            _ => false
+        }
+    }
+}
+
+/// Parses the request head at the start of the buffer and removes it.
+/// Returns None while the head is incomplete.
+pub fn parse_request(pending: &mut Vec<u8>) -> Result<Option<Request>, String>
+{
+    let end = match pending.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None if pending.len() > MAX_REQUEST_SIZE => return Err("request too large".to_string()),
+        None => return Ok(None)
+    };
+
+    let head = String::from_utf8_lossy(&pending[..end]).into_owned();
+    pending.drain(..end + 4);
+
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+
+    let path = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => path.to_string(),
+        _ => return Err("not a GET request".to_string())
+    };
+
+    let mut upgrade = false;
+    let mut key = None;
+    let mut host = None;
+    let mut origin = None;
+
+    for line in lines {
+        if let Some(colon) = line.find(':') {
+            let name = line[..colon].trim().to_ascii_lowercase();
+            let value = line[colon + 1..].trim();
+
+            match name.as_str() {
+                "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+                "sec-websocket-key" => key = Some(value.to_string()),
+                "host" => host = Some(value.to_string()),
+                "origin" => origin = Some(value.to_string()),
+                _ => ()
+            }
+        }
+    }
+
+    Ok(Some(Request {
+        path,
+        key: if upgrade { key } else { None },
+        host,
+        origin,
+    }))
+}
+
+/// The response switching the connection to WebSocket.
+pub fn upgrade_response(key: &str) -> Vec<u8>
+{
+    let mut input = key.as_bytes().to_vec();
+    input.extend_from_slice(ACCEPT_GUID.as_bytes());
+
+    format!("HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            base64(&sha1(&input))).into_bytes()
+}
+
+/// The response to a plain HTTP request, the viewer page or an error.
+pub fn http_response(path: &str) -> Vec<u8>
+{
+    match path {
+        "/" | "/index.html" => response("200 OK", "text/html; charset=utf-8", VIEWER_PAGE),
+        _ => response("404 Not Found", "text/plain", "Not found\n")
+    }
+}
+
+/// The response to an upgrade from a page of an origin not allowed.
+pub fn forbidden_response() -> Vec<u8>
+{
+    response("403 Forbidden", "text/plain", "Origin not allowed\n")
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> Vec<u8>
+{
+    let mut response = format!("HTTP/1.1 {}\r\n\
+                                Content-Type: {}\r\n\
+                                Content-Length: {}\r\n\
+                                Connection: close\r\n\r\n",
+                               status, content_type, body.len()).into_bytes();
+    response.extend_from_slice(body.as_bytes());
+
+    response
+}
+
+/// Appends an unmasked binary frame holding the message.
+pub fn encode_frame(frame: &mut Vec<u8>, message: &[u8])
+{
+    encode(frame, OPCODE_BINARY, message);
+}
+
+/// Appends the answer to a ping with the payload.
+pub fn encode_pong(frame: &mut Vec<u8>, payload: &[u8])
+{
+    encode(frame, OPCODE_PONG, payload);
+}
+
+/// Appends the answer to a close. Only the status code of the client's
+/// close frame is echoed, if it sent one.
+pub fn encode_close(frame: &mut Vec<u8>, payload: &[u8])
+{
+    encode(frame, OPCODE_CLOSE, &payload[..payload.len().min(2)]);
+}
+
+fn encode(frame: &mut Vec<u8>, opcode: u8, message: &[u8])
+{
+    frame.push(FIN | opcode);
+
+    let len = message.len();
+
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= 0xFFFF {
+        frame.push(126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    frame.extend_from_slice(message);
+}
+
+/// Takes the next message, ping or close from the frames at the start of
+/// the buffer. Text frames and pongs are skipped. Returns None while the
+/// frame is incomplete, and an error when the client breaks the protocol.
+pub fn decode_frame(pending: &mut Vec<u8>, max_size: usize) -> Result<Option<Frame>, String>
+{
+    loop {
+        if pending.len() < 2 {
+            return Ok(None)
+        }
+
+        let fin = pending[0] & FIN != 0;
+        let opcode = pending[0] & 0x0F;
+
+        if pending[1] & MASKED == 0 {
+            return Err("unmasked frame from client".to_string())
+        }
+
+        let (len, mut start) = match pending[1] & 0x7F {
+            126 if pending.len() >= 4 => ((pending[2] as usize) << 8 | pending[3] as usize, 4),
+            127 if pending.len() >= 10 => {
+                let mut len = 0u64;
+
+                for &byte in &pending[2..10] {
+                    len = len << 8 | byte as u64;
+                }
+
+                (len.min(usize::MAX as u64) as usize, 10)
+            },
+            126 | 127 => return Ok(None),
+            len => (len as usize, 2)
+        };
+
+        if len > max_size {
+            return Err(format!("frame of {} bytes too large", len))
+        }
+
+        // Control frames are short and never fragmented.
+        if (opcode & 0x8 != 0) & (!fin | (len > MAX_CONTROL_SIZE)) {
+            return Err(format!("invalid control frame with opcode {}", opcode))
+        }
+
+        if pending.len() < start + 4 + len {
+            return Ok(None)
+        }
+
+        let mask = [pending[start], pending[start + 1], pending[start + 2], pending[start + 3]];
+        start += 4;
+
+        let mut payload: Vec<u8> = pending[start..start + len].to_vec();
+        pending.drain(..start + len);
+
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        match opcode {
+            OPCODE_BINARY if fin => return Ok(Some(Frame::Message(payload))),
+            OPCODE_BINARY | OPCODE_CONTINUATION => return Err("fragmented messages are not supported".to_string()),
+            OPCODE_PING => return Ok(Some(Frame::Ping(payload))),
+            OPCODE_CLOSE => return Ok(Some(Frame::Close(payload))),
+            OPCODE_TEXT | OPCODE_PONG => (),
+            _ => return Err(format!("unknown opcode {}", opcode))
+        }
+    }
+}
+
+fn sha1(input: &[u8]) -> [u8; 20]
+{
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = input.to_vec();
+    message.push(0x80);
+
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+
+    message.extend_from_slice(&((input.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([chunk[4 * i], chunk[4 * i + 1], chunk[4 * i + 2], chunk[4 * i + 3]]);
+        }
+
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+
+            let temp = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+
+    for (i, word) in h.iter().enumerate() {
+        digest[4 * i..4 * i + 4].copy_from_slice(&word.to_be_bytes());
+    }
+
+    digest
+}
+
+fn base64(input: &[u8]) -> String
+{
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The frame as a client sends it, masked with the key.
+    fn masked(frame: &[u8], key: [u8; 4]) -> Vec<u8>
+    {
+        let header = match frame[1] {
+            126 => 4,
+            127 => 10,
+            _ => 2
+        };
+
+        let mut masked = frame[..header].to_vec();
+        masked[1] |= MASKED;
+        masked.extend_from_slice(&key);
+        masked.extend(frame[header..].iter().enumerate().map(|(i, byte)| byte ^ key[i % 4]));
+
+        masked
+    }
+
+    fn hex(bytes: &[u8]) -> String
+    {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn accept_key()
+    {
+        // RFC 6455, section 1.3.
+        let response = String::from_utf8(upgrade_response("dGhlIHNhbXBsZSBub25jZQ==")).unwrap();
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn sha1_known_answers()
+    {
+        // FIPS 180-4 examples, the last spanning two blocks.
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+                   "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        assert_eq!(hex(&sha1(&[b'a'; 1000000])), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+    }
+
+    #[test]
+    fn base64_known_answers()
+    {
+        // RFC 4648, section 10.
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+
+        for &(input, output) in &vectors {
+            assert_eq!(base64(input.as_bytes()), output);
+        }
+    }
+
+    #[test]
+    fn round_trip()
+    {
+        // Up to the 7 bit length, the 16 bit length and the 64 bit length.
+        for &(len, header) in &[(0, 2), (1, 2), (125, 2), (126, 4), (65535, 4), (65536, 10), (100000, 10)] {
+            let message: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+
+            let mut frame = Vec::new();
+            encode_frame(&mut frame, &message);
+            assert_eq!(frame.len(), header + len);
+
+            let mut pending = masked(&frame, [0x37, 0xFA, 0x21, 0x3D]);
+            assert_eq!(decode_frame(&mut pending, 100000), Ok(Some(Frame::Message(message))));
+            assert!(pending.is_empty());
+        }
+    }
+
+    #[test]
+    fn incomplete()
+    {
+        let mut frame = Vec::new();
+        encode_frame(&mut frame, &[1u8; 300]);
+        let client = masked(&frame, [1, 2, 3, 4]);
+
+        for length in 0..client.len() {
+            let mut pending = client[..length].to_vec();
+            assert_eq!(decode_frame(&mut pending, 1000), Ok(None));
+            assert_eq!(pending.len(), length);
+        }
+    }
+
+    #[test]
+    fn consecutive()
+    {
+        let mut frame = Vec::new();
+        encode_frame(&mut frame, b"one");
+        let mut pending = masked(&frame, [9, 8, 7, 6]);
+
+        frame.clear();
+        encode_frame(&mut frame, b"two");
+        pending.extend(masked(&frame, [5, 4, 3, 2]));
+
+        assert_eq!(decode_frame(&mut pending, 10), Ok(Some(Frame::Message(b"one".to_vec()))));
+        assert_eq!(decode_frame(&mut pending, 10), Ok(Some(Frame::Message(b"two".to_vec()))));
+        assert_eq!(decode_frame(&mut pending, 10), Ok(None));
+    }
+
+    #[test]
+    fn oversized()
+    {
+        let mut frame = Vec::new();
+        encode_frame(&mut frame, &[0u8; 1001]);
+
+        // Refused from the header, before the payload arrives.
+        let mut pending = masked(&frame, [1, 2, 3, 4])[..8].to_vec();
+        assert!(decode_frame(&mut pending, 1000).is_err());
+
+        let mut pending = vec![FIN | OPCODE_BINARY, MASKED | 127, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert!(decode_frame(&mut pending, 1000).is_err());
+    }
+
+    #[test]
+    fn unmasked()
+    {
+        let mut pending = Vec::new();
+        encode_frame(&mut pending, b"message");
+        assert!(decode_frame(&mut pending, 100).is_err());
+    }
+
+    #[test]
+    fn control_frames()
+    {
+        let mut pending = masked(&[FIN | OPCODE_PING, 4, b'p', b'i', b'n', b'g'], [1, 2, 3, 4]);
+        assert_eq!(decode_frame(&mut pending, 100), Ok(Some(Frame::Ping(b"ping".to_vec()))));
+
+        let mut frame = Vec::new();
+        encode_pong(&mut frame, b"ping");
+        assert_eq!(frame, [FIN | OPCODE_PONG, 4, b'p', b'i', b'n', b'g']);
+
+        // Status 1000 and a reason, of which only the status is echoed.
+        let mut pending = masked(&[FIN | OPCODE_CLOSE, 4, 0x03, 0xE8, b'o', b'k'], [1, 2, 3, 4]);
+        assert_eq!(decode_frame(&mut pending, 100), Ok(Some(Frame::Close(vec![0x03, 0xE8, b'o', b'k']))));
+
+        frame.clear();
+        encode_close(&mut frame, &[0x03, 0xE8, b'o', b'k']);
+        assert_eq!(frame, [FIN | OPCODE_CLOSE, 2, 0x03, 0xE8]);
+
+        frame.clear();
+        encode_close(&mut frame, &[]);
+        assert_eq!(frame, [FIN | OPCODE_CLOSE, 0]);
+
+        // Text frames and pongs are skipped.
+        let mut pending = masked(&[FIN | OPCODE_TEXT, 1, b't'], [1, 2, 3, 4]);
+        pending.extend(masked(&[FIN | OPCODE_PONG, 0], [1, 2, 3, 4]));
+        pending.extend(masked(&[FIN | OPCODE_BINARY, 1, 42], [1, 2, 3, 4]));
+        assert_eq!(decode_frame(&mut pending, 100), Ok(Some(Frame::Message(vec![42]))));
+    }
+
+    #[test]
+    fn invalid_control_frames()
+    {
+        // Fragmented.
+        let mut pending = masked(&[OPCODE_PING, 0], [1, 2, 3, 4]);
+        assert!(decode_frame(&mut pending, 1000).is_err());
+
+        // Longer than 125 bytes.
+        let mut frame = vec![FIN | OPCODE_PING, 126, 0, 126];
+        frame.extend_from_slice(&[0u8; 126]);
+        let mut pending = masked(&frame, [1, 2, 3, 4]);
+        assert!(decode_frame(&mut pending, 1000).is_err());
+    }
+
+    // The upgrade request of a page on the origin.
+    fn upgrade(origin: Option<&str>) -> Request
+    {
+        let mut head = "GET /ws HTTP/1.1\r\nHost: screen.local:9998\r\nUpgrade: websocket\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n".to_string();
+
+        if let Some(origin) = origin {
+            head.push_str(&format!("Origin: {}\r\n", origin));
+        }
+
+        head.push_str("\r\n");
+
+        parse_request(&mut head.into_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn origins()
+    {
+        let allowed = vec!["https://example.com".to_string()];
+
+        assert!(upgrade(None).is_upgrade());
+
+        // Clients other than browsers send no origin.
+        assert!(upgrade(None).origin_allowed(&[]));
+
+        // The page the server served itself.
+        assert!(upgrade(Some("http://screen.local:9998")).origin_allowed(&[]));
+        assert!(upgrade(Some("http://SCREEN.local:9998")).origin_allowed(&[]));
+
+        assert!(!upgrade(Some("http://screen.local:8080")).origin_allowed(&[]));
+        assert!(!upgrade(Some("https://evil.example")).origin_allowed(&allowed));
+        assert!(!upgrade(Some("null")).origin_allowed(&allowed));
+
+        assert!(upgrade(Some("https://example.com")).origin_allowed(&allowed));
+    }
+}
@@ -0,0 +1,162 @@
+// Challenge-response authentication and the lockout of failed addresses.
+
+#[path = "../src/crypto/mod.rs"]
+#[allow(dead_code)]
+mod crypto;
+
+#[path = "../src/auth.rs"]
+#[allow(dead_code)]
+mod auth;
+
+use std::collections::HashMap;
+
+use std::env;
+
+use std::fs;
+
+use std::time::{Duration, Instant};
+
+use auth::{constant_time_eq, session_token, Authenticator, Credentials, Role};
+
+fn response(secret: &str, challenge: &[u8], user: &str) -> Vec<u8>
+{
+    crypto::hmac(secret.as_bytes(), &[challenge, user.as_bytes()]).to_vec()
+}
+
+#[test]
+fn password()
+{
+    let mut auth = Authenticator::new(Credentials::Password("secret".to_string()));
+    let src = "192.0.2.1:5000".parse().unwrap();
+    let now = Instant::now();
+
+    let challenge = auth.challenge(src, 1, None, now).unwrap();
+
+    assert!(!auth.verify(&challenge, "", &response("wrong", &challenge.challenge, ""), now));
+    assert!(!auth.verify(&challenge, "user", &response("secret", &challenge.challenge, "user"), now));
+    assert!(auth.verify(&challenge, "", &response("secret", &challenge.challenge, ""), now));
+}
+
+#[test]
+fn tokens()
+{
+    let mut tokens = HashMap::new();
+    tokens.insert("alice".to_string(), ("a-token".to_string(), None));
+    tokens.insert("bob".to_string(), ("b-token".to_string(), None));
+
+    let mut auth = Authenticator::new(Credentials::Tokens(tokens));
+    let src = "[2001:db8::1]:5000".parse().unwrap();
+    let now = Instant::now();
+
+    let challenge = auth.challenge(src, 1, Some(16), now).unwrap();
+
+    assert!(auth.verify(&challenge, "alice", &response("a-token", &challenge.challenge, "alice"), now));
+    assert!(!auth.verify(&challenge, "alice", &response("b-token", &challenge.challenge, "alice"), now));
+    assert!(!auth.verify(&challenge, "carol", &response("a-token", &challenge.challenge, "carol"), now));
+
+    // A response to another challenge is not accepted.
+    let other = auth.challenge(src, 1, None, now).unwrap();
+    assert!(!auth.verify(&other, "bob", &response("b-token", &challenge.challenge, "bob"), now));
+}
+
+#[test]
+fn password_file()
+{
+    let path = env::temp_dir().join(format!("screen_server_password_{}", std::process::id()));
+    fs::write(&path, "secret\r\nignored\n").unwrap();
+
+    let credentials = Credentials::load_password(&path);
+    fs::write(&path, "\nsecret\n").unwrap();
+    let empty = Credentials::load_password(&path);
+    fs::remove_file(&path).unwrap();
+
+    match credentials {
+        Ok(Credentials::Password(ref password)) => assert_eq!(password, "secret"),
+        _ => panic!("password not loaded")
+    }
+
+    assert!(empty.is_err());
+}
+
+#[test]
+fn token_roles()
+{
+    let path = env::temp_dir().join(format!("screen_server_tokens_{}", std::process::id()));
+    fs::write(&path, "# user token role\nalice a-token admin\nbob b-token view-only\ncarol c-token\n").unwrap();
+
+    let credentials = Credentials::load_tokens(&path);
+    fs::write(&path, "dave d-token superuser\n").unwrap();
+    let invalid = Credentials::load_tokens(&path);
+    fs::remove_file(&path).unwrap();
+
+    let auth = Authenticator::new(credentials.unwrap());
+
+    assert_eq!(auth.role("alice"), Some(Role::Admin));
+    assert_eq!(auth.role("bob"), Some(Role::ViewOnly));
+    assert_eq!(auth.role("carol"), None);
+    assert_eq!(auth.role("eve"), None);
+
+    // An unknown role is an error rather than a silent default.
+    assert!(invalid.is_err());
+
+    assert!(!Role::ViewOnly.can_control() & !Role::ViewOnly.can_exit());
+    assert!(Role::Interactive.can_control() & !Role::Interactive.can_exit());
+    assert!(Role::Admin.can_control() & Role::Admin.can_exit());
+
+    let password = Authenticator::new(Credentials::Password("secret".to_string()));
+    assert_eq!(password.role(""), None);
+}
+
+#[test]
+fn lockout_per_address()
+{
+    let mut auth = Authenticator::new(Credentials::Password("secret".to_string()));
+    let src = "192.0.2.1:5000".parse().unwrap();
+    let mapped = "[::ffff:192.0.2.1]:6000".parse().unwrap();
+    let other = "192.0.2.2:5000".parse().unwrap();
+    let now = Instant::now();
+
+    for _ in 0..2 {
+        let challenge = auth.challenge(src, 1, None, now).unwrap();
+        auth.verify(&challenge, "", &[0; 32], now);
+    }
+
+    assert!(!auth.is_locked_out(src, now));
+
+    let challenge = auth.challenge(src, 1, None, now).unwrap();
+    auth.verify(&challenge, "", &[0; 32], now);
+
+    // The same host over IPv6 is locked out as well, other hosts are not.
+    assert!(auth.is_locked_out(src, now));
+    assert!(auth.is_locked_out(mapped, now));
+    assert!(!auth.is_locked_out(other, now));
+
+    assert!(!auth.is_locked_out(src, now + Duration::from_secs(2)));
+
+    // The next failure locks the address out for twice as long.
+    let later = now + Duration::from_secs(2);
+    let challenge = auth.challenge(src, 1, None, later).unwrap();
+    auth.verify(&challenge, "", &[0; 32], later);
+
+    assert!(auth.is_locked_out(src, later + Duration::from_secs(1)));
+    assert!(!auth.is_locked_out(src, later + Duration::from_secs(3)));
+
+    // Success clears the failures.
+    let challenge = auth.challenge(src, 1, None, later).unwrap();
+    assert!(auth.verify(&challenge, "", &response("secret", &challenge.challenge, ""), later));
+    assert!(!auth.is_locked_out(src, later));
+}
+
+#[test]
+fn session_tokens()
+{
+    let token = session_token().unwrap();
+    let other = session_token().unwrap();
+
+    assert!(constant_time_eq(&token, &token.clone()));
+    assert!(!constant_time_eq(&token, &other));
+
+    // A prefix of the token does not resume the session.
+    assert!(!constant_time_eq(&token, &token[..16]));
+    assert!(!constant_time_eq(&token, &[]));
+}
@@ -0,0 +1,250 @@
+// Decoding of client messages: valid messages survive encoding and decoding,
+// arbitrary bytes are decoded or refused without a panic.
+
+#[path = "../src/crypto/mod.rs"]
+#[allow(dead_code, unused_imports)]
+mod crypto;
+
+#[path = "../src/auth.rs"]
+#[allow(dead_code)]
+mod auth;
+
+#[path = "../src/codec.rs"]
+#[allow(dead_code)]
+mod codec;
+
+use auth::{RESPONSE_SIZE, SESSION_TOKEN_SIZE};
+
+use codec::{
+    AuthResponse,
+    Click,
+    ClientMessage,
+    DecodeError,
+    Handshake,
+    Negotiation,
+    Position,
+    OPCODE_RECEIVE_ACK,
+    OPCODE_RECEIVE_AUTH_RESPONSE,
+    OPCODE_RECEIVE_HANDSHAKE,
+    OPCODE_RECEIVE_KEYBOARD,
+    OPCODE_RECEIVE_LEFT_CLICK,
+    OPCODE_RECEIVE_RESUME,
+};
+
+const ITERATIONS: usize = 20000;
+
+// Xorshift, so that a failure can be reproduced from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64
+    {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize
+    {
+        (self.next() % n as u64) as usize
+    }
+
+    fn u8(&mut self) -> u8
+    {
+        self.next() as u8
+    }
+
+    fn u16(&mut self) -> u16
+    {
+        self.next() as u16
+    }
+
+    fn bytes(&mut self, length: usize) -> Vec<u8>
+    {
+        (0..length).map(|_| self.u8()).collect()
+    }
+
+    fn text(&mut self, length: usize) -> String
+    {
+        const CHARS: &[char] = &['a', 'Z', '0', ' ', '\n', 'é', 'ß', '€', '😀'];
+        (0..length).map(|_| CHARS[self.below(CHARS.len())]).collect()
+    }
+
+    fn click(&mut self) -> Click
+    {
+        Click { x: self.u16(), y: self.u16() }
+    }
+
+    fn position(&mut self) -> Position
+    {
+        Position { x: self.u16(), y: self.u16(), screen: self.u8(), segment: self.u8() }
+    }
+}
+
+fn message(rng: &mut Rng) -> ClientMessage
+{
+    match rng.below(18) {
+        0 => {
+            let cache_size = if rng.below(2) == 0 { Some(rng.u16()) } else { None };
+            let liveness_timeout = if cache_size.is_some() && rng.below(2) == 0 { Some(rng.u16()) } else { None };
+
+            ClientMessage::Handshake(Handshake {
+                min_version: rng.u8(),
+                max_version: rng.u8(),
+                cache_size,
+                liveness_timeout,
+                negotiation: None,
+            })
+        },
+        17 => {
+            let count = rng.below(12);
+
+            ClientMessage::Handshake(Handshake {
+                min_version: rng.u8(),
+                max_version: rng.u8(),
+                cache_size: None,
+                liveness_timeout: None,
+                negotiation: Some(Negotiation {
+                    capabilities: rng.next() as u32,
+                    params: (0..count).map(|_| (rng.u8(), rng.u16())).collect(),
+                }),
+            })
+        },
+        1 => ClientMessage::RequestScreenInfo,
+        2 => ClientMessage::RequestView(rng.u8(), rng.u8()),
+        3 => ClientMessage::Refresh,
+        4 => ClientMessage::Close,
+        5 => ClientMessage::Exit,
+        6 => ClientMessage::LeftClick(rng.click()),
+        7 => ClientMessage::RightClick(rng.click()),
+        8 => ClientMessage::DoubleClick(rng.click()),
+        9 => ClientMessage::Drag(rng.position(), rng.position()),
+        10 => {
+            let length = rng.below(64);
+            ClientMessage::Keyboard(rng.text(length))
+        },
+        11 => {
+            let count = rng.below(256);
+            ClientMessage::Ack((0..count).map(|_| rng.next() as u32).collect())
+        },
+        12 => ClientMessage::Heartbeat,
+        13 => {
+            // At most 63 characters of up to 4 bytes fit the length byte.
+            let length = rng.below(64);
+            let mut response = [0u8; RESPONSE_SIZE];
+            response.copy_from_slice(&rng.bytes(RESPONSE_SIZE));
+
+            ClientMessage::AuthResponse(AuthResponse { user: rng.text(length), response })
+        },
+        14 => ClientMessage::RequestStats,
+        15 => ClientMessage::MtuProbeAck(rng.u16()),
+        16 => {
+            let mut token = [0u8; SESSION_TOKEN_SIZE];
+            token.copy_from_slice(&rng.bytes(SESSION_TOKEN_SIZE));
+            ClientMessage::Resume(token)
+        },
+        _ => unreachable!()
+    }
+}
+
+#[test]
+fn round_trip()
+{
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+
+    for _ in 0..ITERATIONS {
+        let message = message(&mut rng);
+        let encoded = message.encode();
+
+        assert_eq!(ClientMessage::decode(&encoded), Ok(message));
+    }
+}
+
+#[test]
+fn arbitrary_bytes()
+{
+    let mut rng = Rng(0xD1B5_4A32_D192_ED03);
+
+    for _ in 0..ITERATIONS {
+        // Known opcodes most of the time, at lengths around the valid ones.
+        let length = rng.below(48);
+        let mut buf = rng.bytes(length);
+
+        if let Some(opcode) = buf.first_mut() {
+            *opcode %= 20;
+        }
+
+        if let Ok(message) = ClientMessage::decode(&buf) {
+            assert_eq!(message.encode(), buf);
+        }
+    }
+}
+
+#[test]
+fn truncated_and_extended()
+{
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+
+    for _ in 0..ITERATIONS / 10 {
+        let encoded = message(&mut rng).encode();
+
+        for length in 0..encoded.len() {
+            let prefix = &encoded[..length];
+
+            // A shorter message may only be valid if it is a message of its own.
+            if let Ok(message) = ClientMessage::decode(prefix) {
+                assert_eq!(message.encode(), prefix);
+            }
+        }
+
+        let mut extended = encoded.clone();
+        extended.push(rng.u8());
+
+        if let Ok(message) = ClientMessage::decode(&extended) {
+            assert_eq!(message.encode(), extended);
+        }
+    }
+}
+
+#[test]
+fn malformed()
+{
+    assert_eq!(ClientMessage::decode(&[]), Err(DecodeError::Empty));
+    assert_eq!(ClientMessage::decode(&[200]), Err(DecodeError::UnknownOpcode(200)));
+
+    assert_eq!(ClientMessage::decode(&[OPCODE_RECEIVE_HANDSHAKE, 1]),
+               Err(DecodeError::Length(OPCODE_RECEIVE_HANDSHAKE, 2)));
+    assert_eq!(ClientMessage::decode(&[OPCODE_RECEIVE_LEFT_CLICK, 0, 1, 0]),
+               Err(DecodeError::Length(OPCODE_RECEIVE_LEFT_CLICK, 4)));
+
+    // Two parameters announced, one sent.
+    assert_eq!(ClientMessage::decode(&[OPCODE_RECEIVE_HANDSHAKE, 2, 2, 0, 0, 0, 1, 2, 0, 0, 1]),
+               Err(DecodeError::Length(OPCODE_RECEIVE_HANDSHAKE, 11)));
+
+    // Two ids announced, one sent.
+    assert_eq!(ClientMessage::decode(&[OPCODE_RECEIVE_ACK, 2, 0, 0, 0, 1]),
+               Err(DecodeError::Length(OPCODE_RECEIVE_ACK, 6)));
+    assert_eq!(ClientMessage::decode(&[OPCODE_RECEIVE_ACK]),
+               Err(DecodeError::Length(OPCODE_RECEIVE_ACK, 1)));
+
+    assert_eq!(ClientMessage::decode(&[OPCODE_RECEIVE_KEYBOARD, 0xC3, 0x28]),
+               Err(DecodeError::Utf8(OPCODE_RECEIVE_KEYBOARD)));
+
+    // A user name longer than the message.
+    let mut auth = vec![OPCODE_RECEIVE_AUTH_RESPONSE, 200, b'a'];
+    auth.extend_from_slice(&[0u8; RESPONSE_SIZE]);
+    assert_eq!(ClientMessage::decode(&auth),
+               Err(DecodeError::Length(OPCODE_RECEIVE_AUTH_RESPONSE, auth.len())));
+
+    let resume = [OPCODE_RECEIVE_RESUME; SESSION_TOKEN_SIZE];
+    assert_eq!(ClientMessage::decode(&resume),
+               Err(DecodeError::Length(OPCODE_RECEIVE_RESUME, SESSION_TOKEN_SIZE)));
+}
+
+#[test]
+fn empty_keyboard()
+{
+    assert_eq!(ClientMessage::decode(&[OPCODE_RECEIVE_KEYBOARD]),
+               Ok(ClientMessage::Keyboard(String::new())));
+}
@@ -0,0 +1,232 @@
+// A Rust client going through the secure handshake with the server side of
+// the crypto module over loopback, and the primitives against the vectors of
+// their specifications.
+
+#[path = "../src/crypto/mod.rs"]
+#[allow(dead_code, unused_imports)]
+mod crypto;
+
+use std::net::UdpSocket;
+
+use crypto::{chacha20poly1305, respond, sha256, x25519, Initiator, KeyPair, OVERHEAD};
+
+const SECURE_HANDSHAKE: u8 = 0x80;
+const SECURE_DATA: u8 = 0x81;
+
+fn hex(s: &str) -> [u8; 32]
+{
+    let mut out = [0u8; 32];
+
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+    }
+
+    out
+}
+
+fn bytes(s: &str) -> Vec<u8>
+{
+    let s: String = s.split_whitespace().collect();
+
+    (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap()).collect()
+}
+
+fn seal(sealer: &mut crypto::Sealer, plaintext: &[u8]) -> Vec<u8>
+{
+    let mut datagram = vec![SECURE_DATA];
+    sealer.seal(plaintext, &mut datagram);
+
+    datagram
+}
+
+#[test]
+fn public_key_matches_rfc7748()
+{
+    let pair = KeyPair::from_private(hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a"));
+
+    assert_eq!(pair.public, hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"));
+}
+
+#[test]
+fn x25519_matches_rfc7748()
+{
+    // Section 5.2.
+    assert_eq!(x25519::scalar_mult(&hex("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"),
+                                   &hex("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c")),
+               hex("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552"));
+
+    assert_eq!(x25519::scalar_mult(&hex("4b66e9d4d1b4673c5ad22691957d6af5c11b6421e0ea01d42ca4169e7918ba0d"),
+                                   &hex("e5210f12786811d3f4b7959d0538ae2c31dbe7106fc03c3efc4cd549c715a493")),
+               hex("95cbde9476e8907d7aade45cb4b873f88b595a68799fa152e6f8f7647aac7957"));
+
+    // The first of the iterations, which take too long to run all of here.
+    let nine = hex("0900000000000000000000000000000000000000000000000000000000000000");
+    assert_eq!(x25519::scalar_mult(&nine, &nine),
+               hex("422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079"));
+
+    // Section 6.1.
+    let alice = hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+    let bob = hex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+    let bob_public = x25519::public_key(&bob);
+    let shared = hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+
+    assert_eq!(bob_public, hex("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"));
+    assert_eq!(x25519::scalar_mult(&alice, &bob_public), shared);
+    assert_eq!(x25519::scalar_mult(&bob, &x25519::public_key(&alice)), shared);
+}
+
+#[test]
+fn aead_matches_rfc8439()
+{
+    // Section 2.8.2.
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f"));
+
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&bytes("070000004041424344454647"));
+
+    let aad = bytes("50515253c0c1c2c3c4c5c6c7");
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+    let expected = bytes("d31a8d34648e60db7b86afbc53ef7ec2 a4aded51296e08fea9e2b5a736ee62d6
+                          3dbea45e8ca9671282fafb69da92728b 1a71de0a9e060b2905d6a5b67ecd3b36
+                          92ddbd7f2d778b8c9803aee328091b58 fab324e4fad675945585808b4831d7bc
+                          3ff4def08e4b7a9de576d26586cec64b 6116
+                          1ae10b594f09e26a7e902ecbd0600691");
+
+    let mut sealed = Vec::new();
+    chacha20poly1305::seal(&key, &nonce, &aad, plaintext, &mut sealed);
+    assert_eq!(sealed, expected);
+
+    assert_eq!(chacha20poly1305::open(&key, &nonce, &aad, &sealed).unwrap(), &plaintext[..]);
+
+    // Any change to the tag, the ciphertext or the associated data fails.
+    let mut tampered = sealed.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(chacha20poly1305::open(&key, &nonce, &aad, &tampered).is_none());
+
+    tampered = sealed.clone();
+    tampered[0] ^= 1;
+    assert!(chacha20poly1305::open(&key, &nonce, &aad, &tampered).is_none());
+
+    assert!(chacha20poly1305::open(&key, &nonce, &aad[1..], &sealed).is_none());
+}
+
+#[test]
+fn sha256_matches_fips180()
+{
+    // FIPS 180-4 examples, the last spanning two blocks.
+    assert_eq!(sha256::sha256(&[b""]).to_vec(),
+               bytes("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"));
+    assert_eq!(sha256::sha256(&[b"abc"]).to_vec(),
+               bytes("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+
+    let message: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+    let expected = bytes("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+
+    assert_eq!(sha256::sha256(&[message]).to_vec(), expected);
+
+    // Hashing in parts is hashing the concatenation.
+    assert_eq!(sha256::sha256(&[&message[..5], &message[5..40], &message[40..]]).to_vec(), expected);
+}
+
+#[test]
+fn hmac_matches_rfc4231()
+{
+    // Test cases 1, 2 and 6, the last with a key longer than a block.
+    assert_eq!(sha256::hmac(&[0x0b; 20], &[b"Hi There"]).to_vec(),
+               bytes("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"));
+    assert_eq!(sha256::hmac(b"Jefe", &[b"what do ya want ", b"for nothing?"]).to_vec(),
+               bytes("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"));
+    assert_eq!(sha256::hmac(&[0xaa; 131], &[b"Test Using Larger Than Block-Size Key - Hash Key First"]).to_vec(),
+               bytes("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"));
+}
+
+#[test]
+fn session_over_loopback()
+{
+    let server_keys = KeyPair::generate().unwrap();
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_address = server.local_addr().unwrap();
+
+    // The client's first message carries the plain handshake, opcode 0.
+    let handshake = [0u8, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    let (initiator, message) = Initiator::start(&server_keys.public, &handshake).unwrap();
+
+    let mut datagram = vec![SECURE_HANDSHAKE];
+    datagram.extend_from_slice(&message);
+    client.send_to(&datagram, server_address).unwrap();
+
+    let mut buf = [0u8; 800];
+
+    let (amt, client_address) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(buf[0], SECURE_HANDSHAKE);
+
+    let mut session = respond(&server_keys, &buf[1..amt]).unwrap();
+    assert_eq!(session.payload, handshake);
+
+    let mut reply = vec![SECURE_HANDSHAKE];
+    reply.extend_from_slice(&session.reply);
+    server.send_to(&reply, client_address).unwrap();
+
+    let amt = client.recv(&mut buf).unwrap();
+    assert_eq!(buf[0], SECURE_HANDSHAKE);
+
+    let (mut client_sealer, mut client_opener) = initiator.finish(&buf[1..amt]).unwrap();
+
+    // Server to client.
+    let ack = seal(&mut session.sealer, &[0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(ack.len(), 1 + 9 + OVERHEAD);
+    server.send_to(&ack, client_address).unwrap();
+
+    let amt = client.recv(&mut buf).unwrap();
+    assert_eq!(client_opener.open(&buf[..1], &buf[1..amt]).unwrap(), [0u8; 9]);
+
+    // Client to server, a replayed datagram is rejected.
+    let heartbeat = seal(&mut client_sealer, &[12]);
+    client.send_to(&heartbeat, server_address).unwrap();
+    client.send_to(&heartbeat, server_address).unwrap();
+
+    let amt = server.recv(&mut buf).unwrap();
+    assert_eq!(session.opener.open(&buf[..1], &buf[1..amt]).unwrap(), [12]);
+
+    let amt = server.recv(&mut buf).unwrap();
+    assert!(session.opener.open(&buf[..1], &buf[1..amt]).is_none());
+}
+
+#[test]
+fn rejects_tampering_and_old_counters()
+{
+    let server_keys = KeyPair::generate().unwrap();
+
+    let (initiator, message) = Initiator::start(&server_keys.public, &[]).unwrap();
+    let mut session = respond(&server_keys, &message).unwrap();
+    let (mut sealer, _) = initiator.finish(&session.reply).unwrap();
+
+    let datagrams: Vec<Vec<u8>> = (0..100u8).map(|i| seal(&mut sealer, &[i])).collect();
+
+    // Flipping a bit of the ciphertext, the counter or the opcode fails.
+    for position in &[datagrams[0].len() - 1, 8, 0] {
+        let mut tampered = datagrams[0].clone();
+        tampered[*position] ^= 1;
+
+        assert!(session.opener.open(&tampered[..1], &tampered[1..]).is_none());
+    }
+
+    // Reordering within the window is fine.
+    assert_eq!(session.opener.open(&datagrams[1][..1], &datagrams[1][1..]).unwrap(), [1]);
+    assert_eq!(session.opener.open(&datagrams[0][..1], &datagrams[0][1..]).unwrap(), [0]);
+
+    // Counters that fell out of the window are rejected.
+    assert_eq!(session.opener.open(&datagrams[99][..1], &datagrams[99][1..]).unwrap(), [99]);
+    assert!(session.opener.open(&datagrams[2][..1], &datagrams[2][1..]).is_none());
+    assert_eq!(session.opener.open(&datagrams[50][..1], &datagrams[50][1..]).unwrap(), [50]);
+
+    // A handshake for a different server key is not accepted.
+    let other_keys = KeyPair::generate().unwrap();
+    let (_, message) = Initiator::start(&other_keys.public, &[]).unwrap();
+
+    assert!(respond(&server_keys, &message).is_none());
+}